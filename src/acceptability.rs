@@ -0,0 +1,327 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::ConSet,
+    expression::Expression,
+    term::Term,
+    types::Label,
+};
+
+/// clause of the syntax-directed specification of 0-CFA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clause {
+    Var,
+    Fn,
+    Fun,
+    App,
+    If,
+    Let,
+}
+
+impl Display for Clause {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Var => "var",
+            Self::Fn => "fn",
+            Self::Fun => "fun",
+            Self::App => "app",
+            Self::If => "if",
+            Self::Let => "let",
+        };
+        write!(f, "[{name}]")
+    }
+}
+
+/// an inclusion `lhs ⊆ rhs` required by `clause` at `label` that does not hold
+pub struct Violation {
+    pub clause: Clause,
+    pub label: Label,
+    pub lhs: ConSet,
+    pub rhs: ConSet,
+    /// the closures in `lhs` but not in `rhs`
+    pub missing: Vec<Term>,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let missing = self
+            .missing
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "{} at label {}: {} ⊄ {}, missing {}",
+            self.clause,
+            self.label,
+            self.lhs,
+            self.rhs,
+            missing.join(", ")
+        )
+    }
+}
+
+/**
+ * checks `(C, ρ) ⊨s e` for the syntax-directed specification of 0-CFA, where the body
+ * of a closure is checked where it is defined, and an application only requires the
+ * argument and result of the closures in its operator to flow into their parameter and
+ * to the application
+ *
+ * sets missing from `cache` and `env` count as empty
+ */
+struct Checker<'a> {
+    cache: &'a AbstractCache,
+    env: &'a AbstractEnv,
+    violations: Vec<Violation>,
+}
+
+impl Checker<'_> {
+    fn set(&self, node: &ConSet) -> HashSet<Term> {
+        let set = match node {
+            ConSet::Cache(l) => self.cache.get(l),
+            ConSet::Env(x) => self.env.get(x),
+            ConSet::SingleTerm(t) => return HashSet::from([t.clone()]),
+        };
+        set.cloned().unwrap_or_default()
+    }
+
+    fn require(&mut self, clause: Clause, label: Label, lhs: ConSet, rhs: ConSet) {
+        let rhs_set = self.set(&rhs);
+        let mut missing: Vec<Term> = self
+            .set(&lhs)
+            .into_iter()
+            .filter(|t| !rhs_set.contains(t))
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        missing.sort_by_key(ToString::to_string);
+        self.violations.push(Violation {
+            clause,
+            label,
+            lhs,
+            rhs,
+            missing,
+        });
+    }
+
+    fn check(&mut self, expr: &Expression) {
+        use ConSet::*;
+        let l = expr.label;
+
+        match &expr.term {
+            Term::Constant(_) => {}
+
+            Term::Variable(x) => self.require(Clause::Var, l, Env(*x), Cache(l)),
+
+            Term::Closure(_, e0) => {
+                self.require(Clause::Fn, l, SingleTerm(expr.term.clone()), Cache(l));
+                self.check(e0);
+            }
+
+            Term::RecursiveClosure(f, _, e0) => {
+                self.require(Clause::Fun, l, SingleTerm(expr.term.clone()), Cache(l));
+                self.require(Clause::Fun, l, SingleTerm(expr.term.clone()), Env(*f));
+                self.check(e0);
+            }
+
+            Term::Application(e1, e2) => {
+                self.check(e1);
+                self.check(e2);
+
+                let mut closures = Vec::from_iter(self.set(&Cache(e1.label)));
+                closures.sort_by_key(ToString::to_string);
+                for closure in closures {
+                    if let Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) = &closure {
+                        self.require(Clause::App, l, Cache(e2.label), Env(*x));
+                        self.require(Clause::App, l, Cache(e0.label), Cache(l));
+                    }
+                }
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                self.check(e0);
+                self.check(e1);
+                self.check(e2);
+                self.require(Clause::If, l, Cache(e1.label), Cache(l));
+                self.require(Clause::If, l, Cache(e2.label), Cache(l));
+            }
+
+            Term::Let(x, e1, e2) => {
+                self.check(e1);
+                self.check(e2);
+                self.require(Clause::Let, l, Cache(e1.label), Env(*x));
+                self.require(Clause::Let, l, Cache(e2.label), Cache(l));
+            }
+
+            Term::BinaryOp(e1, _, e2) => {
+                self.check(e1);
+                self.check(e2);
+            }
+        }
+    }
+}
+
+/// the inclusions of `(C, ρ) ⊨s expr` that `cache` and `env` violate; none if the
+/// analysis is acceptable
+pub fn check_acceptable(
+    expr: &Expression,
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+) -> Vec<Violation> {
+    let mut checker = Checker {
+        cache,
+        env,
+        violations: vec![],
+    };
+    checker.check(expr);
+    checker.violations
+}
+
+/**
+ * reads an analysis of `expr` with one set per line, as `C(l): l1, l2` or `r(x): l1`,
+ * where `l1, l2` are the labels of the closures in the set
+ *
+ * sets that are not given are empty; empty lines and lines starting with `#` are skipped
+ */
+pub fn parse_analysis(
+    expr: &Expression,
+    text: &str,
+) -> Result<(AbstractCache, AbstractEnv), String> {
+    let labels = expr.labels();
+    let variables = expr.variables();
+    let closures: Vec<&Expression> = expr
+        .subexprs()
+        .into_iter()
+        .filter(|e| matches!(e.term, Term::Closure(..) | Term::RecursiveClosure(..)))
+        .collect();
+
+    let mut cache: AbstractCache = labels.iter().map(|&l| (l, HashSet::new())).collect();
+    let mut env: AbstractEnv = variables.iter().map(|&x| (x, HashSet::new())).collect();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {message}", n + 1);
+
+        let (node, elements) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected `C(l): ...` or `r(x): ...`".to_string()))?;
+        let set = match parse_node(node.trim()) {
+            Some(ConSet::Cache(l)) if labels.contains(&l) => cache.get_mut(&l).unwrap(),
+            Some(ConSet::Env(x)) if variables.contains(&x) => env.get_mut(&x).unwrap(),
+            Some(node) => return Err(error(format!("{node} is not in the program"))),
+            None => return Err(error(format!("invalid set {}", node.trim()))),
+        };
+
+        for element in elements.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let label: Label = element
+                .parse()
+                .map_err(|_| error(format!("invalid closure label {element}")))?;
+            let closure = closures
+                .iter()
+                .find(|e| e.label == label)
+                .ok_or_else(|| error(format!("label {label} is not a closure")))?;
+            set.insert(closure.term.clone());
+        }
+    }
+
+    Ok((cache, env))
+}
+
+/// `C(l)` or `r(x)`
+pub fn parse_node(node: &str) -> Option<ConSet> {
+    let inner = |prefix: &str| node.strip_prefix(prefix)?.strip_suffix(')');
+    if let Some(label) = inner("C(") {
+        return label.trim().parse().ok().map(ConSet::Cache);
+    }
+    let x = inner("r(")?.trim();
+    let mut chars = x.chars();
+    match (chars.next(), chars.next()) {
+        (Some(x), None) => Some(ConSet::Env(x)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        generator::{random_program, Rng},
+        parser::{examples, parse},
+    };
+
+    #[test]
+    fn analyses_are_acceptable() {
+        let random = (0..20).map(|seed| random_program(&mut Rng::new(seed), 100));
+        for program in examples().into_iter().chain(random) {
+            let (cache, env) = analyse(&program, &program.constraint_system());
+            let violations = check_acceptable(&program, &cache, &env);
+            assert!(violations.is_empty(), "{}", violations[0]);
+        }
+    }
+
+    #[test]
+    fn missing_closures_are_violations() {
+        let program = parse("(fn x -> x) (fn y -> y) ").unwrap();
+        let (cache, env) = analyse(&program, &program.constraint_system());
+        let fn_y = cache[&4].iter().next().unwrap().clone();
+
+        let mut without = env.clone();
+        without.get_mut(&'x').unwrap().clear();
+        let violations = check_acceptable(&program, &cache, &without);
+        assert_eq!(violations.len(), 1);
+        let violation = &violations[0];
+        assert_eq!(violation.clause, Clause::App);
+        assert_eq!(violation.label, 5);
+        assert_eq!(
+            (violation.lhs.clone(), violation.rhs.clone()),
+            (ConSet::Cache(4), ConSet::Env('x'))
+        );
+        assert_eq!(violation.missing, std::slice::from_ref(&fn_y));
+
+        let mut without = cache.clone();
+        without.get_mut(&1).unwrap().clear();
+        let violations = check_acceptable(&program, &without, &env);
+        assert_eq!(violations.len(), 1);
+        let violation = &violations[0];
+        assert_eq!(violation.clause, Clause::Var);
+        assert_eq!(violation.label, 1);
+        assert_eq!(
+            (violation.lhs.clone(), violation.rhs.clone()),
+            (ConSet::Env('x'), ConSet::Cache(1))
+        );
+        assert_eq!(violation.missing, [fn_y]);
+    }
+
+    #[test]
+    fn malformed_analyses_are_errors() {
+        let program = parse("(fn x -> x) (fn y -> y) ").unwrap();
+        let (cache, env) = parse_analysis(&program, "# comment\n\nC(5): 4\nr(x): 4, \n").unwrap();
+        assert_eq!(cache[&5].len(), 1);
+        assert_eq!(env[&'x'].len(), 1);
+        assert!(cache[&1].is_empty());
+
+        let error = |text| parse_analysis(&program, text).err().unwrap();
+        assert_eq!(
+            error("C(5) 4"),
+            "line 1: expected `C(l): ...` or `r(x): ...`"
+        );
+        assert_eq!(error("\nC(x): 4"), "line 2: invalid set C(x)");
+        assert_eq!(error("C(9): 4"), "line 1: C(9) is not in the program");
+        assert_eq!(error("r(z): 4"), "line 1: r(z) is not in the program");
+        assert_eq!(error("C(5): y"), "line 1: invalid closure label y");
+        assert_eq!(error("C(5): 1"), "line 1: label 1 is not a closure");
+
+        for node in ["C(", "C(1", "r()", "r(xy)", "c(1)", ""] {
+            assert_eq!(parse_node(node), None);
+        }
+        assert_eq!(parse_node("C( 3 )"), Some(ConSet::Cache(3)));
+        assert_eq!(parse_node("r(y)"), Some(ConSet::Env('y')));
+    }
+}
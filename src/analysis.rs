@@ -1,7 +1,11 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ops::Range,
+};
 
 use crate::{
-    constraint::{ConSet, Constraint},
+    bitset::BitSet,
+    constraint::{CallSite, ConSet, Constraint, ConstraintSystem},
     expression::Expression,
     term::Term,
     types::{Label, Variable},
@@ -10,107 +14,1071 @@ use crate::{
 pub type AbstractCache = HashMap<Label, HashSet<Term>>;
 pub type AbstractEnv = HashMap<Variable, HashSet<Term>>;
 
-#[inline(always)]
-fn add(
-    index: &ConSet,
-    terms: HashSet<Term>,
-    node_data: &mut HashMap<ConSet, HashSet<Term>>,
-    work_list: &mut Vec<ConSet>,
-) {
-    if !terms.is_subset(&node_data[index]) {
-        node_data.get_mut(index).unwrap().extend(terms);
-        work_list.insert(0, index.clone());
-    }
+/// index of an interned closure
+pub type ClosureId = usize;
+/// dense index of a node `C(l)` or `r(x)` of the constraint graph
+pub type NodeId = usize;
+
+/// rounds of the parallel solver are split into chunks of at least this many nodes
+const MIN_NODES_PER_THREAD: usize = 64;
+
+/// `{t} ⊆ guard => from ⊆ to`
+struct ConditionalEdge {
+    closure: ClosureId,
+    guard: NodeId,
+    from: NodeId,
+    to: NodeId,
 }
 
-/**
- * expected to be called with `expr` and `expr.constraints()`
- *
- * this is so that the constraints can be obtained and used beforehand, e.g. for printing
- */
-pub fn analyse(
-    expr: &Expression,
-    constraints: &HashSet<Constraint>,
-) -> (AbstractCache, AbstractEnv) {
-    let nodes: HashSet<ConSet> = expr
-        .labels()
-        .iter()
-        .map(|l| ConSet::Cache(*l))
-        .chain(expr.variables().iter().map(|x| ConSet::Env(*x)))
-        .collect();
+/// a call site, whose constraints are instantiated for every closure reaching its operator
+struct CallSiteEdges {
+    operand: NodeId,
+    result: NodeId,
+    /// closures for which the constraints have already been instantiated
+    instantiated: BitSet,
+}
+
+/// closures to send along an edge in the parallel solver
+enum Transfer {
+    /// the new closures of the `i`th node of the round
+    Delta(usize),
+    /// the whole set of a node
+    Whole(NodeId),
+}
+
+/// what processing some nodes in a round of the parallel solver produced
+#[derive(Default)]
+struct RoundOutput {
+    /// `(to, terms)`: `terms` are to be added to the set of `to`
+    transfers: Vec<(NodeId, Transfer)>,
+    /// `(site, c)`: closure `c` has reached the operator of call site `site`
+    instantiations: Vec<(usize, ClosureId)>,
+}
+
+/// what the worklist solver did in one step
+#[derive(Debug, Clone)]
+pub enum TraceEvent {
+    /// `node` was taken from the worklist, with the closures `delta` new to it
+    Pop {
+        iteration: usize,
+        node: ConSet,
+        delta: Vec<Term>,
+        worklist: Vec<ConSet>,
+    },
+    /// `constraint` was looked at because a node in it changed
+    Examine { constraint: Constraint },
+    /// `terms` were added to `node`
+    Add { node: ConSet, terms: Vec<Term> },
+    /// the nodes `merged` were found on a cycle with `node` and merged into it
+    Collapse { node: ConSet, merged: Vec<ConSet> },
+}
+
+/// worklist solver over interned closures and dense node indices
+struct Solver<'a> {
+    closures: Vec<&'a Term>,
+    closure_ids: HashMap<&'a Term, ClosureId>,
+    /// `r(x)` of each closure's parameter `x`
+    closure_params: Vec<NodeId>,
+    /// `C(l)` of each closure's body
+    closure_bodies: Vec<NodeId>,
+
+    nodes: Vec<ConSet>,
+    node_ids: HashMap<ConSet, NodeId>,
+    node_data: Vec<BitSet>,
+    /// closures added to each node since it was last taken from the worklist
+    delta: Vec<BitSet>,
+    /// whether to propagate only `delta` instead of the whole set of a node
+    difference_propagation: bool,
+
+    /// unconditional edges `p1 ⊆ p2`, indexed by `p1`
+    successors: Vec<Vec<NodeId>>,
+    conditionals: Vec<ConditionalEdge>,
+    /// conditional edges to re-examine when a node changes (as guard or as source)
+    watchers: Vec<Vec<usize>>,
+    call_sites: Vec<CallSiteEdges>,
+    /// call sites, indexed by the node of their operator
+    operator_sites: Vec<Vec<usize>>,
+
+    work_list: VecDeque<NodeId>,
+    queued: Vec<bool>,
+
+    /// union-find forest of nodes merged because they lie on a cycle
+    parent: Vec<NodeId>,
+    /// edges that have already triggered cycle detection
+    checked_edges: HashSet<(NodeId, NodeId)>,
+    /// marks of the nodes visited by the current search for a cycle
+    visited: Vec<usize>,
+    predecessor: Vec<NodeId>,
+    search_epoch: usize,
+
+    statistics: Statistics,
+
+    /// whether nodes on a cycle are merged
+    collapse_cycles: bool,
+    /// if recorded, the constraint that first added each closure to each node
+    reasons: Option<HashMap<(NodeId, ClosureId), Constraint>>,
+    /// the constraint each edge comes from (only while recording reasons)
+    edge_reasons: HashMap<(NodeId, NodeId), Constraint>,
+    /// if recorded, every step of Step 3
+    trace: Option<Vec<TraceEvent>>,
+}
+
+impl<'a> Solver<'a> {
+    fn new(expr: &'a Expression, system: &ConstraintSystem, difference_propagation: bool) -> Self {
+        Self::without_constraints(expr, difference_propagation)
+            .with_constraints(&system.constraints, &system.call_sites)
+    }
 
     // Step 1: Initialization
-    let mut work_list: Vec<ConSet> = Vec::new();
+    fn without_constraints(expr: &'a Expression, difference_propagation: bool) -> Self {
+        let mut subexprs: Vec<&Expression> = expr.subexprs().into_iter().collect();
+        subexprs.sort_by_key(|e| e.label);
 
-    let (mut node_data, mut edges): (
-        HashMap<ConSet, HashSet<Term>>,
-        HashMap<ConSet, HashSet<&Constraint>>,
-    ) = nodes
-        .iter()
-        .map(|q| ((q.clone(), HashSet::new()), (q.clone(), HashSet::new())))
-        .unzip();
+        let mut nodes: Vec<ConSet> = subexprs.iter().map(|e| ConSet::Cache(e.label)).collect();
+        let mut variables = Vec::from_iter(expr.variables());
+        variables.sort();
+        nodes.extend(variables.into_iter().map(ConSet::Env));
 
-    // Step 2: Building the graph
-    for constraint in constraints {
-        use ConSet::*;
-        use Constraint::*;
-        match &constraint {
-            Unconditional(p1, p2) => match p1 {
-                SingleTerm(t) => add(
-                    p2,
-                    HashSet::from([t.clone()]),
-                    &mut node_data,
-                    &mut work_list,
-                ),
-                _ => {
-                    edges.get_mut(p1).unwrap().insert(constraint);
-                }
-            },
+        let node_ids: HashMap<ConSet, NodeId> = nodes
+            .iter()
+            .enumerate()
+            .map(|(id, node)| (node.clone(), id))
+            .collect();
 
-            Conditional((_t, p), p1, _p2) => {
-                edges.get_mut(p1).unwrap().insert(constraint);
-                edges.get_mut(p).unwrap().insert(constraint);
+        let (mut closures, mut closure_params, mut closure_bodies) = (vec![], vec![], vec![]);
+        for e in &subexprs {
+            if let Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) = &e.term {
+                closures.push(&e.term);
+                closure_params.push(node_ids[&ConSet::Env(*x)]);
+                closure_bodies.push(node_ids[&ConSet::Cache(e0.label)]);
             }
         }
+
+        let closure_ids = closures
+            .iter()
+            .enumerate()
+            .map(|(id, &t)| (t, id))
+            .collect();
+
+        let node_count = nodes.len();
+        let closure_count = closures.len();
+
+        Self {
+            closures,
+            closure_ids,
+            closure_params,
+            closure_bodies,
+
+            nodes,
+            node_ids,
+            node_data: vec![BitSet::new(closure_count); node_count],
+            delta: vec![BitSet::new(closure_count); node_count],
+            difference_propagation,
+
+            successors: vec![vec![]; node_count],
+            conditionals: vec![],
+            watchers: vec![vec![]; node_count],
+            call_sites: vec![],
+            operator_sites: vec![vec![]; node_count],
+
+            work_list: VecDeque::new(),
+            queued: vec![false; node_count],
+
+            parent: (0..node_count).collect(),
+            checked_edges: HashSet::new(),
+            visited: vec![0; node_count],
+            predecessor: vec![0; node_count],
+            search_epoch: 0,
+
+            statistics: Statistics::default(),
+
+            collapse_cycles: true,
+            reasons: None,
+            edge_reasons: HashMap::new(),
+            trace: None,
+        }
     }
 
-    // Step 3: Iteration
-    while work_list.len() > 0 {
-        let q = work_list.remove(0);
+    // Step 2: Building the graph
+    fn with_constraints<'c>(
+        mut self,
+        constraints: impl IntoIterator<Item = &'c Constraint>,
+        call_sites: &[CallSite],
+    ) -> Self {
+        let closure_ids = self.closure_ids.clone();
+        let closure_id = |t: &Term| -> ClosureId {
+            *closure_ids
+                .get(t)
+                .unwrap_or_else(|| panic!("Not a closure of the program: {t}"))
+        };
 
-        for constraint in &edges[&q] {
+        for constraint in constraints {
+            use ConSet::*;
             use Constraint::*;
-            match &constraint {
+            match constraint {
+                Unconditional(SingleTerm(t), p2) => {
+                    let p2 = self.node_ids[p2];
+                    if self.node_data[p2].insert(closure_id(t)) {
+                        self.delta[p2].insert(closure_id(t));
+                        self.enqueue(p2);
+                    }
+                }
+
                 Unconditional(p1, p2) => {
-                    add(
-                        p2,
-                        node_data.get(p1).unwrap().clone(),
-                        &mut node_data,
-                        &mut work_list,
-                    );
+                    self.successors[self.node_ids[p1]].push(self.node_ids[p2]);
                 }
 
                 Conditional((t, p), p1, p2) => {
-                    if node_data[p].contains(t) {
-                        add(p2, node_data[p1].clone(), &mut node_data, &mut work_list)
+                    let edge = ConditionalEdge {
+                        closure: closure_id(t),
+                        guard: self.node_ids[p],
+                        from: self.node_ids[p1],
+                        to: self.node_ids[p2],
+                    };
+                    self.watchers[edge.guard].push(self.conditionals.len());
+                    self.watchers[edge.from].push(self.conditionals.len());
+                    self.conditionals.push(edge);
+                }
+            }
+        }
+
+        for call_site in call_sites {
+            let operator = self.node_ids[&ConSet::Cache(call_site.operator)];
+            self.operator_sites[operator].push(self.call_sites.len());
+            self.call_sites.push(CallSiteEdges {
+                operand: self.node_ids[&ConSet::Cache(call_site.operand)],
+                result: self.node_ids[&ConSet::Cache(call_site.label)],
+                instantiated: BitSet::new(self.closures.len()),
+            });
+        }
+
+        self
+    }
+
+    /**
+     * starts from the given sets instead of empty ones, leaving out nodes and closures
+     * that are not in the program (any more)
+     *
+     * the `initial` sets are taken to satisfy every constraint between them, except
+     * those in `added`, so only what flows into the other nodes or along `added` is put
+     * on the worklist instead of every set again
+     */
+    fn with_initial(
+        mut self,
+        initial: &HashMap<ConSet, HashSet<Term>>,
+        added: &ConstraintSystem,
+    ) -> Self {
+        // the initial sets are not final yet, so equal ones do not mean a cycle
+        let collapse_cycles = std::mem::replace(&mut self.collapse_cycles, false);
+        let mut kept = vec![false; self.nodes.len()];
+        for (node, terms) in initial {
+            let Some(&n) = self.node_ids.get(node) else {
+                continue;
+            };
+            kept[n] = true;
+            for c in terms
+                .iter()
+                .filter_map(|t| self.closure_ids.get(t).copied())
+            {
+                self.node_data[n].insert(c);
+                // closures of `{t} ⊆ C(l)` the initial set already has were propagated
+                self.delta[n].remove(c);
+            }
+        }
+
+        // the call sites already have the edges for the closures reaching them, unless
+        // they are new
+        let added_sites: HashSet<NodeId> = added
+            .call_sites
+            .iter()
+            .map(|site| self.node_ids[&ConSet::Cache(site.label)])
+            .collect();
+        for n in 0..self.nodes.len() {
+            for i in 0..self.operator_sites[n].len() {
+                let site = self.operator_sites[n][i];
+                let CallSiteEdges {
+                    operand, result, ..
+                } = self.call_sites[site];
+                for c in self.node_data[n].clone().iter() {
+                    self.call_sites[site].instantiated.insert(c);
+                    for (from, to) in [
+                        (operand, self.closure_params[c]),
+                        (self.closure_bodies[c], result),
+                    ] {
+                        self.successors[from].push(to);
+                        if added_sites.contains(&result) {
+                            self.propagate_all(from, to);
+                        }
+                    }
+                }
+            }
+        }
+
+        // what flows from the initial sets into the other nodes
+        for from in 0..self.nodes.len() {
+            let mut i = 0;
+            while i < self.successors[from].len() {
+                let to = self.successors[from][i];
+                if !kept[to] {
+                    self.propagate_all(from, to);
+                }
+                i += 1;
+            }
+        }
+        for i in 0..self.conditionals.len() {
+            let edge = &self.conditionals[i];
+            let (closure, guard, from, to) = (edge.closure, edge.guard, edge.from, edge.to);
+            let guard = self.find(guard);
+            if !kept[to] && self.node_data[guard].contains(closure) {
+                self.propagate_all(from, to);
+            }
+        }
+
+        // and along the constraints that are new
+        for constraint in &added.constraints {
+            match constraint {
+                Constraint::Unconditional(ConSet::SingleTerm(_), _) => {}
+                Constraint::Unconditional(p1, p2) => {
+                    self.propagate_all(self.node_ids[p1], self.node_ids[p2]);
+                }
+                Constraint::Conditional((t, p), p1, p2) => {
+                    if self.node_data[self.node_ids[p]].contains(self.closure_ids[t]) {
+                        self.propagate_all(self.node_ids[p1], self.node_ids[p2]);
+                    }
+                }
+            }
+        }
+
+        let work_list = std::mem::take(&mut self.work_list);
+        for q in work_list {
+            self.queued[q] = false;
+            if !self.delta[q].is_empty() {
+                self.enqueue(q);
+            }
+        }
+
+        self.collapse_cycles = collapse_cycles;
+        self
+    }
+
+    /// records which constraint adds each closure to each node; nodes on a cycle are not
+    /// merged then, so that every node keeps its own reasons
+    fn with_reasons(mut self, system: &ConstraintSystem) -> Self {
+        let mut reasons = HashMap::new();
+        for constraint in &system.constraints {
+            use ConSet::*;
+            use Constraint::*;
+            match constraint {
+                Unconditional(SingleTerm(t), p2) => {
+                    let key = (self.node_ids[p2], self.closure_ids[t]);
+                    reasons.entry(key).or_insert_with(|| constraint.clone());
+                }
+                Unconditional(p1, p2) | Conditional(_, p1, p2) => {
+                    let key = (self.node_ids[p1], self.node_ids[p2]);
+                    self.edge_reasons
+                        .entry(key)
+                        .or_insert_with(|| constraint.clone());
+                }
+            }
+        }
+
+        self.reasons = Some(reasons);
+        self.collapse_cycles = false;
+        self
+    }
+
+    /// records every step of Step 3, starting with the sets after initialization
+    fn with_trace(mut self) -> Self {
+        let mut trace = vec![];
+        for (n, data) in self.node_data.iter().enumerate() {
+            if !data.is_empty() {
+                trace.push(TraceEvent::Add {
+                    node: self.nodes[n].clone(),
+                    terms: self.terms(data.iter()),
+                });
+            }
+        }
+
+        self.trace = Some(trace);
+        self
+    }
+
+    fn terms(&self, closures: impl Iterator<Item = ClosureId>) -> Vec<Term> {
+        closures.map(|c| self.closures[c].clone()).collect()
+    }
+
+    fn trace(&mut self, event: impl FnOnce(&Self) -> TraceEvent) {
+        if let Some(mut trace) = self.trace.take() {
+            trace.push(event(self));
+            self.trace = Some(trace);
+        }
+    }
+
+    fn enqueue(&mut self, q: NodeId) {
+        if !self.queued[q] {
+            self.queued[q] = true;
+            self.work_list.push_back(q);
+        }
+    }
+
+    /// representative of the (collapsed) node `n`
+    fn find(&mut self, mut n: NodeId) -> NodeId {
+        while self.parent[n] != n {
+            self.parent[n] = self.parent[self.parent[n]];
+            n = self.parent[n];
+        }
+        n
+    }
+
+    /// `node_data[to] ∪= terms`, where `terms` are (some of) the closures of `from`
+    fn propagate(&mut self, from: NodeId, to: NodeId, terms: &BitSet) {
+        let (from, to) = (self.find(from), self.find(to));
+        if from == to {
+            return;
+        }
+
+        if let Some(reasons) = &mut self.reasons {
+            for c in terms.iter().filter(|&c| !self.node_data[to].contains(c)) {
+                reasons.insert((to, c), self.edge_reasons[&(from, to)].clone());
+            }
+        }
+        if self.trace.is_some() {
+            let added = self.terms(terms.iter().filter(|&c| !self.node_data[to].contains(c)));
+            if !added.is_empty() {
+                self.trace(|solver| TraceEvent::Add {
+                    node: solver.nodes[to].clone(),
+                    terms: added,
+                });
+            }
+        }
+
+        self.statistics.transfers += terms.len();
+        if self.node_data[to].union_tracking(terms, &mut self.delta[to]) {
+            self.enqueue(to);
+        }
+
+        // lazy cycle detection: an edge whose ends end up with equal sets is likely on a cycle
+        if self.collapse_cycles
+            && !self.node_data[to].is_empty()
+            && self.node_data[from] == self.node_data[to]
+            && self.checked_edges.insert((from, to))
+        {
+            if let Some(cycle) = self.find_path(to, from) {
+                self.collapse(&cycle);
+            }
+        }
+    }
+
+    /// a path of unconditional edges from `start` to `goal`, if there is one
+    ///
+    /// only nodes with the same set as `goal` are visited, as all nodes on a cycle
+    /// end up with the same set anyway
+    fn find_path(&mut self, start: NodeId, goal: NodeId) -> Option<Vec<NodeId>> {
+        self.search_epoch += 1;
+        self.visited[start] = self.search_epoch;
+        self.predecessor[start] = start;
+        let mut stack = vec![start];
+
+        while let Some(n) = stack.pop() {
+            if n == goal {
+                let mut path = vec![goal];
+                let mut n = goal;
+                while n != start {
+                    n = self.predecessor[n];
+                    path.push(n);
+                }
+                return Some(path);
+            }
+
+            for i in 0..self.successors[n].len() {
+                let m = self.find(self.successors[n][i]);
+                if self.visited[m] != self.search_epoch && self.node_data[m] == self.node_data[goal]
+                {
+                    self.visited[m] = self.search_epoch;
+                    self.predecessor[m] = n;
+                    stack.push(m);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// merges the nodes of a cycle, which must all end up with the same set, into one
+    fn collapse(&mut self, cycle: &[NodeId]) {
+        let representative = cycle[0];
+        self.trace(|solver| TraceEvent::Collapse {
+            node: solver.nodes[representative].clone(),
+            merged: cycle[1..]
+                .iter()
+                .filter(|&&n| n != representative)
+                .map(|&n| solver.nodes[n].clone())
+                .collect(),
+        });
+
+        for &n in &cycle[1..] {
+            if n == representative {
+                continue;
+            }
+
+            self.parent[n] = representative;
+            self.statistics.collapsed_nodes += 1;
+
+            let data = std::mem::take(&mut self.node_data[n]);
+            self.node_data[representative].union_with(&data);
+            self.delta[n] = BitSet::default();
+            for list in [
+                &mut self.successors,
+                &mut self.watchers,
+                &mut self.operator_sites,
+            ] {
+                let moved = std::mem::take(&mut list[n]);
+                list[representative].extend(moved);
+            }
+        }
+
+        // edges within the cycle have become self-loops, and some edges are now duplicates
+        let mut successors = std::mem::take(&mut self.successors[representative]);
+        for successor in successors.iter_mut() {
+            *successor = self.find(*successor);
+        }
+        successors.sort_unstable();
+        successors.dedup();
+        successors.retain(|&successor| successor != representative);
+        self.successors[representative] = successors;
+
+        // the successors of each merged node may lack closures of the others
+        self.delta[representative] = self.node_data[representative].clone();
+        self.enqueue(representative);
+    }
+
+    fn add_edge(&mut self, from: NodeId, to: NodeId) {
+        let from = self.find(from);
+        self.successors[from].push(to);
+        self.propagate_all(from, to);
+    }
+
+    /// records the conditional constraints behind the edges a call site with the operator
+    /// `operator` adds for closure `c`
+    fn record_instantiation(
+        &mut self,
+        operator: NodeId,
+        c: ClosureId,
+        operand: NodeId,
+        result: NodeId,
+    ) {
+        let guard = (self.closures[c].clone(), self.nodes[operator].clone());
+        for (from, to) in [
+            (operand, self.closure_params[c]),
+            (self.closure_bodies[c], result),
+        ] {
+            let constraint = Constraint::Conditional(
+                guard.clone(),
+                self.nodes[from].clone(),
+                self.nodes[to].clone(),
+            );
+            self.edge_reasons.entry((from, to)).or_insert(constraint);
+        }
+    }
+
+    /// propagates the whole set of `from`, e.g. along a new edge
+    fn propagate_all(&mut self, from: NodeId, to: NodeId) {
+        let from = self.find(from);
+        let terms = self.node_data[from].clone();
+        self.propagate(from, to, &terms);
+    }
+
+    // Step 3: Iteration
+    fn solve(mut self) -> Self {
+        while let Some(q) = self.work_list.pop_front() {
+            self.queued[q] = false;
+            // collapsed nodes are handled by their representative
+            if self.find(q) != q {
+                continue;
+            }
+            self.statistics.iterations += 1;
+
+            let delta = std::mem::replace(&mut self.delta[q], BitSet::new(self.closures.len()));
+            let delta = if self.difference_propagation {
+                delta
+            } else {
+                self.node_data[q].clone()
+            };
+            self.trace(|solver| TraceEvent::Pop {
+                iteration: solver.statistics.iterations,
+                node: solver.nodes[q].clone(),
+                delta: solver.terms(delta.iter()),
+                worklist: solver
+                    .work_list
+                    .iter()
+                    .map(|&n| solver.nodes[n].clone())
+                    .collect(),
+            });
+
+            // instantiate the constraints of call sites reached by new closures
+            let mut i = 0;
+            while i < self.operator_sites[q].len() {
+                let site = self.operator_sites[q][i];
+                let new_closures: Vec<ClosureId> = delta
+                    .iter()
+                    .filter(|&c| self.call_sites[site].instantiated.insert(c))
+                    .collect();
+
+                for c in new_closures {
+                    let CallSiteEdges {
+                        operand, result, ..
+                    } = self.call_sites[site];
+                    if self.reasons.is_some() {
+                        self.record_instantiation(q, c, operand, result);
                     }
+                    self.trace(|solver| {
+                        let guard = (solver.closures[c].clone(), solver.nodes[q].clone());
+                        TraceEvent::Examine {
+                            constraint: Constraint::Conditional(
+                                guard,
+                                solver.nodes[operand].clone(),
+                                solver.nodes[solver.closure_params[c]].clone(),
+                            ),
+                        }
+                    });
+                    self.add_edge(operand, self.closure_params[c]);
+                    self.trace(|solver| {
+                        let guard = (solver.closures[c].clone(), solver.nodes[q].clone());
+                        TraceEvent::Examine {
+                            constraint: Constraint::Conditional(
+                                guard,
+                                solver.nodes[solver.closure_bodies[c]].clone(),
+                                solver.nodes[result].clone(),
+                            ),
+                        }
+                    });
+                    self.add_edge(self.closure_bodies[c], result);
+                }
+                i += 1;
+            }
+
+            let mut i = 0;
+            while i < self.successors[q].len() && self.find(q) == q {
+                let successor = self.successors[q][i];
+                self.trace(|solver| TraceEvent::Examine {
+                    constraint: Constraint::Unconditional(
+                        solver.nodes[q].clone(),
+                        solver.nodes[successor].clone(),
+                    ),
+                });
+                self.propagate(q, successor, &delta);
+                i += 1;
+            }
+
+            let mut i = 0;
+            while i < self.watchers[q].len() && self.find(q) == q {
+                let edge = &self.conditionals[self.watchers[q][i]];
+                let (guard, closure, from, to) = (edge.guard, edge.closure, edge.from, edge.to);
+                let (guard, from) = (self.find(guard), self.find(from));
+                self.trace(|solver| TraceEvent::Examine {
+                    constraint: Constraint::Conditional(
+                        (
+                            solver.closures[closure].clone(),
+                            solver.nodes[guard].clone(),
+                        ),
+                        solver.nodes[from].clone(),
+                        solver.nodes[to].clone(),
+                    ),
+                });
+                if self.node_data[guard].contains(closure) {
+                    if guard == q && delta.contains(closure) {
+                        // the constraint has just become active
+                        self.propagate_all(from, to);
+                    } else if from == q {
+                        self.propagate(from, to, &delta);
+                    }
+                }
+                i += 1;
+            }
+        }
+
+        self
+    }
+
+    // Step 3, in parallel: all nodes on the worklist are processed at once in rounds,
+    // reading the sets as they were at the start of the round
+    //
+    // nodes are not collapsed, as that would change the graph during a round
+    fn solve_parallel(mut self, threads: usize) -> Self {
+        while !self.work_list.is_empty() {
+            let round: Vec<(NodeId, BitSet)> = std::mem::take(&mut self.work_list)
+                .into_iter()
+                .map(|q| {
+                    self.queued[q] = false;
+                    let delta = BitSet::new(self.closures.len());
+                    (q, std::mem::replace(&mut self.delta[q], delta))
+                })
+                .collect();
+            self.statistics.iterations += round.len();
+
+            let outputs = if threads <= 1 || round.len() < MIN_NODES_PER_THREAD {
+                vec![self.process(&round, 0..round.len())]
+            } else {
+                let chunk_size = round.len().div_ceil(threads).max(MIN_NODES_PER_THREAD);
+                let (solver, round) = (&self, &round);
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = (0..round.len())
+                        .step_by(chunk_size)
+                        .map(|start| {
+                            let chunk = start..round.len().min(start + chunk_size);
+                            scope.spawn(move || solver.process(round, chunk))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| handle.join().expect("solver thread panicked"))
+                        .collect()
+                })
+            };
+
+            for output in outputs {
+                self.apply(&round, output);
+            }
+        }
+
+        self
+    }
+
+    /// what processing the nodes `round[chunk]` with their new closures produces, without
+    /// changing the solver
+    fn process(&self, round: &[(NodeId, BitSet)], chunk: Range<usize>) -> RoundOutput {
+        let mut output = RoundOutput::default();
+
+        for i in chunk {
+            let (q, delta) = (round[i].0, &round[i].1);
+            for &site in &self.operator_sites[q] {
+                let instantiated = &self.call_sites[site].instantiated;
+                output.instantiations.extend(
+                    delta
+                        .iter()
+                        .filter(|&c| !instantiated.contains(c))
+                        .map(|c| (site, c)),
+                );
+            }
+
+            for &to in &self.successors[q] {
+                output.transfers.push((to, Transfer::Delta(i)));
+            }
+
+            for &watcher in &self.watchers[q] {
+                let edge = &self.conditionals[watcher];
+                if self.node_data[edge.guard].contains(edge.closure) {
+                    if edge.guard == q && delta.contains(edge.closure) {
+                        // the constraint has just become active
+                        output.transfers.push((edge.to, Transfer::Whole(edge.from)));
+                    } else if edge.from == q {
+                        output.transfers.push((edge.to, Transfer::Delta(i)));
+                    }
+                }
+            }
+        }
+
+        output
+    }
+
+    /// adds the closures and edges found in a round
+    ///
+    /// whole sets are taken as they are now, which may be more than at the start of
+    /// the round, but never more than in the least solution
+    fn apply(&mut self, round: &[(NodeId, BitSet)], output: RoundOutput) {
+        for (to, transfer) in output.transfers {
+            let changed = match transfer {
+                Transfer::Delta(i) => {
+                    self.statistics.transfers += round[i].1.len();
+                    self.node_data[to].union_tracking(&round[i].1, &mut self.delta[to])
+                }
+                Transfer::Whole(from) => {
+                    let terms = self.node_data[from].clone();
+                    self.statistics.transfers += terms.len();
+                    self.node_data[to].union_tracking(&terms, &mut self.delta[to])
+                }
+            };
+            if changed {
+                self.enqueue(to);
+            }
+        }
+
+        for (site, c) in output.instantiations {
+            if !self.call_sites[site].instantiated.insert(c) {
+                continue;
+            }
+
+            let CallSiteEdges {
+                operand, result, ..
+            } = self.call_sites[site];
+            for (from, to) in [
+                (operand, self.closure_params[c]),
+                (self.closure_bodies[c], result),
+            ] {
+                self.successors[from].push(to);
+                let terms = self.node_data[from].clone();
+                self.statistics.transfers += terms.len();
+                if self.node_data[to].union_tracking(&terms, &mut self.delta[to]) {
+                    self.enqueue(to);
                 }
             }
         }
     }
 
     // Step 4: Recording the solution
-    let mut cache: AbstractCache = AbstractCache::new();
-    let mut env: AbstractEnv = AbstractEnv::new();
-    for (key, value) in node_data {
-        use ConSet::*;
-        match key {
-            Cache(l) => cache.insert(l, value),
-            Env(x) => env.insert(x, value),
-
-            _ => panic!("Non-label/variable key in node_data: {:?}", key),
-        };
+    fn solution(mut self) -> Solution<'a> {
+        // collapsed nodes share the set of their representative
+        let node_data = (0..self.nodes.len())
+            .map(|n| {
+                let representative = self.find(n);
+                self.node_data[representative].clone()
+            })
+            .collect();
+
+        Solution {
+            closures: self.closures,
+            nodes: self.nodes,
+            node_data,
+            statistics: self.statistics,
+        }
     }
+}
+
+/// counters describing how the solver arrived at its solution
+#[derive(Debug, Clone, Default)]
+pub struct Statistics {
+    /// nodes taken from the worklist
+    pub iterations: usize,
+    /// nodes merged into another node because they were found on a cycle
+    pub collapsed_nodes: usize,
+    /// closures sent along edges (counting those already in the target)
+    pub transfers: usize,
+}
+
+/// least solution of a constraint system, as sets of interned closures per node
+pub struct Solution<'a> {
+    pub closures: Vec<&'a Term>,
+    pub nodes: Vec<ConSet>,
+    pub node_data: Vec<BitSet>,
+    pub statistics: Statistics,
+}
+
+impl<'a> Solution<'a> {
+    /// converts to the book's representation, cloning every closure into every set it is in
+    pub fn into_analysis(self) -> (AbstractCache, AbstractEnv) {
+        let mut cache: AbstractCache = AbstractCache::new();
+        let mut env: AbstractEnv = AbstractEnv::new();
+        for (node, data) in self.nodes.iter().zip(&self.node_data) {
+            let terms: HashSet<Term> = data.iter().map(|c| self.closures[c].clone()).collect();
+
+            use ConSet::*;
+            match node {
+                Cache(l) => cache.insert(*l, terms),
+                Env(x) => env.insert(*x, terms),
+
+                _ => panic!("Non-label/variable node: {:?}", node),
+            };
+        }
 
-    (cache, env)
+        (cache, env)
+    }
+}
+
+/// like `analyse`, but keeps the solution in its compact interned form
+///
+/// when a node changes, only the closures new to it are propagated to its successors
+pub fn solve<'a>(expr: &'a Expression, system: &ConstraintSystem) -> Solution<'a> {
+    Solver::new(expr, system, true).solve().solution()
+}
+
+/// like `solve`, but starts from the `initial` sets of some nodes instead of empty ones,
+/// which satisfy all constraints of `system` between them but those in `added`
+///
+/// the result is only the least solution if `initial` is below it, e.g. consists of the
+/// sets of nodes whose constraints have not changed since a previous solution
+pub fn solve_from<'a>(
+    expr: &'a Expression,
+    system: &ConstraintSystem,
+    initial: &HashMap<ConSet, HashSet<Term>>,
+    added: &ConstraintSystem,
+) -> Solution<'a> {
+    Solver::new(expr, system, true)
+        .with_initial(initial, added)
+        .solve()
+        .solution()
+}
+
+/// like `solve`, but processes the nodes on the worklist on up to `threads` threads
+pub fn solve_parallel<'a>(
+    expr: &'a Expression,
+    system: &ConstraintSystem,
+    threads: usize,
+) -> Solution<'a> {
+    Solver::new(expr, system, true)
+        .solve_parallel(threads)
+        .solution()
+}
+
+/// like `solve`, but also returns the constraint that first added each closure to each
+/// set, and does not merge nodes on cycles
+pub fn solve_with_reasons<'a>(
+    expr: &'a Expression,
+    system: &ConstraintSystem,
+) -> (Solution<'a>, HashMap<(ConSet, Term), Constraint>) {
+    let mut solver = Solver::new(expr, system, true).with_reasons(system).solve();
+    let reasons = solver
+        .reasons
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|((n, c), constraint)| {
+            (
+                (solver.nodes[n].clone(), solver.closures[c].clone()),
+                constraint,
+            )
+        })
+        .collect();
+    (solver.solution(), reasons)
+}
+
+/// like `solve`, but also returns what happened in every step of Step 3; the constraints
+/// are taken in a fixed order, so that the steps are the same on every run
+pub fn solve_traced<'a>(
+    expr: &'a Expression,
+    system: &ConstraintSystem,
+) -> (Solution<'a>, Vec<TraceEvent>) {
+    let mut constraints = Vec::from_iter(&system.constraints);
+    constraints.sort_by_cached_key(|constraint| constraint.to_string());
+    let mut solver = Solver::without_constraints(expr, true)
+        .with_constraints(constraints, &system.call_sites)
+        .with_trace()
+        .solve();
+    let trace = solver.trace.take().unwrap_or_default();
+    (solver.solution(), trace)
+}
+
+/// like `solve`, but always propagates the whole set of a node when it changes
+pub fn solve_without_differences<'a>(
+    expr: &'a Expression,
+    system: &ConstraintSystem,
+) -> Solution<'a> {
+    Solver::new(expr, system, false).solve().solution()
+}
+
+/**
+ * expected to be called with `expr` and `expr.constraint_system()`
+ *
+ * this is so that the constraints can be obtained and used beforehand, e.g. for printing
+ *
+ * the constraints of a call site are only instantiated for a closure once that closure
+ * reaches the call site's operator, rather than for every closure in the program
+ */
+pub fn analyse(expr: &Expression, system: &ConstraintSystem) -> (AbstractCache, AbstractEnv) {
+    solve(expr, system).into_analysis()
+}
+
+/// like `analyse`, but on `threads` threads if there are more than one; this finds the
+/// same solution, though it is not faster so far (see the README)
+pub fn analyse_on(
+    expr: &Expression,
+    system: &ConstraintSystem,
+    threads: usize,
+) -> (AbstractCache, AbstractEnv) {
+    if threads > 1 {
+        solve_parallel(expr, system, threads).into_analysis()
+    } else {
+        solve(expr, system).into_analysis()
+    }
+}
+
+/// number of threads `--threads` solves on by default
+pub fn available_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// a set of one analysis with the closures it has in addition to another analysis
+pub struct Imprecision {
+    /// `C(l)` or `r(x)`
+    pub node: String,
+    pub extra: Vec<Term>,
+}
+
+/// the sets of `cache` and `env` that have closures not in `precise_cache` and
+/// `precise_env`, e.g. the results of `analyse`
+pub fn imprecision(
+    (cache, env): (&AbstractCache, &AbstractEnv),
+    (precise_cache, precise_env): (&AbstractCache, &AbstractEnv),
+) -> Vec<Imprecision> {
+    let extra = |terms: &HashSet<Term>, precise: Option<&HashSet<Term>>| {
+        let mut extra: Vec<Term> = terms
+            .iter()
+            .filter(|t| !precise.is_some_and(|precise| precise.contains(t)))
+            .cloned()
+            .collect();
+        extra.sort_by_key(ToString::to_string);
+        extra
+    };
+
+    let mut labels = Vec::from_iter(cache);
+    labels.sort_by_key(|(label, _)| **label);
+    let mut variables = Vec::from_iter(env);
+    variables.sort_by_key(|(x, _)| **x);
+
+    labels
+        .into_iter()
+        .map(|(label, terms)| Imprecision {
+            node: format!("C({label})"),
+            extra: extra(terms, precise_cache.get(label)),
+        })
+        .chain(variables.into_iter().map(|(x, terms)| Imprecision {
+            node: format!("r({x})"),
+            extra: extra(terms, precise_env.get(x)),
+        }))
+        .filter(|imprecision| !imprecision.extra.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        baseline::solve_baseline,
+        generator::{random_program, Rng},
+        parser::examples,
+    };
+
+    fn random_programs() -> Vec<Expression> {
+        [10, 50, 100]
+            .into_iter()
+            .flat_map(|size| (0..20).map(move |seed| random_program(&mut Rng::new(seed), size)))
+            .collect()
+    }
+
+    #[test]
+    fn same_solution_as_the_previous_solver() {
+        for program in examples().iter().chain(&random_programs()) {
+            let system = program.constraint_system();
+            assert_eq!(
+                solve(program, &system).into_analysis(),
+                solve_baseline(program, &system),
+                "{program}"
+            );
+        }
+    }
+
+    #[test]
+    fn parallel_solver_finds_the_same_solution() {
+        let large = (0..3).map(|seed| random_program(&mut Rng::new(seed), 400));
+        for program in examples().into_iter().chain(random_programs()).chain(large) {
+            let system = program.constraint_system();
+            let sequential = solve(&program, &system).into_analysis();
+            for threads in [2, 4] {
+                assert_eq!(
+                    solve_parallel(&program, &system, threads).into_analysis(),
+                    sequential,
+                    "{program}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,525 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// index of an (unannotated) type variable
+type TypeVar = usize;
+/// index of an annotation variable, a set of closure labels
+type AnnotationVar = usize;
+
+#[derive(Debug, Clone, Copy)]
+enum Shape {
+    Int,
+    Function(TypeVar, TypeVar),
+}
+
+/// type whose function types carry the labels of the closures they may be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnotatedType {
+    Int,
+    /// a type that is not constrained by the program, numbered from 0
+    Variable(usize),
+    /// `τ1 -{π, ...}-> τ2`
+    Function(Box<AnnotatedType>, BTreeSet<Label>, Box<AnnotatedType>),
+}
+
+impl Display for AnnotatedType {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int => write!(formatter, "int"),
+            Self::Variable(n) => {
+                write!(formatter, "'{}", (b'a' + (n % 26) as u8) as char)?;
+                // 'a, ..., 'z, 'a1, ..., 'z1, 'a2, ...
+                match n / 26 {
+                    0 => Ok(()),
+                    round => write!(formatter, "{round}"),
+                }
+            }
+            Self::Function(parameter, labels, result) => {
+                let labels = labels
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                match **parameter {
+                    Self::Function(..) => write!(formatter, "({parameter})")?,
+                    _ => write!(formatter, "{parameter}")?,
+                }
+                write!(formatter, " -{{{labels}}}-> {result}")
+            }
+        }
+    }
+}
+
+/// annotated type whose annotations are still variables
+#[derive(Debug, Clone)]
+enum Annotated {
+    Int,
+    Variable(TypeVar),
+    Function(Box<Annotated>, AnnotationVar, Box<Annotated>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    /// an integer is used as a function or the other way round
+    Mismatch(Label),
+    /// a function would have to take or return itself
+    Infinite(Label),
+}
+
+impl Display for TypeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mismatch(label) => write!(
+                formatter,
+                "integer used as a function or function used as an integer at label {label}"
+            ),
+            Self::Infinite(label) => write!(formatter, "infinite type at label {label}"),
+        }
+    }
+}
+
+/// result of the type-based analysis
+pub struct TypeBasedAnalysis {
+    pub types: HashMap<Label, AnnotatedType>,
+    pub variable_types: HashMap<Variable, AnnotatedType>,
+    pub cache: AbstractCache,
+    pub env: AbstractEnv,
+}
+
+/**
+ * control flow analysis as an annotated type system (Nielson, Nielson & Hankin, ch. 5):
+ * every function type carries the labels of the closures it may be, and a closure of
+ * type `τ1 -φ-> τ2` may be used where `τ1' -φ'-> τ2'` is expected if `τ1' ≤ τ1`,
+ * `φ ⊆ φ'` and `τ2 ≤ τ2'`
+ *
+ * the underlying simple types are inferred first by unification, which also rejects
+ * programs that are not typable. every label and variable then gets its own copy of its
+ * type with fresh annotation variables, the subtyping constraints of the typing rules
+ * are decomposed into inclusions between annotations, and their least solution is taken
+ */
+struct Inference<'a> {
+    parent: Vec<TypeVar>,
+    shapes: Vec<Option<Shape>>,
+    label_types: HashMap<Label, TypeVar>,
+    variable_types: HashMap<Variable, TypeVar>,
+
+    annotated_labels: HashMap<Label, Annotated>,
+    annotated_variables: HashMap<Variable, Annotated>,
+    /// labels of the closures known to be in each annotation
+    annotations: Vec<BTreeSet<Label>>,
+    /// inclusions `annotation1 ⊆ annotation2`, indexed by `annotation1`
+    inclusions: Vec<Vec<AnnotationVar>>,
+
+    closures: HashMap<Label, &'a Term>,
+}
+
+impl<'a> Inference<'a> {
+    fn new(expr: &'a Expression) -> Self {
+        let mut inference = Self {
+            parent: vec![],
+            shapes: vec![],
+            label_types: HashMap::new(),
+            variable_types: HashMap::new(),
+
+            annotated_labels: HashMap::new(),
+            annotated_variables: HashMap::new(),
+            annotations: vec![],
+            inclusions: vec![],
+
+            closures: expr
+                .subexprs()
+                .into_iter()
+                .filter(|e| matches!(e.term, Term::Closure(..) | Term::RecursiveClosure(..)))
+                .map(|e| (e.label, &e.term))
+                .collect(),
+        };
+
+        for label in expr.labels() {
+            let t = inference.fresh();
+            inference.label_types.insert(label, t);
+        }
+        for x in expr.variables() {
+            let t = inference.fresh();
+            inference.variable_types.insert(x, t);
+        }
+
+        inference
+    }
+
+    fn fresh(&mut self) -> TypeVar {
+        self.parent.push(self.parent.len());
+        self.shapes.push(None);
+        self.parent.len() - 1
+    }
+
+    fn find(&mut self, mut t: TypeVar) -> TypeVar {
+        while self.parent[t] != t {
+            self.parent[t] = self.parent[self.parent[t]];
+            t = self.parent[t];
+        }
+        t
+    }
+
+    fn unify(&mut self, a: TypeVar, b: TypeVar, label: Label) -> Result<(), TypeError> {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return Ok(());
+        }
+        self.parent[b] = a;
+
+        match (self.shapes[a], self.shapes[b]) {
+            (_, None) => Ok(()),
+            (None, shape) => {
+                self.shapes[a] = shape;
+                Ok(())
+            }
+            (Some(Shape::Int), Some(Shape::Int)) => Ok(()),
+            (Some(Shape::Function(p1, r1)), Some(Shape::Function(p2, r2))) => {
+                self.unify(p1, p2, label)?;
+                self.unify(r1, r2, label)
+            }
+            _ => Err(TypeError::Mismatch(label)),
+        }
+    }
+
+    fn unify_shape(&mut self, t: TypeVar, shape: Shape, label: Label) -> Result<(), TypeError> {
+        let s = self.fresh();
+        self.shapes[s] = Some(shape);
+        self.unify(t, s, label)
+    }
+
+    // Step 1: the underlying types
+    fn simple_types(&mut self, expr: &Expression) -> Result<(), TypeError> {
+        let l = expr.label;
+        let t = self.label_types[&l];
+
+        match &expr.term {
+            Term::Constant(_) => self.unify_shape(t, Shape::Int, l),
+
+            Term::Variable(x) => self.unify(self.variable_types[x], t, l),
+
+            Term::Closure(x, e0) => {
+                self.simple_types(e0)?;
+                let shape = Shape::Function(self.variable_types[x], self.label_types[&e0.label]);
+                self.unify_shape(t, shape, l)
+            }
+
+            Term::RecursiveClosure(f, x, e0) => {
+                self.simple_types(e0)?;
+                let shape = Shape::Function(self.variable_types[x], self.label_types[&e0.label]);
+                self.unify_shape(t, shape, l)?;
+                self.unify(self.variable_types[f], t, l)
+            }
+
+            Term::Application(e1, e2) => {
+                self.simple_types(e1)?;
+                self.simple_types(e2)?;
+                let shape = Shape::Function(self.label_types[&e2.label], t);
+                self.unify_shape(self.label_types[&e1.label], shape, l)
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                self.simple_types(e0)?;
+                self.simple_types(e1)?;
+                self.simple_types(e2)?;
+                self.unify_shape(self.label_types[&e0.label], Shape::Int, l)?;
+                self.unify(self.label_types[&e1.label], t, l)?;
+                self.unify(self.label_types[&e2.label], t, l)
+            }
+
+            Term::Let(x, e1, e2) => {
+                self.simple_types(e1)?;
+                self.simple_types(e2)?;
+                self.unify(self.label_types[&e1.label], self.variable_types[x], l)?;
+                self.unify(self.label_types[&e2.label], t, l)
+            }
+
+            Term::BinaryOp(e1, _, e2) => {
+                self.simple_types(e1)?;
+                self.simple_types(e2)?;
+                self.unify_shape(self.label_types[&e1.label], Shape::Int, l)?;
+                self.unify_shape(self.label_types[&e2.label], Shape::Int, l)?;
+                self.unify_shape(t, Shape::Int, l)
+            }
+        }
+    }
+
+    // Step 2: a copy of the type of `t` with fresh annotation variables
+    fn annotate(
+        &mut self,
+        t: TypeVar,
+        label: Label,
+        outer: &mut Vec<TypeVar>,
+    ) -> Result<Annotated, TypeError> {
+        let t = self.find(t);
+        if outer.contains(&t) {
+            return Err(TypeError::Infinite(label));
+        }
+
+        match self.shapes[t] {
+            None => Ok(Annotated::Variable(t)),
+            Some(Shape::Int) => Ok(Annotated::Int),
+            Some(Shape::Function(parameter, result)) => {
+                outer.push(t);
+                let parameter = self.annotate(parameter, label, outer)?;
+                let result = self.annotate(result, label, outer)?;
+                outer.pop();
+
+                self.annotations.push(BTreeSet::new());
+                self.inclusions.push(vec![]);
+                let annotation = self.annotations.len() - 1;
+                Ok(Annotated::Function(
+                    Box::new(parameter),
+                    annotation,
+                    Box::new(result),
+                ))
+            }
+        }
+    }
+
+    fn annotate_all(&mut self, expr: &Expression) -> Result<(), TypeError> {
+        let mut labels = Vec::from_iter(self.label_types.clone());
+        labels.sort();
+        for (label, t) in labels {
+            let annotated = self.annotate(t, label, &mut vec![])?;
+            self.annotated_labels.insert(label, annotated);
+        }
+
+        let mut variables = Vec::from_iter(self.variable_types.clone());
+        variables.sort();
+        for (x, t) in variables {
+            let annotated = self.annotate(t, expr.label, &mut vec![])?;
+            self.annotated_variables.insert(x, annotated);
+        }
+
+        Ok(())
+    }
+
+    /// `a ≤ b`, for types with the same shape
+    fn subtype(&mut self, a: &Annotated, b: &Annotated) {
+        if let (
+            Annotated::Function(p1, annotation1, r1),
+            Annotated::Function(p2, annotation2, r2),
+        ) = (a, b)
+        {
+            self.subtype(p2, p1);
+            self.inclusions[*annotation1].push(*annotation2);
+            self.subtype(r1, r2);
+        }
+    }
+
+    fn label_type(&self, label: Label) -> Annotated {
+        self.annotated_labels[&label].clone()
+    }
+
+    fn variable_type(&self, x: Variable) -> Annotated {
+        self.annotated_variables[&x].clone()
+    }
+
+    // Step 3: the subtyping constraints of the typing rules
+    fn constraints(&mut self, expr: &Expression) {
+        let l = expr.label;
+        let t = self.label_type(l);
+
+        match &expr.term {
+            Term::Constant(_) => {}
+
+            Term::Variable(x) => self.subtype(&self.variable_type(*x), &t),
+
+            Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) => {
+                let Annotated::Function(parameter, annotation, result) = &t else {
+                    unreachable!("closure without a function type")
+                };
+                self.annotations[*annotation].insert(l);
+                self.subtype(parameter, &self.variable_type(*x));
+                self.subtype(&self.label_type(e0.label), result);
+                if let Term::RecursiveClosure(f, ..) = &expr.term {
+                    self.subtype(&t, &self.variable_type(*f));
+                }
+                self.constraints(e0);
+            }
+
+            Term::Application(e1, e2) => {
+                let Annotated::Function(parameter, _, result) = self.label_type(e1.label) else {
+                    unreachable!("operator without a function type")
+                };
+                self.subtype(&self.label_type(e2.label), &parameter);
+                self.subtype(&result, &t);
+                self.constraints(e1);
+                self.constraints(e2);
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                self.subtype(&self.label_type(e1.label), &t);
+                self.subtype(&self.label_type(e2.label), &t);
+                self.constraints(e0);
+                self.constraints(e1);
+                self.constraints(e2);
+            }
+
+            Term::Let(x, e1, e2) => {
+                self.subtype(&self.label_type(e1.label), &self.variable_type(*x));
+                self.subtype(&self.label_type(e2.label), &t);
+                self.constraints(e1);
+                self.constraints(e2);
+            }
+
+            Term::BinaryOp(e1, _, e2) => {
+                self.constraints(e1);
+                self.constraints(e2);
+            }
+        }
+    }
+
+    // Step 4: the least annotations satisfying the inclusions
+    fn solve(&mut self) {
+        let mut work_list: Vec<AnnotationVar> = (0..self.annotations.len()).collect();
+        while let Some(annotation) = work_list.pop() {
+            for i in 0..self.inclusions[annotation].len() {
+                let target = self.inclusions[annotation][i];
+                let labels = self.annotations[annotation].clone();
+                let before = self.annotations[target].len();
+                self.annotations[target].extend(labels);
+                if self.annotations[target].len() != before {
+                    work_list.push(target);
+                }
+            }
+        }
+    }
+
+    fn resolve(
+        &self,
+        annotated: &Annotated,
+        variables: &mut HashMap<TypeVar, usize>,
+    ) -> AnnotatedType {
+        match annotated {
+            Annotated::Int => AnnotatedType::Int,
+            Annotated::Variable(t) => {
+                let next = variables.len();
+                AnnotatedType::Variable(*variables.entry(*t).or_insert(next))
+            }
+            Annotated::Function(parameter, annotation, result) => AnnotatedType::Function(
+                Box::new(self.resolve(parameter, variables)),
+                self.annotations[*annotation].clone(),
+                Box::new(self.resolve(result, variables)),
+            ),
+        }
+    }
+
+    /// the closures a value of type `annotated` may be
+    fn closures_of(&self, annotated: &Annotated) -> HashSet<Term> {
+        match annotated {
+            Annotated::Function(_, annotation, _) => self.annotations[*annotation]
+                .iter()
+                .map(|label| self.closures[label].clone())
+                .collect(),
+            _ => HashSet::new(),
+        }
+    }
+
+    fn solution(&self) -> TypeBasedAnalysis {
+        let mut variables = HashMap::new();
+        let mut labels = Vec::from_iter(&self.annotated_labels);
+        labels.sort_by_key(|(label, _)| **label);
+
+        let mut analysis = TypeBasedAnalysis {
+            types: HashMap::new(),
+            variable_types: HashMap::new(),
+            cache: AbstractCache::new(),
+            env: AbstractEnv::new(),
+        };
+        for (&label, annotated) in labels {
+            let resolved = self.resolve(annotated, &mut variables);
+            analysis.types.insert(label, resolved);
+            analysis.cache.insert(label, self.closures_of(annotated));
+        }
+        for (&x, annotated) in &self.annotated_variables {
+            let resolved = self.resolve(annotated, &mut variables);
+            analysis.variable_types.insert(x, resolved);
+            analysis.env.insert(x, self.closures_of(annotated));
+        }
+
+        analysis
+    }
+}
+
+/// infers the annotated types of `expr`, if it is typable with simple types
+pub fn analyse_types(expr: &Expression) -> Result<TypeBasedAnalysis, TypeError> {
+    let mut inference = Inference::new(expr);
+    inference.simple_types(expr)?;
+    inference.annotate_all(expr)?;
+    inference.constraints(expr);
+    inference.solve();
+    Ok(inference.solution())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser::examples};
+
+    fn type_of(typing: &TypeBasedAnalysis, label: Label) -> String {
+        typing.types[&label].to_string()
+    }
+
+    #[test]
+    fn same_sets_as_the_constraints_on_typable_examples() {
+        for program in &examples()[..3] {
+            let typing = analyse_types(program).unwrap();
+            let (cache, env) = analyse(program, &program.constraint_system());
+            assert_eq!(typing.cache, cache, "{program}");
+            assert_eq!(typing.env, env, "{program}");
+        }
+    }
+
+    #[test]
+    fn annotations_of_example1() {
+        let typing = analyse_types(&examples()[0]).unwrap();
+        assert_eq!(type_of(&typing, 2), "('a -{4}-> 'a) -{2}-> 'a -{4}-> 'a");
+        assert_eq!(type_of(&typing, 5), "'a -{4}-> 'a");
+        assert_eq!(typing.variable_types[&'y'].to_string(), "'a");
+    }
+
+    #[test]
+    fn annotations_of_example2() {
+        let typing = analyse_types(&examples()[1]).unwrap();
+        // `x` is bound to both `g` and `h`, but each use of `f` only to one of them
+        assert_eq!(
+            typing.variable_types[&'x'].to_string(),
+            "int -{8, 12}-> int"
+        );
+        assert_eq!(type_of(&typing, 13), "(int -{8}-> int) -{4}-> int");
+        assert_eq!(type_of(&typing, 16), "(int -{12}-> int) -{4}-> int");
+        assert_eq!(type_of(&typing, 22), "int");
+    }
+
+    #[test]
+    fn unbound_variable_has_no_closures() {
+        let typing = analyse_types(&examples()[2]).unwrap();
+        assert_eq!(typing.variable_types[&'h'].to_string(), "int -{}-> int");
+        assert!(typing.env[&'h'].is_empty());
+    }
+
+    #[test]
+    fn self_application_is_not_typable() {
+        assert_eq!(
+            analyse_types(&examples()[3]).err(),
+            Some(TypeError::Infinite(1))
+        );
+    }
+
+    #[test]
+    fn type_variables_beyond_z_are_numbered() {
+        let names = [0, 25, 26, 53].map(|n| AnnotatedType::Variable(n).to_string());
+        assert_eq!(names, ["'a", "'z", "'a1", "'b2"]);
+    }
+}
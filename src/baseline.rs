@@ -0,0 +1,166 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::{CallSite, ConSet, Constraint, ConstraintSystem},
+    expression::Expression,
+    term::Term,
+    types::Label,
+};
+
+type NodeData = HashMap<ConSet, HashSet<Term>>;
+type Edges = HashMap<ConSet, HashSet<Constraint>>;
+
+#[inline(always)]
+fn add(
+    index: &ConSet,
+    terms: HashSet<Term>,
+    node_data: &mut NodeData,
+    work_list: &mut Vec<ConSet>,
+) {
+    if !terms.is_subset(&node_data[index]) {
+        node_data.get_mut(index).unwrap().extend(terms);
+        work_list.insert(0, index.clone());
+    }
+}
+
+/**
+ * the worklist solver as it was before closures were interned: every node holds a
+ * `HashSet<Term>`, which is cloned whole on every propagation, and the worklist is a
+ * `Vec` taken from the front; the constraints of call sites are already instantiated
+ * on demand
+ *
+ * only kept as a baseline for `--bench` and to check that the current solver finds
+ * the same solution; gives up once it has taken longer than `limit`
+ */
+pub fn solve_baseline_within(
+    expr: &Expression,
+    system: &ConstraintSystem,
+    limit: Option<Duration>,
+) -> Option<(AbstractCache, AbstractEnv)> {
+    let start = Instant::now();
+    let nodes: HashSet<ConSet> = expr
+        .labels()
+        .iter()
+        .map(|l| ConSet::Cache(*l))
+        .chain(expr.variables().iter().map(|x| ConSet::Env(*x)))
+        .collect();
+
+    // Step 1: Initialization
+    let mut work_list: Vec<ConSet> = Vec::new();
+
+    let (mut node_data, mut edges): (NodeData, Edges) = nodes
+        .iter()
+        .map(|q| ((q.clone(), HashSet::new()), (q.clone(), HashSet::new())))
+        .unzip();
+
+    let mut call_sites: HashMap<ConSet, Vec<&CallSite>> = HashMap::new();
+    for call_site in &system.call_sites {
+        call_sites
+            .entry(ConSet::Cache(call_site.operator))
+            .or_default()
+            .push(call_site);
+    }
+    // closures for which a call site's constraints have already been instantiated
+    let mut instantiated: HashSet<(Label, Term)> = HashSet::new();
+
+    // Step 2: Building the graph
+    for constraint in &system.constraints {
+        use ConSet::*;
+        use Constraint::*;
+        match &constraint {
+            Unconditional(p1, p2) => match p1 {
+                SingleTerm(t) => add(
+                    p2,
+                    HashSet::from([t.clone()]),
+                    &mut node_data,
+                    &mut work_list,
+                ),
+                _ => {
+                    edges.get_mut(p1).unwrap().insert(constraint.clone());
+                }
+            },
+
+            Conditional((_t, p), p1, _p2) => {
+                edges.get_mut(p1).unwrap().insert(constraint.clone());
+                edges.get_mut(p).unwrap().insert(constraint.clone());
+            }
+        }
+    }
+
+    // Step 3: Iteration
+    while !work_list.is_empty() {
+        if limit.is_some_and(|limit| start.elapsed() > limit) {
+            return None;
+        }
+        let q = work_list.remove(0);
+
+        // instantiate the constraints of call sites reached by new closures
+        for call_site in call_sites.get(&q).into_iter().flatten() {
+            let new_closures: Vec<Term> = node_data[&q]
+                .iter()
+                .filter(|t| !instantiated.contains(&(call_site.label, (*t).clone())))
+                .cloned()
+                .collect();
+
+            for t in new_closures {
+                if let Some(constraints) = call_site.instantiate(&t) {
+                    for constraint in constraints {
+                        if let Constraint::Unconditional(p1, p2) = &constraint {
+                            add(p2, node_data[p1].clone(), &mut node_data, &mut work_list);
+                            edges.get_mut(p1).unwrap().insert(constraint);
+                        }
+                    }
+                }
+                instantiated.insert((call_site.label, t));
+            }
+        }
+
+        for constraint in &edges[&q] {
+            use Constraint::*;
+            match &constraint {
+                Unconditional(p1, p2) => {
+                    add(
+                        p2,
+                        node_data.get(p1).unwrap().clone(),
+                        &mut node_data,
+                        &mut work_list,
+                    );
+                }
+
+                Conditional((t, p), p1, p2) => {
+                    if node_data[p].contains(t) {
+                        add(p2, node_data[p1].clone(), &mut node_data, &mut work_list)
+                    }
+                }
+            }
+        }
+    }
+
+    // Step 4: Recording the solution
+    let mut cache: AbstractCache = AbstractCache::new();
+    let mut env: AbstractEnv = AbstractEnv::new();
+    for (key, value) in node_data {
+        use ConSet::*;
+        match key {
+            Cache(l) => cache.insert(l, value),
+            Env(x) => env.insert(x, value),
+
+            _ => panic!("Non-label/variable key in node_data: {:?}", key),
+        };
+    }
+
+    Some((cache, env))
+}
+
+/// `solve_baseline_within` without a time limit
+#[cfg(test)]
+pub fn solve_baseline(
+    expr: &Expression,
+    system: &ConstraintSystem,
+) -> (AbstractCache, AbstractEnv) {
+    solve_baseline_within(expr, system, None).expect("no time limit")
+}
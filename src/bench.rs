@@ -0,0 +1,255 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    analysis::{analyse, solve, solve_parallel, solve_without_differences},
+    baseline::solve_baseline_within,
+    defunctionalize::{defunctionalize, run as run_defunctionalized, same_outcome},
+    generator::{random_edit, random_program, random_source, Rng},
+    incremental::{constraints_by_label, reanalyse},
+    interpreter::{evaluate, observe_flows, unsound_flows},
+    parser,
+    reference::solve_naive,
+    unification::unify,
+};
+
+const SIZES: &[usize] = &[100, 200, 400, 1000, 2000, 4000];
+const PROGRAMS_PER_SIZE: u64 = 5;
+const THREADS: usize = 4;
+/// the largest size the previous solver is timed on by default, as it takes seconds
+/// beyond that
+const BASELINE_MAX_SIZE: usize = 200;
+/// how long the previous solver may take on one of the larger programs before it is
+/// given up on
+const BASELINE_TIME_LIMIT: Duration = Duration::from_secs(60);
+
+const CROSS_CHECK_SIZES: &[usize] = &[10, 20, 50, 100];
+const CROSS_CHECK_PROGRAMS_PER_SIZE: u64 = 50;
+const SOUNDNESS_PROGRAMS: u64 = 2000;
+const INCREMENTAL_SIZE: usize = 200;
+const INCREMENTAL_EDITS: u64 = 200;
+
+/// times the solver on random programs with thousands of labels, and the previous
+/// solver on the smaller ones (with `all_baseline`, on all of them until it takes longer
+/// than `BASELINE_TIME_LIMIT` on one)
+///
+/// also checks that the parallel solver finds the same solution as the sequential one
+pub fn run(all_baseline: bool) {
+    println!(
+        "{:>8} {:>8} {:>12} {:>12} {:>10} {:>12} {:>12} {:>12} {:>12}",
+        "labels",
+        "programs",
+        "previous",
+        "time/program",
+        "collapsed",
+        "transfers",
+        "whole sets",
+        format!("{THREADS} threads"),
+        "unification"
+    );
+
+    for &size in SIZES {
+        let mut total = Duration::ZERO;
+        let mut baseline_total = Duration::ZERO;
+        let mut baseline_timed_out = false;
+        let mut parallel_total = Duration::ZERO;
+        let mut unification_total = Duration::ZERO;
+        let mut labels = 0;
+        let mut collapsed = 0;
+        let (mut transfers, mut whole_set_transfers) = (0, 0);
+
+        for seed in 0..PROGRAMS_PER_SIZE {
+            let program = random_program(&mut Rng::new(seed), size);
+            labels += program.labels().len();
+
+            let start = Instant::now();
+            let system = program.constraint_system();
+            let solution = solve(&program, &system);
+            total += start.elapsed();
+
+            collapsed += solution.statistics.collapsed_nodes;
+            transfers += solution.statistics.transfers;
+            whole_set_transfers += solve_without_differences(&program, &system)
+                .statistics
+                .transfers;
+
+            if (size <= BASELINE_MAX_SIZE || all_baseline) && !baseline_timed_out {
+                let start = Instant::now();
+                let limit = (size > BASELINE_MAX_SIZE).then_some(BASELINE_TIME_LIMIT);
+                baseline_timed_out = solve_baseline_within(&program, &system, limit).is_none();
+                baseline_total += start.elapsed();
+            }
+
+            let start = Instant::now();
+            let parallel = solve_parallel(&program, &system, THREADS);
+            parallel_total += start.elapsed();
+            assert!(
+                parallel.node_data == solution.node_data,
+                "the parallel solver disagrees with the sequential one on program {seed} of size {size}"
+            );
+
+            let start = Instant::now();
+            unify(&program);
+            unification_total += start.elapsed();
+        }
+
+        let baseline = if baseline_timed_out {
+            format!("> {BASELINE_TIME_LIMIT:?}")
+        } else if size <= BASELINE_MAX_SIZE || all_baseline {
+            format!("{:.2?}", baseline_total / PROGRAMS_PER_SIZE as u32)
+        } else {
+            "–".to_string()
+        };
+        println!(
+            "{:>8} {:>8} {baseline:>12} {:>12.2?} {:>10} {:>12} {:>12} {:>12.2?} {:>12.2?}",
+            labels as u64 / PROGRAMS_PER_SIZE,
+            PROGRAMS_PER_SIZE,
+            total / PROGRAMS_PER_SIZE as u32,
+            collapsed as u64 / PROGRAMS_PER_SIZE,
+            transfers as u64 / PROGRAMS_PER_SIZE,
+            whole_set_transfers as u64 / PROGRAMS_PER_SIZE,
+            parallel_total / PROGRAMS_PER_SIZE as u32,
+            unification_total / PROGRAMS_PER_SIZE as u32
+        );
+    }
+}
+
+/// compares `analyse` with the naive reference solver on random programs, and fails if
+/// they disagree on any of them
+pub fn cross_check() {
+    for &size in CROSS_CHECK_SIZES {
+        for seed in 0..CROSS_CHECK_PROGRAMS_PER_SIZE {
+            let program = random_program(&mut Rng::new(seed), size);
+            let solution = analyse(&program, &program.constraint_system());
+            let reference = solve_naive(&program, &program.constraints());
+            assert!(
+                solution == reference,
+                "the solver disagrees with the reference solver on program {seed} of size {size}"
+            );
+        }
+        println!("size {size:>4}: same solution on {CROSS_CHECK_PROGRAMS_PER_SIZE} programs");
+    }
+}
+
+/// runs random programs and checks that `analyse` has every closure flow seen at run
+/// time, and fails with the first program where it does not
+pub fn check_soundness() {
+    let mut rng = Rng::new(0);
+    let (mut observed, mut finished) = (0, 0);
+
+    for _ in 0..SOUNDNESS_PROGRAMS {
+        // bind every variable to the identity, so that fewer runs stop at a free variable
+        let mut source = random_source(&mut rng, 30);
+        for x in ('a'..='z').rev() {
+            source = format!("let {x} = (fn {x} -> {x}) in ({source})");
+        }
+        let program = parser::parse(&format!("{source} ")).expect("generated program should parse");
+        let (cache, env) = analyse(&program, &program.constraint_system());
+
+        let (observations, result) = observe_flows(&program);
+        let unsound = unsound_flows(&program, &observations, &cache, &env);
+        if let Some(flow) = unsound.first() {
+            panic!(
+                "soundness bug: {} is seen in {} when running, but not in the analysis of\n{program:#}",
+                flow.closure, flow.node
+            );
+        }
+
+        observed += observations.cache.values().map(HashSet::len).sum::<usize>();
+        finished += result.is_ok() as usize;
+    }
+
+    println!(
+        "{SOUNDNESS_PROGRAMS} programs ({finished} ran to completion): \
+         all {observed} closures seen at labels are in the analysis"
+    );
+}
+
+/// defunctionalizes random programs with `analyse` and checks that they behave like
+/// the originals, and fails with the first program where they do not
+pub fn check_defunctionalization() {
+    let mut rng = Rng::new(0);
+    let (mut compared, mut finished) = (0, 0);
+
+    for _ in 0..SOUNDNESS_PROGRAMS {
+        let mut source = random_source(&mut rng, 30);
+        for x in ('a'..='z').rev() {
+            source = format!("let {x} = (fn {x} -> {x}) in ({source})");
+        }
+        let program = parser::parse(&format!("{source} ")).expect("generated program should parse");
+        let (cache, _) = analyse(&program, &program.constraint_system());
+
+        let first_order = defunctionalize(&program, &cache);
+        let original = evaluate(&program);
+        let result = run_defunctionalized(&first_order);
+        match same_outcome(&original, &result) {
+            Some(true) => compared += 1,
+            Some(false) => panic!(
+                "the defunctionalized program gives {result:?} instead of {original:?} for\n{program:#}"
+            ),
+            None => continue,
+        }
+        finished += original.is_ok() as usize;
+    }
+
+    println!(
+        "{SOUNDNESS_PROGRAMS} programs: the {compared} that stopped within the step limit \
+         ({finished} with a value) behave the same when defunctionalized"
+    );
+}
+
+/// re-analyses random edits of generated programs incrementally, and fails with the
+/// first edit where the result differs from analysing the edited program from scratch
+///
+/// also times both ways of analysing the edited program
+pub fn check_incremental() {
+    let mut rng = Rng::new(0);
+    let (mut labels, mut regenerated, mut reused_nodes, mut nodes) = (0, 0, 0, 0);
+    let (mut incremental_total, mut scratch_total) = (Duration::ZERO, Duration::ZERO);
+    let (mut iterations, mut scratch_iterations) = (0, 0);
+
+    for _ in 0..INCREMENTAL_EDITS {
+        let (source, edited) = random_edit(&mut rng, INCREMENTAL_SIZE);
+        let old = parser::parse(&source).expect("generated program should parse");
+        let (old_cache, old_env) = analyse(&old, &old.constraint_system());
+        let old_constraints = constraints_by_label(&old);
+        let edited = parser::parse(&edited).expect("generated program should parse");
+
+        let start = Instant::now();
+        let result = reanalyse(&old, &old_constraints, &old_cache, &old_env, edited);
+        incremental_total += start.elapsed();
+
+        let start = Instant::now();
+        let system = result.program.constraint_system();
+        let scratch = analyse(&result.program, &system);
+        scratch_total += start.elapsed();
+        assert!(
+            (&result.cache, &result.env) == (&scratch.0, &scratch.1),
+            "re-analysing incrementally differs from analysing from scratch after editing\n\
+             {source}\ninto\n{:#}",
+            result.program
+        );
+
+        labels += result.constraints.len();
+        regenerated += result.regenerated;
+        reused_nodes += result.reused_nodes;
+        nodes += result.reused_nodes + result.recomputed_nodes;
+        iterations += result.iterations;
+        scratch_iterations += solve(&result.program, &system).statistics.iterations;
+    }
+
+    println!(
+        "{INCREMENTAL_EDITS} edits: same solution as from scratch, generated the constraints \
+         of {regenerated} of {labels} subexpressions and solved {} of {nodes} nodes again",
+        nodes - reused_nodes
+    );
+    println!(
+        "{iterations} worklist iterations instead of {scratch_iterations}; {:.2?} per edit \
+         instead of {:.2?} from scratch ({:.2}x as fast)",
+        incremental_total / INCREMENTAL_EDITS as u32,
+        scratch_total / INCREMENTAL_EDITS as u32,
+        scratch_total.as_secs_f64() / incremental_total.as_secs_f64()
+    );
+}
@@ -0,0 +1,84 @@
+/// fixed-capacity set of small integers, stored as one bit per element
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            words: vec![0; capacity.div_ceil(64)],
+        }
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        self.words[i / 64] & (1 << (i % 64)) != 0
+    }
+
+    /// returns whether `i` was newly inserted
+    pub fn insert(&mut self, i: usize) -> bool {
+        let word = &mut self.words[i / 64];
+        let was_absent = *word & (1 << (i % 64)) == 0;
+        *word |= 1 << (i % 64);
+        was_absent
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    /// adds all elements of `other`; returns whether `self` changed
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (word, other_word) in self.words.iter_mut().zip(&other.words) {
+            let new_word = *word | other_word;
+            changed |= new_word != *word;
+            *word = new_word;
+        }
+        changed
+    }
+
+    /// adds all elements of `other`, also recording the ones that were new in `added`;
+    /// returns whether `self` changed
+    pub fn union_tracking(&mut self, other: &BitSet, added: &mut BitSet) -> bool {
+        let mut changed = false;
+        for ((word, other_word), added_word) in self
+            .words
+            .iter_mut()
+            .zip(&other.words)
+            .zip(&mut added.words)
+        {
+            let new_bits = other_word & !*word;
+            *word |= new_bits;
+            *added_word |= new_bits;
+            changed |= new_bits != 0;
+        }
+        changed
+    }
+
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&word| word == 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            let mut word = word;
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(i * 64 + bit)
+            })
+        })
+    }
+}
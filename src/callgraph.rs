@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use crate::{
+    analysis::AbstractCache, dot::quoted, expression::Expression, term::Term, types::Label,
+};
+
+/// an application and the closures it may call
+pub struct Call {
+    pub label: Label,
+    /// the closure whose body contains the application, `None` at the top level
+    pub caller: Option<Label>,
+    /// labels of the closures that may reach the operator
+    pub callees: Vec<Label>,
+    /// whether a callee is on a cycle of calls with the caller
+    pub recursive: bool,
+}
+
+/// which closures each application may call, according to an analysis
+pub struct CallGraph {
+    /// every application, in order of labels
+    pub calls: Vec<Call>,
+    /// the closures of the program, by label
+    pub closures: HashMap<Label, Term>,
+    /// sets of closures that may call each other (directly or through others), each
+    /// ordered by label; a single closure if it may call itself
+    pub cycles: Vec<Vec<Label>>,
+}
+
+impl CallGraph {
+    /// applications that no closure reaches, so that they can never call anything
+    pub fn unreached(&self) -> Vec<Label> {
+        self.calls
+            .iter()
+            .filter(|call| call.callees.is_empty())
+            .map(|call| call.label)
+            .collect()
+    }
+
+    pub fn text(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for call in &self.calls {
+            let caller = match call.caller {
+                Some(caller) => format!("in closure {caller}"),
+                None => "at top level".to_string(),
+            };
+            let callees = if call.callees.is_empty() {
+                "nothing".to_string()
+            } else {
+                labels(&call.callees)
+            };
+            let recursive = if call.recursive { " (recursive)" } else { "" };
+            lines.push(format!(
+                "  call {} {caller}: {callees}{recursive}",
+                call.label
+            ));
+        }
+
+        let unreached = self.unreached();
+        if !unreached.is_empty() {
+            lines.push(format!(
+                "  calls no closure reaches: {}",
+                labels(&unreached)
+            ));
+        }
+        let cycles: Vec<String> = self
+            .cycles
+            .iter()
+            .map(|cycle| format!("{{{}}}", labels(cycle)))
+            .collect();
+        if !cycles.is_empty() {
+            lines.push(format!("  recursive cycles: {}", cycles.join(", ")));
+        }
+        lines
+    }
+
+    /// the closures (and the top level as `main`) are the nodes, and there is an edge
+    /// labelled with the application for every closure an application may call
+    ///
+    /// edges on recursive cycles are bold, and applications no closure reaches are
+    /// dashed boxes
+    pub fn dot(&self) -> Vec<String> {
+        let node = |closure: Option<Label>| match closure {
+            Some(label) => quoted(&label.to_string()),
+            None => quoted("main"),
+        };
+
+        let mut lines = vec!["digraph calls {".to_string(), format!("  {};", node(None))];
+        let mut closures = Vec::from_iter(&self.closures);
+        closures.sort_by_key(|(label, _)| **label);
+        for (label, closure) in closures {
+            lines.push(format!(
+                "  {} [label={}];",
+                node(Some(*label)),
+                quoted(&format!("{label}: {closure}"))
+            ));
+        }
+
+        for call in &self.calls {
+            let style = if call.recursive { ", style=bold" } else { "" };
+            for &callee in &call.callees {
+                lines.push(format!(
+                    "  {} -> {} [label={}{style}];",
+                    node(call.caller),
+                    node(Some(callee)),
+                    quoted(&call.label.to_string())
+                ));
+            }
+
+            if call.callees.is_empty() {
+                let unreached = quoted(&format!("call {}", call.label));
+                lines.push(format!("  {unreached} [shape=box, style=dashed];"));
+                lines.push(format!(
+                    "  {} -> {unreached} [style=dashed];",
+                    node(call.caller)
+                ));
+            }
+        }
+
+        lines.push("}".to_string());
+        lines
+    }
+
+    /// one JSON object with `calls` (`label`, `caller` or `null`, `callees`,
+    /// `recursive`), `unreached` and `cycles`, all by labels
+    pub fn json(&self) -> String {
+        let calls: Vec<String> = self
+            .calls
+            .iter()
+            .map(|call| {
+                let caller = call.caller.map_or("null".to_string(), |c| c.to_string());
+                format!(
+                    r#"{{"label":{},"caller":{caller},"callees":{},"recursive":{}}}"#,
+                    call.label,
+                    json_labels(&call.callees),
+                    call.recursive
+                )
+            })
+            .collect();
+        let cycles: Vec<String> = self.cycles.iter().map(|c| json_labels(c)).collect();
+
+        format!(
+            r#"{{"calls":[{}],"unreached":{},"cycles":[{}]}}"#,
+            calls.join(","),
+            json_labels(&self.unreached()),
+            cycles.join(",")
+        )
+    }
+}
+
+fn labels(labels: &[Label]) -> String {
+    labels
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn json_labels(labels: &[Label]) -> String {
+    let labels = labels.iter().map(ToString::to_string).collect::<Vec<_>>();
+    format!("[{}]", labels.join(","))
+}
+
+/// the applications in `expr` with the innermost closure around each of them
+fn applications<'a>(
+    expr: &'a Expression,
+    caller: Option<Label>,
+    calls: &mut Vec<(&'a Expression, Option<Label>)>,
+) {
+    match &expr.term {
+        Term::Constant(_) | Term::Variable(_) => {}
+        Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+            applications(e0, Some(expr.label), calls)
+        }
+        Term::Application(e1, e2) => {
+            calls.push((expr, caller));
+            applications(e1, caller, calls);
+            applications(e2, caller, calls);
+        }
+        Term::IfThenElse(e0, e1, e2) => {
+            for e in [e0, e1, e2] {
+                applications(e, caller, calls);
+            }
+        }
+        Term::Let(_, e1, e2) | Term::BinaryOp(e1, _, e2) => {
+            applications(e1, caller, calls);
+            applications(e2, caller, calls);
+        }
+    }
+}
+
+/// Tarjan's algorithm: the strongly connected components of `edges` (by closure label)
+/// that contain a cycle
+///
+/// the depth-first search keeps its own stack, so that long chains of calls do not
+/// overflow the call stack
+fn cycles(edges: &HashMap<Label, Vec<Label>>, nodes: &[Label]) -> Vec<Vec<Label>> {
+    struct Search<'a> {
+        edges: &'a HashMap<Label, Vec<Label>>,
+        index: HashMap<Label, usize>,
+        lowlink: HashMap<Label, usize>,
+        stack: Vec<Label>,
+        on_stack: HashMap<Label, bool>,
+        components: Vec<Vec<Label>>,
+    }
+
+    impl Search<'_> {
+        fn callees(&self, v: Label) -> &[Label] {
+            self.edges.get(&v).map_or(&[], Vec::as_slice)
+        }
+
+        fn discover(&mut self, v: Label) {
+            let index = self.index.len();
+            self.index.insert(v, index);
+            self.lowlink.insert(v, index);
+            self.stack.push(v);
+            self.on_stack.insert(v, true);
+        }
+
+        /// once all edges of `v` have been followed
+        fn finish(&mut self, v: Label) {
+            if self.lowlink[&v] == self.index[&v] {
+                let mut component = vec![];
+                while let Some(w) = self.stack.pop() {
+                    self.on_stack.insert(w, false);
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                let calls_itself = self.callees(v).contains(&v);
+                if component.len() > 1 || calls_itself {
+                    component.sort();
+                    self.components.push(component);
+                }
+            }
+        }
+
+        fn visit(&mut self, root: Label) {
+            // the nodes being visited, each with how many of its edges have been followed
+            let mut path = vec![(root, 0)];
+            self.discover(root);
+
+            while let Some((v, followed)) = path.last_mut() {
+                let v = *v;
+                if let Some(&w) = self.callees(v).get(*followed) {
+                    *followed += 1;
+                    if !self.index.contains_key(&w) {
+                        self.discover(w);
+                        path.push((w, 0));
+                    } else if self.on_stack[&w] {
+                        self.lowlink.insert(v, self.lowlink[&v].min(self.index[&w]));
+                    }
+                    continue;
+                }
+
+                path.pop();
+                self.finish(v);
+                if let Some(&(parent, _)) = path.last() {
+                    self.lowlink
+                        .insert(parent, self.lowlink[&parent].min(self.lowlink[&v]));
+                }
+            }
+        }
+    }
+
+    let mut search = Search {
+        edges,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        stack: vec![],
+        on_stack: HashMap::new(),
+        components: vec![],
+    };
+    for &node in nodes {
+        if !search.index.contains_key(&node) {
+            search.visit(node);
+        }
+    }
+
+    search.components.sort();
+    search.components
+}
+
+/// the call graph of `expr` given the sets `C(l)` of an analysis
+pub fn call_graph(expr: &Expression, cache: &AbstractCache) -> CallGraph {
+    let closure_labels = expr.closures();
+    let closures: HashMap<Label, Term> = closure_labels
+        .iter()
+        .map(|(&t, &label)| (label, t.clone()))
+        .collect();
+
+    let mut applications_found = vec![];
+    applications(expr, None, &mut applications_found);
+    applications_found.sort_by_key(|(e, _)| e.label);
+
+    let mut calls: Vec<Call> = applications_found
+        .into_iter()
+        .map(|(e, caller)| {
+            let Term::Application(e1, _) = &e.term else {
+                unreachable!("found a non-application")
+            };
+            let mut callees: Vec<Label> = cache
+                .get(&e1.label)
+                .into_iter()
+                .flatten()
+                .filter_map(|t| closure_labels.get(t).copied())
+                .collect();
+            callees.sort();
+            Call {
+                label: e.label,
+                caller,
+                callees,
+                recursive: false,
+            }
+        })
+        .collect();
+
+    let mut edges: HashMap<Label, Vec<Label>> = HashMap::new();
+    for call in &calls {
+        if let Some(caller) = call.caller {
+            edges.entry(caller).or_default().extend(&call.callees);
+        }
+    }
+    let mut nodes = Vec::from_iter(closures.keys().copied());
+    nodes.sort();
+    let cycles = cycles(&edges, &nodes);
+
+    for call in &mut calls {
+        call.recursive = call.caller.is_some_and(|caller| {
+            cycles.iter().any(|cycle| {
+                cycle.contains(&caller) && call.callees.iter().any(|c| cycle.contains(c))
+            })
+        });
+    }
+
+    CallGraph {
+        calls,
+        closures,
+        cycles,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        parser::{examples, parse},
+        pruning::{analyse_pruned, Prune},
+    };
+
+    fn graph(source: &str) -> CallGraph {
+        let program = parse(source).unwrap();
+        let (cache, _) = analyse(&program, &program.constraint_system());
+        call_graph(&program, &cache)
+    }
+
+    #[test]
+    fn recursive_closure_calls_itself() {
+        let graph = graph("let g = fun f x -> (f x) in (g 1) ");
+        assert_eq!(
+            graph.text(),
+            [
+                "  call 3 in closure 4: 4 (recursive)",
+                "  call 7 at top level: 4",
+                "  recursive cycles: {4}",
+            ]
+        );
+    }
+
+    #[test]
+    fn mutual_recursion_through_let() {
+        // `b` passes itself to `a`, which calls it
+        let graph = graph("let a = fn x -> (x 1) in let b = fn y -> (a b) in (b 2) ");
+        assert_eq!(graph.cycles, [vec![4, 8]]);
+        let recursive: Vec<(Label, bool)> =
+            graph.calls.iter().map(|c| (c.label, c.recursive)).collect();
+        assert_eq!(recursive, [(3, true), (7, true), (11, false)]);
+    }
+
+    #[test]
+    fn call_in_dead_branch_of_example3_is_unreached() {
+        let program = &examples()[2];
+        let prune = Prune {
+            branches: true,
+            closure_bodies: false,
+        };
+        let analysis = analyse_pruned(program, prune, 1);
+        let graph = call_graph(program, &analysis.cache);
+        assert_eq!(graph.unreached(), [23]);
+        assert_eq!(
+            graph.text().last().unwrap(),
+            "  calls no closure reaches: 23"
+        );
+        assert!(graph
+            .dot()
+            .contains(&r#"  "call 23" [shape=box, style=dashed];"#.to_string()));
+    }
+
+    #[test]
+    fn json_output() {
+        let graph = graph("let g = fun f x -> (f x) in (g h) ");
+        assert_eq!(
+            graph.json(),
+            r#"{"calls":[{"label":3,"caller":4,"callees":[4],"recursive":true},{"label":7,"caller":null,"callees":[4],"recursive":false}],"unreached":[],"cycles":[[4]]}"#
+        );
+    }
+
+    #[test]
+    fn long_chains_of_calls_do_not_overflow() {
+        let nodes: Vec<Label> = (0..100_000).collect();
+        let mut edges: HashMap<Label, Vec<Label>> =
+            nodes.iter().map(|&n| (n, vec![n + 1])).collect();
+        edges.insert(nodes.len() - 1, vec![0]);
+        assert_eq!(cycles(&edges, &nodes), [nodes]);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+    analysis::AbstractCache, domain::AbstractValue, expression::Expression, pruning::values,
+    term::Term, types::Label,
+};
+
+/// how many closures may be called at an application
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CallKind {
+    /// no closure reaches the operator, so the call never succeeds
+    Dead,
+    /// exactly one closure, so the call could be made direct or inlined
+    Monomorphic,
+    Polymorphic,
+}
+
+impl Display for CallKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dead => write!(f, "dead"),
+            Self::Monomorphic => write!(f, "monomorphic"),
+            Self::Polymorphic => write!(f, "polymorphic"),
+        }
+    }
+}
+
+/// an application classified by the closures in `C(e1)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassifiedCall {
+    pub label: Label,
+    pub kind: CallKind,
+    /// the closures that may be called
+    pub closures: Vec<Term>,
+    /// the base values (constants or results of operators) that may reach the operator,
+    /// if any, which would be a run-time error
+    pub non_closures: Option<AbstractValue>,
+}
+
+/**
+ * every application outside `excluded` (e.g. unreachable code), in order of labels,
+ * classified by how many closures the analysis finds for its operator
+ *
+ * the base values reaching the operator come from the same abstract values as branch
+ * pruning (`pruning::values`), so they are only as precise as the closures in `cache`;
+ * that free variables may be any integer there is what flags calls of them
+ */
+pub fn classify_calls(
+    expr: &Expression,
+    cache: &AbstractCache,
+    excluded: &HashSet<Label>,
+) -> Vec<ClassifiedCall> {
+    let (value_cache, _) = values(expr, cache, excluded);
+
+    let mut calls: Vec<ClassifiedCall> = expr
+        .subexprs_excluding(excluded)
+        .into_iter()
+        .filter_map(|e| match &e.term {
+            Term::Application(e1, _) => {
+                let mut closures = Vec::from_iter(cache[&e1.label].iter().cloned());
+                closures.sort_by_key(ToString::to_string);
+                let kind = match closures.len() {
+                    0 => CallKind::Dead,
+                    1 => CallKind::Monomorphic,
+                    _ => CallKind::Polymorphic,
+                };
+                let value = &value_cache[&e1.label];
+                Some(ClassifiedCall {
+                    label: e.label,
+                    kind,
+                    closures,
+                    non_closures: (!value.is_bottom()).then(|| value.clone()),
+                })
+            }
+            _ => None,
+        })
+        .collect();
+
+    calls.sort_by_key(|call| call.label);
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        parser::{examples, parse},
+    };
+
+    fn classify(program: &Expression) -> Vec<ClassifiedCall> {
+        let (cache, _) = analyse(program, &program.constraint_system());
+        classify_calls(program, &cache, &HashSet::new())
+    }
+
+    fn kinds(calls: &[ClassifiedCall]) -> Vec<(Label, CallKind)> {
+        calls.iter().map(|call| (call.label, call.kind)).collect()
+    }
+
+    #[test]
+    fn applying_a_number_is_flagged() {
+        let calls = classify(&parse("1 2 ").unwrap());
+        assert_eq!(kinds(&calls), [(3, CallKind::Dead)]);
+        assert_eq!(calls[0].non_closures, Some(AbstractValue::constant(1)));
+    }
+
+    #[test]
+    fn applying_a_free_variable_is_flagged() {
+        let calls = classify(&parse("let f = fn x -> x in ((if h then f else 3) (h 1)) ").unwrap());
+        assert_eq!(
+            kinds(&calls),
+            [(9, CallKind::Dead), (10, CallKind::Monomorphic)]
+        );
+        assert_eq!(calls[0].non_closures, Some(AbstractValue::Any));
+        assert_eq!(calls[1].non_closures, Some(AbstractValue::constant(3)));
+    }
+
+    #[test]
+    fn calls_of_example2() {
+        let calls = classify(&examples()[1]);
+        assert_eq!(
+            kinds(&calls),
+            [
+                (3, CallKind::Polymorphic),
+                (15, CallKind::Monomorphic),
+                (18, CallKind::Monomorphic),
+            ]
+        );
+        // `f` is called at both sites, and calls both `g` and `h`
+        assert_eq!(calls[1].closures, calls[2].closures);
+        let called: Vec<String> = calls[0].closures.iter().map(ToString::to_string).collect();
+        assert_eq!(called, ["fn y -> (y⁵ + 2⁶)⁷", "fn z -> (z⁹ + 3¹⁰)¹¹"]);
+        assert!(calls.iter().all(|call| call.non_closures.is_none()));
+    }
+}
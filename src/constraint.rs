@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{collections::HashSet, fmt::Display};
 
 use crate::{
     term::Term,
@@ -43,3 +43,67 @@ impl Display for Constraint {
         }
     }
 }
+
+/// an application `(e1 e2)`: every closure `fn x -> e0` reaching `C(e1)` adds the
+/// constraints `C(e2) ⊆ r(x)` and `C(e0) ⊆ C(label)`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CallSite {
+    pub label: Label,
+    /// label of `e1`
+    pub operator: Label,
+    /// label of `e2`
+    pub operand: Label,
+}
+
+impl CallSite {
+    /// the constraints added once `closure` reaches `C(self.operator)`
+    pub fn instantiate(&self, closure: &Term) -> Option<[Constraint; 2]> {
+        use ConSet::*;
+        use Constraint::*;
+        match closure {
+            Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) => Some([
+                Unconditional(Cache(self.operand), Env(*x)),
+                Unconditional(Cache(e0.label), Cache(self.label)),
+            ]),
+            _ => None,
+        }
+    }
+
+    /// the constraints of the book's formulation: one pair of conditional constraints per
+    /// closure in the program, whether or not it may ever reach the operator position
+    pub fn conditionals(&self, closures: &HashSet<&Term>) -> HashSet<Constraint> {
+        let guard = ConSet::Cache(self.operator);
+
+        closures
+            .iter()
+            .filter_map(|&t| self.instantiate(t).map(|cs| (t, cs)))
+            .flat_map(|(t, cs)| {
+                cs.map(|c| match c {
+                    Constraint::Unconditional(lhs, rhs) => {
+                        Constraint::Conditional((t.clone(), guard.clone()), lhs, rhs)
+                    }
+                    conditional => conditional,
+                })
+            })
+            .collect()
+    }
+}
+
+/// constraints of a program, with applications kept as call sites whose constraints
+/// are only instantiated for closures that actually reach them
+#[derive(Debug, Clone, Default)]
+pub struct ConstraintSystem {
+    pub constraints: HashSet<Constraint>,
+    pub call_sites: Vec<CallSite>,
+}
+
+impl ConstraintSystem {
+    /// all constraints, with every call site expanded into its conditional constraints
+    pub fn expand(&self, closures: &HashSet<&Term>) -> HashSet<Constraint> {
+        let mut constraints = self.constraints.clone();
+        for call_site in &self.call_sites {
+            constraints.extend(call_site.conditionals(closures));
+        }
+        constraints
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    callsites::{classify_calls, CallKind},
+    escape::reachable_from_outside,
+    expression::Expression,
+    term::Term,
+    types::{Constant, Label, Operator, Variable},
+};
+
+/// an expression of the closure-converted program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Low {
+    Int(Constant),
+    /// a parameter, a `let`-bound variable, a temporary or `env`
+    Local(String),
+    /// a free variable of the current code, read from its environment record
+    Field(Variable),
+    /// allocates the environment record of a closure: a pointer to its code (only if the
+    /// closure is not well-known) and the values of its free variables
+    Alloc {
+        closure: Label,
+        code: bool,
+        fields: Vec<(Variable, Low)>,
+    },
+    /// a well-known closure without free variables, which needs no record at all
+    Null,
+    /// calls the code of the closure `label` with a record and an argument
+    DirectCall(Label, Box<Low>, Box<Low>),
+    /// calls the code pointer stored in the record with the record and an argument
+    IndirectCall(Box<Low>, Box<Low>),
+    IfThenElse(Box<Low>, Box<Low>, Box<Low>),
+    Let(String, Box<Low>, Box<Low>),
+    BinaryOp(Box<Low>, Operator, Box<Low>),
+}
+
+/// the top-level code of a closure, taking its environment record as `env`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub closure: Label,
+    pub parameter: Variable,
+    /// every application that may call the closure only calls this closure, so it is
+    /// called directly and its record needs no code pointer
+    pub well_known: bool,
+    /// the free variables, stored in the record
+    pub captured: Vec<Variable>,
+    pub body: Low,
+}
+
+impl Code {
+    /// whether the closure needs no record, being well-known without free variables
+    pub fn without_environment(&self) -> bool {
+        self.well_known && self.captured.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosureConverted {
+    /// in order of the closures' labels
+    pub codes: Vec<Code>,
+    pub main: Low,
+    pub direct_calls: usize,
+    pub indirect_calls: usize,
+}
+
+impl Low {
+    /// on one line, with compound subexpressions in parentheses
+    fn inline(&self) -> String {
+        let operand = |low: &Low| match low {
+            Low::IfThenElse(..) | Low::Let(..) | Low::BinaryOp(..) => format!("({})", low.inline()),
+            _ => low.inline(),
+        };
+
+        match self {
+            Low::Int(c) => c.to_string(),
+            Low::Local(x) => x.clone(),
+            Low::Field(x) => format!("env.{x}"),
+            Low::Alloc {
+                closure,
+                code,
+                fields,
+            } => {
+                let mut entries = vec![];
+                if *code {
+                    entries.push(format!("code{closure}"));
+                }
+                entries.extend(
+                    fields
+                        .iter()
+                        .map(|(x, value)| format!("{x}: {}", value.inline())),
+                );
+                format!("alloc {{{}}}", entries.join(", "))
+            }
+            Low::Null => "null".to_string(),
+            Low::DirectCall(closure, record, argument) => {
+                format!("code{closure}({}, {})", record.inline(), argument.inline())
+            }
+            Low::IndirectCall(record, argument) => {
+                let record = operand(record);
+                format!("{record}.code({record}, {})", argument.inline())
+            }
+            Low::IfThenElse(e0, e1, e2) => format!(
+                "if {} then {} else {}",
+                e0.inline(),
+                e1.inline(),
+                e2.inline()
+            ),
+            Low::Let(x, e1, e2) => format!("let {x} = {} in {}", operand(e1), e2.inline()),
+            Low::BinaryOp(e1, op, e2) => format!("{} {op} {}", operand(e1), operand(e2)),
+        }
+    }
+
+    /// as a block of statements: one `let` per line, `if` with indented branches and
+    /// `return` for the result
+    fn block(&self, indent: usize, lines: &mut Vec<String>) {
+        let pad = " ".repeat(indent);
+        match self {
+            Low::Let(x, e1, e2) => {
+                let e1 = match **e1 {
+                    Low::Let(..) => format!("({})", e1.inline()),
+                    _ => e1.inline(),
+                };
+                lines.push(format!("{pad}let {x} = {e1}"));
+                e2.block(indent, lines);
+            }
+            Low::IfThenElse(e0, e1, e2) => {
+                lines.push(format!("{pad}if {} then", e0.inline()));
+                e1.block(indent + 2, lines);
+                lines.push(format!("{pad}else"));
+                e2.block(indent + 2, lines);
+            }
+            _ => lines.push(format!("{pad}return {}", self.inline())),
+        }
+    }
+}
+
+impl ClosureConverted {
+    /// the code of every closure, then the code of the program itself
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for code in &self.codes {
+            let record = if code.without_environment() {
+                "well-known, no environment".to_string()
+            } else {
+                let mut fields = vec![];
+                if !code.well_known {
+                    fields.push("code".to_string());
+                }
+                fields.extend(code.captured.iter().map(ToString::to_string));
+                let record = format!("environment {{{}}}", fields.join(", "));
+                if code.well_known {
+                    format!("well-known, {record}")
+                } else {
+                    record
+                }
+            };
+            lines.push(format!(
+                "  code{}(env, {}):  ({record})",
+                code.closure, code.parameter
+            ));
+            code.body.block(4, &mut lines);
+        }
+
+        lines.push("  main:".to_string());
+        self.main.block(4, &mut lines);
+        lines
+    }
+}
+
+struct Converter<'a> {
+    /// the closure called directly at each monomorphic application
+    direct: HashMap<Label, Label>,
+    well_known: &'a HashSet<Label>,
+    codes: Vec<Code>,
+    direct_calls: usize,
+    indirect_calls: usize,
+}
+
+impl Converter<'_> {
+    /**
+     * converts `expr` inside the code of a closure with the free variables `captured`
+     * (none for `main`), where `locals` are the variables bound since, innermost last
+     */
+    fn convert(
+        &mut self,
+        expr: &Expression,
+        captured: &[Variable],
+        locals: &mut Vec<Variable>,
+    ) -> Low {
+        match &expr.term {
+            Term::Constant(c) => Low::Int(*c),
+
+            Term::Variable(x) if !locals.contains(x) && captured.contains(x) => Low::Field(*x),
+            Term::Variable(x) => Low::Local(x.to_string()),
+
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+                let (recursive, parameter) = match &expr.term {
+                    Term::Closure(x, _) => (None, *x),
+                    Term::RecursiveClosure(f, x, _) => (Some(*f), *x),
+                    _ => unreachable!("not a closure"),
+                };
+                let mut free = Vec::from_iter(expr.free_variables());
+                free.sort();
+                let fields = free
+                    .iter()
+                    .map(|&x| {
+                        let value = if !locals.contains(&x) && captured.contains(&x) {
+                            Low::Field(x)
+                        } else {
+                            Low::Local(x.to_string())
+                        };
+                        (x, value)
+                    })
+                    .collect();
+
+                let mut body_locals = Vec::from_iter(recursive);
+                body_locals.push(parameter);
+                let mut body = self.convert(e0, &free, &mut body_locals);
+                if let Some(f) = recursive {
+                    body = Low::Let(
+                        f.to_string(),
+                        Box::new(Low::Local("env".to_string())),
+                        Box::new(body),
+                    );
+                }
+
+                let well_known = self.well_known.contains(&expr.label);
+                self.codes.push(Code {
+                    closure: expr.label,
+                    parameter,
+                    well_known,
+                    captured: free.clone(),
+                    body,
+                });
+                if well_known && free.is_empty() {
+                    Low::Null
+                } else {
+                    Low::Alloc {
+                        closure: expr.label,
+                        code: !well_known,
+                        fields,
+                    }
+                }
+            }
+
+            Term::Application(e1, e2) => {
+                let operator = self.convert(e1, captured, locals);
+                let operand = self.convert(e2, captured, locals);
+                match self.direct.get(&expr.label) {
+                    Some(&closure) => {
+                        self.direct_calls += 1;
+                        Low::DirectCall(closure, Box::new(operator), Box::new(operand))
+                    }
+                    None if matches!(operator, Low::Local(_) | Low::Field(_)) => {
+                        self.indirect_calls += 1;
+                        Low::IndirectCall(Box::new(operator), Box::new(operand))
+                    }
+                    // the record is used twice, so it is evaluated into a temporary first
+                    None => {
+                        self.indirect_calls += 1;
+                        let record = format!("c{}", expr.label);
+                        Low::Let(
+                            record.clone(),
+                            Box::new(operator),
+                            Box::new(Low::IndirectCall(
+                                Box::new(Low::Local(record)),
+                                Box::new(operand),
+                            )),
+                        )
+                    }
+                }
+            }
+
+            Term::IfThenElse(e0, e1, e2) => Low::IfThenElse(
+                Box::new(self.convert(e0, captured, locals)),
+                Box::new(self.convert(e1, captured, locals)),
+                Box::new(self.convert(e2, captured, locals)),
+            ),
+
+            Term::Let(x, e1, e2) => {
+                let bound = self.convert(e1, captured, locals);
+                locals.push(*x);
+                let body = self.convert(e2, captured, locals);
+                locals.pop();
+                Low::Let(x.to_string(), Box::new(bound), Box::new(body))
+            }
+
+            Term::BinaryOp(e1, op, e2) => Low::BinaryOp(
+                Box::new(self.convert(e1, captured, locals)),
+                op.clone(),
+                Box::new(self.convert(e2, captured, locals)),
+            ),
+        }
+    }
+}
+
+/**
+ * closure conversion guided by the analysis: every closure becomes top-level code that
+ * takes its environment record, and every closure expression allocates that record
+ *
+ * an application is a direct call if exactly one closure and no other value may reach
+ * its operator, and otherwise calls the code pointer in the record; a closure that is
+ * only ever called at such applications is well-known, so its record holds no code
+ * pointer, and it is not allocated at all if it has no free variables either
+ *
+ * a closure that may be called outside the program (see `reachable_from_outside`) is
+ * never well-known
+ */
+pub fn closure_convert(
+    expr: &Expression,
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+) -> ClosureConverted {
+    let labels = expr.closures();
+
+    let mut direct = HashMap::new();
+    let mut called_indirectly = HashSet::new();
+    for call in classify_calls(expr, cache, &HashSet::new()) {
+        let callees = call.closures.iter().map(|t| labels[t]);
+        if call.kind == CallKind::Monomorphic && call.non_closures.is_none() {
+            direct.extend(callees.map(|closure| (call.label, closure)));
+        } else {
+            called_indirectly.extend(callees);
+        }
+    }
+    let escaping = reachable_from_outside(expr, cache, env);
+    let well_known = labels
+        .values()
+        .copied()
+        .filter(|closure| !called_indirectly.contains(closure) && !escaping.contains(closure))
+        .collect();
+
+    let mut converter = Converter {
+        direct,
+        well_known: &well_known,
+        codes: vec![],
+        direct_calls: 0,
+        indirect_calls: 0,
+    };
+    let main = converter.convert(expr, &[], &mut vec![]);
+    converter.codes.sort_by_key(|code| code.closure);
+
+    ClosureConverted {
+        codes: converter.codes,
+        main,
+        direct_calls: converter.direct_calls,
+        indirect_calls: converter.indirect_calls,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        domain::eval_binary_op,
+        generator::{random_source, Rng},
+        interpreter::{evaluate, RuntimeError, Value},
+        parser,
+        parser::examples,
+    };
+
+    /// run-time value of a closure-converted program
+    #[derive(Debug, Clone)]
+    enum LowValue {
+        Int(Constant),
+        Record {
+            closure: Label,
+            code: bool,
+            fields: Rc<HashMap<Variable, LowValue>>,
+        },
+        Null,
+    }
+
+    /// evaluates closure-converted programs, failing with `None` when out of fuel
+    struct Evaluator<'a> {
+        codes: HashMap<Label, &'a Code>,
+        fuel: usize,
+    }
+
+    impl Evaluator<'_> {
+        fn call(
+            &mut self,
+            closure: Label,
+            record: LowValue,
+            argument: LowValue,
+        ) -> Option<Result<LowValue, ()>> {
+            let code = self.codes[&closure];
+            let fields = match &record {
+                LowValue::Record { fields, .. } => fields.clone(),
+                _ => Rc::default(),
+            };
+            let locals = HashMap::from([
+                ("env".to_string(), record),
+                (code.parameter.to_string(), argument),
+            ]);
+            self.eval(&code.body, &locals, &fields)
+        }
+
+        fn eval(
+            &mut self,
+            low: &Low,
+            locals: &HashMap<String, LowValue>,
+            fields: &HashMap<Variable, LowValue>,
+        ) -> Option<Result<LowValue, ()>> {
+            self.fuel = self.fuel.checked_sub(1)?;
+            let mut eval = |low: &Low| self.eval(low, locals, fields);
+            Some(Ok(match low {
+                Low::Int(c) => LowValue::Int(*c),
+                Low::Local(x) => return Some(locals.get(x).cloned().ok_or(())),
+                Low::Field(x) => return Some(fields.get(x).cloned().ok_or(())),
+                Low::Alloc {
+                    closure,
+                    code,
+                    fields: values,
+                } => {
+                    let mut stored = HashMap::new();
+                    for (x, value) in values {
+                        match eval(value)? {
+                            Ok(value) => stored.insert(*x, value),
+                            Err(()) => return Some(Err(())),
+                        };
+                    }
+                    LowValue::Record {
+                        closure: *closure,
+                        code: *code,
+                        fields: Rc::new(stored),
+                    }
+                }
+                Low::Null => LowValue::Null,
+                Low::DirectCall(closure, record, argument) => {
+                    let (Ok(record), Ok(argument)) = (eval(record)?, eval(argument)?) else {
+                        return Some(Err(()));
+                    };
+                    return self.call(*closure, record, argument);
+                }
+                Low::IndirectCall(record, argument) => {
+                    let (Ok(record), Ok(argument)) = (eval(record)?, eval(argument)?) else {
+                        return Some(Err(()));
+                    };
+                    let LowValue::Record {
+                        closure,
+                        code: true,
+                        ..
+                    } = record
+                    else {
+                        return Some(Err(()));
+                    };
+                    return self.call(closure, record, argument);
+                }
+                Low::IfThenElse(e0, e1, e2) => {
+                    let Ok(LowValue::Int(condition)) = eval(e0)? else {
+                        return Some(Err(()));
+                    };
+                    return eval(if condition != 0 { e1 } else { e2 });
+                }
+                Low::Let(x, e1, e2) => {
+                    let Ok(value) = eval(e1)? else {
+                        return Some(Err(()));
+                    };
+                    let mut locals = locals.clone();
+                    locals.insert(x.clone(), value);
+                    return self.eval(e2, &locals, fields);
+                }
+                Low::BinaryOp(e1, op, e2) => {
+                    let (Ok(LowValue::Int(a)), Ok(LowValue::Int(b))) = (eval(e1)?, eval(e2)?)
+                    else {
+                        return Some(Err(()));
+                    };
+                    match eval_binary_op(a, op, b) {
+                        Ok(c) => LowValue::Int(c),
+                        Err(_) => return Some(Err(())),
+                    }
+                }
+            }))
+        }
+    }
+
+    fn convert(program: &Expression) -> ClosureConverted {
+        let (cache, env) = analyse(program, &program.constraint_system());
+        closure_convert(program, &cache, &env)
+    }
+
+    /// whether the converted program has the same result as the original, `None` if
+    /// either does not finish soon enough
+    fn runs_like_the_original(program: &Expression) -> Option<bool> {
+        let converted = convert(program);
+        let mut evaluator = Evaluator {
+            codes: converted
+                .codes
+                .iter()
+                .map(|code| (code.closure, code))
+                .collect(),
+            fuel: 1000,
+        };
+        let result = evaluator.eval(&converted.main, &HashMap::new(), &HashMap::new())?;
+
+        Some(match (evaluate(program), result) {
+            (Err(RuntimeError::StepLimit), _) => return None,
+            (Ok(Value::Int(a)), Ok(LowValue::Int(b))) => a == b,
+            // the result may be called outside the program, so it needs its code
+            (
+                Ok(Value::Closure(closure, _)),
+                Ok(LowValue::Record {
+                    closure: label,
+                    code,
+                    ..
+                }),
+            ) => closure.label == label && code,
+            (Err(_), Err(())) => true,
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn escaping_closures_are_not_well_known() {
+        for source in ["fn x -> x ", "let y = 3 in fn x -> y "] {
+            let converted = convert(&parser::parse(source).unwrap());
+            assert!(!converted.codes[0].well_known, "{source}");
+            assert!(matches!(
+                converted.main,
+                Low::Alloc { code: true, .. } | Low::Let(..)
+            ));
+        }
+
+        // `g` is called directly, but `f` escapes and may return it
+        let source = "let f = fn x -> (let g = fn y -> y in let z = (g 1) in g) in f ";
+        let converted = convert(&parser::parse(source).unwrap());
+        assert!(converted.codes.iter().all(|code| !code.well_known));
+
+        // `g` is called directly, but also passed to `h`, which may call it
+        let source = "let g = fn y -> y in let z = (h g) in (g 1) ";
+        let converted = convert(&parser::parse(source).unwrap());
+        assert!(!converted.codes[0].well_known);
+        let Low::Let(_, record, _) = &converted.main else {
+            panic!("{:?}", converted.main)
+        };
+        assert!(matches!(**record, Low::Alloc { code: true, .. }));
+    }
+
+    #[test]
+    fn well_known_closures_are_called_directly() {
+        let [_, program, ..] = examples();
+        let converted = convert(&program);
+        assert_eq!(converted.direct_calls, 2);
+        assert_eq!(converted.indirect_calls, 1);
+        assert_eq!(runs_like_the_original(&program), Some(true));
+    }
+
+    #[test]
+    fn converted_programs_run_like_the_originals() {
+        for program in examples() {
+            assert_ne!(runs_like_the_original(&program), Some(false), "{program}");
+        }
+
+        // bind the free variables to the identity, so that fewer runs stop at one
+        let mut rng = Rng::new(0);
+        for _ in 0..200 {
+            let mut source = random_source(&mut rng, 20);
+            let mut free = Vec::from_iter(parser::parse(&source).unwrap().free_variables());
+            free.sort();
+            for x in free {
+                source = format!("let {x} = (fn {x} -> {x}) in ({source})");
+            }
+            let program = parser::parse(&format!("{source} ")).unwrap();
+            assert_ne!(runs_like_the_original(&program), Some(false), "{program}");
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    analysis::AbstractCache,
+    expression::Expression,
+    interpreter::{Callee, Env, Language, Machine, RuntimeError, Step, Value},
+    term::Term,
+    types::{Constant, Label, Operator, Variable},
+};
+
+/// a first-order expression, keeping the label of the expression it comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub label: Label,
+    pub term: FirstOrder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirstOrder {
+    Constant(Constant),
+    Variable(Variable),
+    /// the constructor of the closure `label`, applied to the values of its free variables
+    Construct(Vec<Variable>),
+    /// `apply_label(e1, e2)`: the dispatch of the application `label`
+    Apply(Box<Node>, Box<Node>),
+    IfThenElse(Box<Node>, Box<Node>, Box<Node>),
+    Let(Variable, Box<Node>, Box<Node>),
+    BinaryOp(Box<Node>, Operator, Box<Node>),
+}
+
+/// the first-order function with the body of a closure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    /// label of the closure, which is also the name of its constructor
+    pub closure: Label,
+    /// the free variables of the closure, stored by its constructor
+    pub captured: Vec<Variable>,
+    /// for `fun f x -> e0`, `f`, which is bound to the constructed closure itself
+    pub recursive: Option<Variable>,
+    pub parameter: Variable,
+    pub body: Node,
+}
+
+/// the dispatch function of an application, over the closures that may reach it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dispatch {
+    pub label: Label,
+    pub closures: Vec<Label>,
+}
+
+/// a program without closures: constructors and first-order functions instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Defunctionalized {
+    pub main: Node,
+    /// in order of the closures' labels
+    pub functions: Vec<Function>,
+    /// in order of the applications' labels
+    pub dispatches: Vec<Dispatch>,
+}
+
+fn constructor(closure: Label, captured: &[Variable]) -> String {
+    if captured.is_empty() {
+        format!("C{closure}")
+    } else {
+        let captured = captured.iter().map(ToString::to_string).collect::<Vec<_>>();
+        format!("C{closure}({})", captured.join(", "))
+    }
+}
+
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        /// `node`, in parentheses unless it is atomic
+        fn operand(node: &Node) -> String {
+            match node.term {
+                FirstOrder::Constant(_)
+                | FirstOrder::Variable(_)
+                | FirstOrder::Construct(_)
+                | FirstOrder::Apply(..) => node.to_string(),
+                _ => format!("({node})"),
+            }
+        }
+
+        match &self.term {
+            FirstOrder::Constant(c) => write!(f, "{c}"),
+            FirstOrder::Variable(x) => write!(f, "{x}"),
+            FirstOrder::Construct(captured) => write!(f, "{}", constructor(self.label, captured)),
+            FirstOrder::Apply(e1, e2) => write!(f, "apply{}({e1}, {e2})", self.label),
+            FirstOrder::IfThenElse(e0, e1, e2) => write!(f, "if {e0} then {e1} else {e2}"),
+            FirstOrder::Let(x, e1, e2) => write!(f, "let {x} = {e1} in {e2}"),
+            FirstOrder::BinaryOp(e1, op, e2) => write!(f, "{} {op} {}", operand(e1), operand(e2)),
+        }
+    }
+}
+
+impl Defunctionalized {
+    /// the program as text: the dispatch functions, the functions of the closures and
+    /// the main expression
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for dispatch in &self.dispatches {
+            let cases: Vec<String> = dispatch
+                .closures
+                .iter()
+                .map(|closure| {
+                    let function = &self.functions[self.function(*closure)];
+                    format!(
+                        "{} -> f{closure}(c, a)",
+                        constructor(*closure, &function.captured)
+                    )
+                })
+                .collect();
+            let cases = if cases.is_empty() {
+                "fail".to_string()
+            } else {
+                format!("case c of {}", cases.join(" | "))
+            };
+            lines.push(format!("  apply{}(c, a) = {cases}", dispatch.label));
+        }
+
+        for function in &self.functions {
+            let pattern = constructor(function.closure, &function.captured);
+            let pattern = match function.recursive {
+                Some(f) => format!("{pattern} as {f}"),
+                None => pattern,
+            };
+            lines.push(format!(
+                "  f{}({pattern}, {}) = {}",
+                function.closure, function.parameter, function.body
+            ));
+        }
+
+        lines.push(format!("  main = {}", self.main));
+        lines
+    }
+
+    fn function(&self, closure: Label) -> usize {
+        self.functions
+            .binary_search_by_key(&closure, |function| function.closure)
+            .expect("dispatch to a closure without a function")
+    }
+}
+
+struct Defunctionalizer<'a> {
+    cache: &'a AbstractCache,
+    /// the labels of the closures of the program, by term
+    closures: HashMap<&'a Term, Label>,
+    functions: Vec<Function>,
+    dispatches: Vec<Dispatch>,
+}
+
+impl<'a> Defunctionalizer<'a> {
+    fn transform(&mut self, expr: &'a Expression) -> Node {
+        let term = match &expr.term {
+            Term::Constant(c) => FirstOrder::Constant(*c),
+            Term::Variable(x) => FirstOrder::Variable(*x),
+
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+                let (recursive, parameter) = match &expr.term {
+                    Term::Closure(x, _) => (None, *x),
+                    Term::RecursiveClosure(f, x, _) => (Some(*f), *x),
+                    _ => unreachable!("not a closure"),
+                };
+                let mut captured = Vec::from_iter(expr.free_variables());
+                captured.sort();
+
+                let body = self.transform(e0);
+                self.functions.push(Function {
+                    closure: expr.label,
+                    captured: captured.clone(),
+                    recursive,
+                    parameter,
+                    body,
+                });
+                FirstOrder::Construct(captured)
+            }
+
+            Term::Application(e1, e2) => {
+                let mut closures: Vec<Label> = self
+                    .cache
+                    .get(&e1.label)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|t| self.closures.get(t).copied())
+                    .collect();
+                closures.sort();
+                self.dispatches.push(Dispatch {
+                    label: expr.label,
+                    closures,
+                });
+                FirstOrder::Apply(Box::new(self.transform(e1)), Box::new(self.transform(e2)))
+            }
+
+            Term::IfThenElse(e0, e1, e2) => FirstOrder::IfThenElse(
+                Box::new(self.transform(e0)),
+                Box::new(self.transform(e1)),
+                Box::new(self.transform(e2)),
+            ),
+            Term::Let(x, e1, e2) => FirstOrder::Let(
+                *x,
+                Box::new(self.transform(e1)),
+                Box::new(self.transform(e2)),
+            ),
+            Term::BinaryOp(e1, op, e2) => FirstOrder::BinaryOp(
+                Box::new(self.transform(e1)),
+                op.clone(),
+                Box::new(self.transform(e2)),
+            ),
+        };
+
+        Node {
+            label: expr.label,
+            term,
+        }
+    }
+}
+
+/**
+ * defunctionalizes `expr` with the closures found by an analysis: every closure becomes
+ * a constructor holding its free variables and a first-order function with its body,
+ * and every application calls a dispatch function over the closures in `C(e1)`
+ *
+ * if the analysis is sound, the result behaves like `expr`
+ */
+pub fn defunctionalize(expr: &Expression, cache: &AbstractCache) -> Defunctionalized {
+    let mut defunctionalizer = Defunctionalizer {
+        cache,
+        closures: expr.closures(),
+        functions: vec![],
+        dispatches: vec![],
+    };
+
+    let main = defunctionalizer.transform(expr);
+    defunctionalizer
+        .functions
+        .sort_by_key(|function| function.closure);
+    defunctionalizer
+        .dispatches
+        .sort_by_key(|dispatch| dispatch.label);
+    Defunctionalized {
+        main,
+        functions: defunctionalizer.functions,
+        dispatches: defunctionalizer.dispatches,
+    }
+}
+
+impl Display for Value<Label> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(c) => write!(f, "{c}"),
+            Self::Closure(closure, _) => write!(f, "C{closure}"),
+        }
+    }
+}
+
+/// first-order programs for the machine of the interpreter, where a closure is the
+/// constructor of the closure `label` with the values of its free variables
+impl<'a> Language<'a> for &'a Defunctionalized {
+    type Node = Node;
+    type Function = Label;
+
+    fn label(node: &Node) -> Label {
+        node.label
+    }
+
+    fn step(node: &'a Node) -> Step<'a, Node> {
+        match &node.term {
+            FirstOrder::Constant(c) => Step::Constant(*c),
+            FirstOrder::Variable(x) => Step::Variable(*x),
+            FirstOrder::Construct(_) => Step::Closure,
+            FirstOrder::Apply(e1, e2) => Step::Application(e1, e2),
+            FirstOrder::IfThenElse(e0, e1, e2) => Step::IfThenElse(e0, e1, e2),
+            FirstOrder::Let(x, e1, e2) => Step::Let(*x, e1, e2),
+            FirstOrder::BinaryOp(e1, op, e2) => Step::BinaryOp(e1, op, e2),
+        }
+    }
+
+    fn closure_label(function: &Label) -> Label {
+        *function
+    }
+
+    // free variables that are unbound here only fail once they are used
+    fn close(&self, node: &'a Node, env: Env<Label>) -> Value<Label> {
+        let FirstOrder::Construct(captured) = &node.term else {
+            unreachable!("constructor of a non-closure")
+        };
+        let mut stored = Env::default();
+        for &x in captured {
+            if let Some(value) = env.lookup(x) {
+                stored = stored.bind(x, value.clone());
+            }
+        }
+        Value::Closure(node.label, stored)
+    }
+
+    // the dispatch: only the closures the analysis found are handled
+    fn callee(
+        &self,
+        app: Label,
+        function: &Value<Label>,
+    ) -> Result<Callee<'a, Node, Label>, RuntimeError> {
+        let dispatch = &self.dispatches[self
+            .dispatches
+            .binary_search_by_key(&app, |dispatch| dispatch.label)
+            .expect("application without a dispatch")];
+        let Value::Closure(closure, stored) = function else {
+            return Err(RuntimeError::NotAFunction {
+                label: app,
+                value: function.to_string(),
+            });
+        };
+        if !dispatch.closures.contains(closure) {
+            return Err(RuntimeError::NotAFunction {
+                label: app,
+                value: format!("{function}, which apply{app} does not handle"),
+            });
+        }
+
+        let callee = &self.functions[self.function(*closure)];
+        Ok(Callee {
+            env: stored.clone(),
+            recursive: callee.recursive,
+            parameter: callee.parameter,
+            body: &callee.body,
+        })
+    }
+}
+
+/// evaluates the first-order program call-by-value, with the same steps and errors as
+/// the interpreter of the original language
+pub fn run(program: &Defunctionalized) -> Result<Value<Label>, RuntimeError> {
+    Machine::new(program).run(&program.main)
+}
+
+/**
+ * whether the first-order program gave the same outcome as the original: the same
+ * integer, the constructor of the same closure, or the same kind of error at the same
+ * label (the values in error messages are shown differently)
+ *
+ * `None` if either run was cut off by the step limit, which is inconclusive
+ */
+pub fn same_outcome(
+    original: &Result<Value<&Expression>, RuntimeError>,
+    defunctionalized: &Result<Value<Label>, RuntimeError>,
+) -> Option<bool> {
+    Some(match (original, defunctionalized) {
+        (Err(RuntimeError::StepLimit), _) | (_, Err(RuntimeError::StepLimit)) => return None,
+        (Ok(Value::Int(a)), Ok(Value::Int(b))) => a == b,
+        (Ok(Value::Closure(closure, _)), Ok(Value::Closure(label, _))) => closure.label == *label,
+        (Err(a), Err(b)) => {
+            std::mem::discriminant(a) == std::mem::discriminant(b) && a.label() == b.label()
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        analysis::{analyse, AbstractCache},
+        generator::{random_source, Rng},
+        interpreter::evaluate,
+        parser,
+        parser::examples,
+    };
+
+    fn defunctionalize_analysed(program: &Expression) -> Defunctionalized {
+        let (cache, _) = analyse(program, &program.constraint_system());
+        defunctionalize(program, &cache)
+    }
+
+    #[test]
+    fn example1() {
+        let [program, ..] = examples();
+        let first_order = defunctionalize_analysed(&program);
+        assert_eq!(
+            first_order.lines(),
+            [
+                "  apply5(c, a) = case c of C2 -> f2(c, a)",
+                "  f2(C2, x) = x",
+                "  f4(C4, y) = y",
+                "  main = apply5(C2, C4)",
+            ]
+        );
+        assert_eq!(run(&first_order).unwrap().to_string(), "C4");
+    }
+
+    #[test]
+    fn runs_like_the_original() {
+        let mut rng = Rng::new(0);
+        let random = (0..50).map(|_| parser::parse(&random_source(&mut rng, 20)).unwrap());
+        for program in examples().into_iter().chain(random) {
+            let first_order = defunctionalize_analysed(&program);
+            let (original, result) = (evaluate(&program), run(&first_order));
+            assert_ne!(same_outcome(&original, &result), Some(false), "{program}");
+        }
+
+        let sum = "let f = fun f n -> if n < 1 then 0 else n + (f (n - 1)) in f 100 ";
+        let first_order = defunctionalize_analysed(&parser::parse(sum).unwrap());
+        assert_eq!(run(&first_order).unwrap().to_string(), "5050");
+    }
+
+    #[test]
+    fn dispatch_only_handles_the_closures_of_the_analysis() {
+        // `fn y -> y` instead of `fn x -> x` as the operator of `apply5`
+        let [program, ..] = examples();
+        let closures: HashMap<Label, &Term> = program
+            .closures()
+            .into_iter()
+            .map(|(t, l)| (l, t))
+            .collect();
+        let mut cache: AbstractCache = program
+            .labels()
+            .into_iter()
+            .map(|l| (l, HashSet::new()))
+            .collect();
+        cache.insert(2, HashSet::from([closures[&4].clone()]));
+
+        let result = run(&defunctionalize(&program, &cache));
+        let Err(error) = result else {
+            panic!("ran with an unsound analysis")
+        };
+        assert_eq!(error.label(), Some(5));
+        assert_eq!(
+            error.to_string(),
+            "cannot apply C2, which apply5 does not handle"
+        );
+    }
+}
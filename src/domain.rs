@@ -0,0 +1,181 @@
+use std::{collections::BTreeSet, fmt::Display};
+
+use crate::types::{Constant, Operator};
+
+/// how many distinct constants are tracked before an abstract value becomes `Any`
+const MAX_CONSTANTS: usize = 8;
+
+/// Abstract truth value of a condition
+///
+/// There are no boolean constants in the language: comparisons yield `1` or `0`,
+/// and any non-zero value counts as true.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbstractBool {
+    /// no value reaches the condition
+    Never,
+    True,
+    False,
+    Unknown,
+}
+
+impl AbstractBool {
+    pub fn may_be_true(self) -> bool {
+        matches!(self, Self::True | Self::Unknown)
+    }
+
+    pub fn may_be_false(self) -> bool {
+        matches!(self, Self::False | Self::Unknown)
+    }
+}
+
+/// Abstract (base) value of an expression: a small set of known integers, or any integer
+///
+/// The empty set is the bottom element, i.e. no integer can be the result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AbstractValue {
+    Constants(BTreeSet<Constant>),
+    Any,
+}
+
+impl AbstractValue {
+    pub fn bottom() -> Self {
+        Self::Constants(BTreeSet::new())
+    }
+
+    pub fn constant(c: Constant) -> Self {
+        Self::Constants(BTreeSet::from([c]))
+    }
+
+    fn boolean(b: AbstractBool) -> Self {
+        match b {
+            AbstractBool::Never => Self::bottom(),
+            AbstractBool::True => Self::constant(1),
+            AbstractBool::False => Self::constant(0),
+            AbstractBool::Unknown => Self::Constants(BTreeSet::from([0, 1])),
+        }
+    }
+
+    pub fn is_bottom(&self) -> bool {
+        matches!(self, Self::Constants(cs) if cs.is_empty())
+    }
+
+    /// least upper bound; returns whether `self` changed
+    pub fn join(&mut self, other: &AbstractValue) -> bool {
+        match (&mut *self, other) {
+            (Self::Any, _) => false,
+            (_, Self::Any) => {
+                *self = Self::Any;
+                true
+            }
+            (Self::Constants(cs), Self::Constants(other_cs)) => {
+                if other_cs.is_subset(cs) {
+                    return false;
+                }
+
+                cs.extend(other_cs);
+                if cs.len() > MAX_CONSTANTS {
+                    *self = Self::Any;
+                }
+                true
+            }
+        }
+    }
+
+    pub fn truthiness(&self) -> AbstractBool {
+        match self {
+            Self::Any => AbstractBool::Unknown,
+            Self::Constants(cs) => match (cs.iter().any(|&c| c != 0), cs.contains(&0)) {
+                (false, false) => AbstractBool::Never,
+                (true, false) => AbstractBool::True,
+                (false, true) => AbstractBool::False,
+                (true, true) => AbstractBool::Unknown,
+            },
+        }
+    }
+
+    /// abstract counterpart of evaluating `lhs op rhs`
+    ///
+    /// pairs of operands for which the operation fails (division by zero) contribute no result
+    pub fn binary_op(lhs: &AbstractValue, op: &Operator, rhs: &AbstractValue) -> AbstractValue {
+        if lhs.is_bottom() || rhs.is_bottom() {
+            return Self::bottom();
+        }
+
+        if matches!(op.as_str(), "&&" | "||") {
+            use AbstractBool::*;
+            return Self::boolean(match (op.as_str(), lhs.truthiness(), rhs.truthiness()) {
+                ("&&", False, _) | ("&&", _, False) => False,
+                ("&&", True, True) => True,
+                ("||", True, _) | ("||", _, True) => True,
+                ("||", False, False) => False,
+                _ => Unknown,
+            });
+        }
+
+        match (lhs, rhs) {
+            (Self::Constants(lhs), Self::Constants(rhs)) => {
+                let mut result = Self::bottom();
+                for &a in lhs {
+                    for &b in rhs {
+                        match eval_binary_op(a, op, b) {
+                            Ok(c) => result.join(&Self::constant(c)),
+                            Err(BinaryOpError::Overflow) => result.join(&Self::Any),
+                            Err(BinaryOpError::DivisionByZero) => false,
+                        };
+                    }
+                }
+                result
+            }
+
+            _ if is_comparison(op) => Self::boolean(AbstractBool::Unknown),
+            _ => Self::Any,
+        }
+    }
+}
+
+impl Display for AbstractValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Any => write!(f, "⊤"),
+            Self::Constants(cs) => write!(
+                f,
+                "{{{}}}",
+                cs.iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOpError {
+    DivisionByZero,
+    Overflow,
+}
+
+fn is_comparison(op: &Operator) -> bool {
+    matches!(op.as_str(), "<" | ">" | "<=" | ">=" | "==" | "!=")
+}
+
+/// concrete semantics of the binary operators accepted by the parser
+pub fn eval_binary_op(a: Constant, op: &Operator, b: Constant) -> Result<Constant, BinaryOpError> {
+    use BinaryOpError::*;
+    match op.as_str() {
+        "+" => a.checked_add(b).ok_or(Overflow),
+        "-" => a.checked_sub(b).ok_or(Overflow),
+        "*" => a.checked_mul(b).ok_or(Overflow),
+        "/" if b == 0 => Err(DivisionByZero),
+        "/" => a.checked_div(b).ok_or(Overflow),
+        "<" => Ok((a < b) as Constant),
+        ">" => Ok((a > b) as Constant),
+        "<=" => Ok((a <= b) as Constant),
+        ">=" => Ok((a >= b) as Constant),
+        "==" => Ok((a == b) as Constant),
+        "!=" => Ok((a != b) as Constant),
+        "&&" => Ok((a != 0 && b != 0) as Constant),
+        "||" => Ok((a != 0 || b != 0) as Constant),
+        _ => panic!("Unknown binary operator: {op}"),
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashSet;
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::{ConSet, Constraint},
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// `s` as a quoted DOT identifier, with line breaks in `s` kept
+pub fn quoted(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{escaped}\"")
+}
+
+fn closures(terms: Option<&HashSet<Term>>) -> String {
+    let mut terms: Vec<String> = terms
+        .into_iter()
+        .flatten()
+        .map(ToString::to_string)
+        .collect();
+    terms.sort();
+    format!("{{{}}}", terms.join(", "))
+}
+
+/**
+ * the constraint graph in DOT: the nodes are the sets `C(l)` and `r(x)` and the closures
+ * of the program (as boxes), unconditional constraints are solid edges and conditional
+ * constraints are dashed edges labelled with their guard
+ *
+ * with a `solution`, every set also shows its closures
+ */
+pub fn constraint_graph(
+    constraints: &HashSet<Constraint>,
+    labels: &[Label],
+    variables: &[Variable],
+    solution: Option<(&AbstractCache, &AbstractEnv)>,
+) -> Vec<String> {
+    let mut lines = vec!["digraph constraints {".to_string()];
+
+    let nodes = labels
+        .iter()
+        .map(|&l| (ConSet::Cache(l), solution.map(|(cache, _)| cache.get(&l))))
+        .chain(
+            variables
+                .iter()
+                .map(|&x| (ConSet::Env(x), solution.map(|(_, env)| env.get(&x)))),
+        );
+    for (node, terms) in nodes {
+        let label = match terms {
+            Some(terms) => format!("{node}\n{}", closures(terms)),
+            None => node.to_string(),
+        };
+        lines.push(format!(
+            "  {} [label={}];",
+            quoted(&node.to_string()),
+            quoted(&label)
+        ));
+    }
+
+    let mut single_terms: Vec<String> = constraints
+        .iter()
+        .filter_map(|constraint| match constraint {
+            Constraint::Unconditional(node @ ConSet::SingleTerm(_), _) => Some(node.to_string()),
+            _ => None,
+        })
+        .collect();
+    single_terms.sort();
+    single_terms.dedup();
+    for node in single_terms {
+        lines.push(format!("  {} [shape=box];", quoted(&node)));
+    }
+
+    let mut edges: Vec<String> = constraints
+        .iter()
+        .map(|constraint| match constraint {
+            Constraint::Unconditional(lhs, rhs) => format!(
+                "  {} -> {};",
+                quoted(&lhs.to_string()),
+                quoted(&rhs.to_string())
+            ),
+            Constraint::Conditional((t, guard), lhs, rhs) => format!(
+                "  {} -> {} [style=dashed, label={}];",
+                quoted(&lhs.to_string()),
+                quoted(&rhs.to_string()),
+                quoted(&format!("{{{t}}} ⊆ {guard}"))
+            ),
+        })
+        .collect();
+    edges.sort();
+    lines.extend(edges);
+
+    lines.push("}".to_string());
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser::examples};
+
+    fn graph_of_example1(with_sets: bool) -> Vec<String> {
+        let program = &examples()[0];
+        let (cache, env) = analyse(program, &program.constraint_system());
+        let solution = with_sets.then_some((&cache, &env));
+        constraint_graph(
+            &program.constraints(),
+            &[1, 2, 3, 4, 5],
+            &['x', 'y'],
+            solution,
+        )
+    }
+
+    #[test]
+    fn conditional_constraints_are_dashed_edges() {
+        let graph = graph_of_example1(false);
+        assert_eq!(graph[1], r#"  "C(1)" [label="C(1)"];"#);
+        assert!(graph.contains(&r#"  "{fn x -> x¹}" [shape=box];"#.to_string()));
+        assert!(graph.contains(&r#"  "r(x)" -> "C(1)";"#.to_string()));
+
+        let dashed: Vec<&String> = graph.iter().filter(|l| l.contains("dashed")).collect();
+        assert_eq!(
+            dashed,
+            [
+                r#"  "C(1)" -> "C(5)" [style=dashed, label="{fn x -> x¹} ⊆ C(2)"];"#,
+                r#"  "C(3)" -> "C(5)" [style=dashed, label="{fn y -> y³} ⊆ C(2)"];"#,
+                r#"  "C(4)" -> "r(x)" [style=dashed, label="{fn x -> x¹} ⊆ C(2)"];"#,
+                r#"  "C(4)" -> "r(y)" [style=dashed, label="{fn y -> y³} ⊆ C(2)"];"#,
+            ]
+        );
+        assert_eq!(graph.last().unwrap(), "}");
+    }
+
+    #[test]
+    fn sets_are_added_to_the_labels() {
+        let graph = graph_of_example1(true);
+        assert_eq!(graph[1], r#"  "C(1)" [label="C(1)\n{fn y -> y³}"];"#);
+        assert_eq!(graph[3], r#"  "C(3)" [label="C(3)\n{}"];"#);
+        assert_eq!(graph[6], r#"  "r(x)" [label="r(x)\n{fn y -> y³}"];"#);
+    }
+
+    #[test]
+    fn quotes_are_escaped() {
+        assert_eq!(quoted(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(quoted("a\\b\nc"), r#""a\\b\nc""#);
+    }
+}
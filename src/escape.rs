@@ -0,0 +1,340 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt::Display,
+};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    expression::Expression,
+    pruning::values,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// why a closure may outlive the activation of the scope that defines it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Escape {
+    /// it may be the result of the scope (of the program, at the top level)
+    Returned,
+    /// it may be bound to `variable` in the environment of the closure `by`, which
+    /// escapes the same scope
+    Captured { by: Label, variable: Variable },
+    /// it may be the argument of the application `call`, whose operator may be
+    /// something other than a closure of the program, e.g. a free variable
+    Passed { call: Label },
+}
+
+impl Display for Escape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Returned => write!(f, "returned"),
+            Self::Captured { by, variable } => {
+                write!(f, "captured as {variable} by closure {by}, which escapes")
+            }
+            Self::Passed { call } => write!(f, "passed to an unknown function at call {call}"),
+        }
+    }
+}
+
+/// a closure and whether it may escape the scope that defines it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosureEscape {
+    pub label: Label,
+    /// the innermost closure around it, `None` at the top level
+    pub scope: Option<Label>,
+    /// `None` if the closure never outlives its scope, so that its environment could
+    /// live on the stack
+    pub escape: Option<Escape>,
+}
+
+/// the closures in `expr` with the innermost closure around each of them
+fn closures<'a>(
+    expr: &'a Expression,
+    scope: Option<Label>,
+    found: &mut Vec<(&'a Expression, Option<Label>)>,
+) {
+    match &expr.term {
+        Term::Constant(_) | Term::Variable(_) => {}
+        Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+            found.push((expr, scope));
+            closures(e0, Some(expr.label), found);
+        }
+        Term::Application(e1, e2) | Term::Let(_, e1, e2) | Term::BinaryOp(e1, _, e2) => {
+            closures(e1, scope, found);
+            closures(e2, scope, found);
+        }
+        Term::IfThenElse(e0, e1, e2) => {
+            for e in [e0, e1, e2] {
+                closures(e, scope, found);
+            }
+        }
+    }
+}
+
+/// `(closure, call)`: the closures that may be passed to an application whose operator
+/// may not be a closure of the program (by `pruning::values`), such as a free variable
+fn passed_to_unknown(expr: &Expression, cache: &AbstractCache) -> Vec<(Label, Label)> {
+    let labels = expr.closures();
+    let (value_cache, _) = values(expr, cache, &HashSet::new());
+
+    let mut passed: Vec<(Label, Label)> = expr
+        .subexprs()
+        .into_iter()
+        .filter_map(|e| match &e.term {
+            Term::Application(e1, e2) if !value_cache[&e1.label].is_bottom() => {
+                Some((e.label, e2.label))
+            }
+            _ => None,
+        })
+        .flat_map(|(call, operand)| {
+            cache[&operand]
+                .iter()
+                .filter_map(|t| labels.get(t).map(|&closure| (closure, call)))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    passed.sort();
+    passed
+}
+
+/**
+ * which closures may escape the scope that defines them, given the analysis
+ *
+ * in this language a closure can only outlive an activation of its scope by being its
+ * result, by being passed to a function outside the program (which may keep it), or by
+ * being captured in the environment of another closure that escapes; passing it to a
+ * closure of the program only matters if that returns or captures it
+ *
+ * since `r(x)` merges all activations, a closure that is captured by an escaping
+ * closure through any binding of that variable counts as escaping
+ */
+pub fn escaping_closures(
+    expr: &Expression,
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+) -> Vec<ClosureEscape> {
+    let mut found = vec![];
+    closures(expr, None, &mut found);
+
+    let labels = expr.closures();
+    let free: HashMap<Label, HashSet<Variable>> = found
+        .iter()
+        .map(|(e, _)| (e.label, e.free_variables()))
+        .collect();
+
+    // the result of each scope: the body of a closure, or the whole program
+    let mut results: HashMap<Option<Label>, Label> = found
+        .iter()
+        .filter_map(|(e, _)| match &e.term {
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+                Some((Some(e.label), e0.label))
+            }
+            _ => None,
+        })
+        .collect();
+    results.insert(None, expr.label);
+
+    let passed = passed_to_unknown(expr, cache);
+
+    // the closures that may outlive each scope, with the first reason found
+    let mut escaping: HashMap<Option<Label>, HashMap<Label, Escape>> = HashMap::new();
+    for (&scope, result) in &results {
+        let mut outliving: HashMap<Label, Escape> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let mut returned: Vec<Label> = cache
+            .get(result)
+            .into_iter()
+            .flatten()
+            .filter_map(|t| labels.get(t).copied())
+            .collect();
+        returned.sort();
+        for label in returned {
+            outliving.insert(label, Escape::Returned);
+            queue.push_back(label);
+        }
+        for &(label, call) in &passed {
+            if let Entry::Vacant(entry) = outliving.entry(label) {
+                entry.insert(Escape::Passed { call });
+                queue.push_back(label);
+            }
+        }
+
+        while let Some(by) = queue.pop_front() {
+            let mut variables = Vec::from_iter(&free[&by]);
+            variables.sort();
+            for &variable in variables {
+                let mut captured: Vec<Label> = env
+                    .get(&variable)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|t| labels.get(t).copied())
+                    .collect();
+                captured.sort();
+                for label in captured {
+                    if let Entry::Vacant(entry) = outliving.entry(label) {
+                        entry.insert(Escape::Captured { by, variable });
+                        queue.push_back(label);
+                    }
+                }
+            }
+        }
+
+        escaping.insert(scope, outliving);
+    }
+
+    let mut closures: Vec<ClosureEscape> = found
+        .iter()
+        .map(|(e, scope)| ClosureEscape {
+            label: e.label,
+            scope: *scope,
+            escape: escaping[scope].get(&e.label).cloned(),
+        })
+        .collect();
+    closures.sort_by_key(|closure| closure.label);
+    closures
+}
+
+/**
+ * the closures that code outside the program may get hold of, and so call: those that
+ * may be its result or be passed to a function outside it and, transitively, those that
+ * one of them may return or has captured
+ *
+ * as in `escaping_closures`, a captured variable counts with all its bindings
+ */
+pub fn reachable_from_outside(
+    expr: &Expression,
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+) -> HashSet<Label> {
+    let labels = expr.closures();
+    let closures: HashMap<Label, &Expression> = expr
+        .subexprs()
+        .into_iter()
+        .filter(|e| labels.contains_key(&e.term))
+        .map(|e| (e.label, e))
+        .collect();
+    let labels_in = |terms: Option<&HashSet<Term>>| -> Vec<Label> {
+        terms
+            .into_iter()
+            .flatten()
+            .filter_map(|t| labels.get(t).copied())
+            .collect()
+    };
+
+    let mut reachable = HashSet::new();
+    let mut queue = VecDeque::from(labels_in(cache.get(&expr.label)));
+    queue.extend(
+        passed_to_unknown(expr, cache)
+            .into_iter()
+            .map(|(label, _)| label),
+    );
+    while let Some(label) = queue.pop_front() {
+        if !reachable.insert(label) {
+            continue;
+        }
+        let closure = closures[&label];
+        let (Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0)) = &closure.term else {
+            unreachable!("closure label of a non-closure")
+        };
+        queue.extend(labels_in(cache.get(&e0.label)));
+        for x in closure.free_variables() {
+            queue.extend(labels_in(env.get(&x)));
+        }
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser};
+
+    fn escapes(source: &str) -> Vec<ClosureEscape> {
+        let program = parser::parse(source).unwrap();
+        let (cache, env) = analyse(&program, &program.constraint_system());
+        escaping_closures(&program, &cache, &env)
+    }
+
+    #[test]
+    fn returned_closures_escape() {
+        let closures = escapes("let f = fn x -> (fn y -> x) in (f 1) ");
+        assert_eq!(
+            closures,
+            [
+                ClosureEscape {
+                    label: 2,
+                    scope: Some(3),
+                    escape: Some(Escape::Returned),
+                },
+                ClosureEscape {
+                    label: 3,
+                    scope: None,
+                    escape: None,
+                },
+            ]
+        );
+
+        let closures = escapes("let f = fn x -> x in (f 1) ");
+        assert_eq!(closures[0].escape, None);
+    }
+
+    #[test]
+    fn captured_closures_escape_with_their_captor() {
+        let source = "let f = fn x -> (let g = fn y -> y in fn z -> (g z)) in let w = (f 1) in 2 ";
+        let closures = escapes(source);
+        let escape = |label| {
+            let closure = closures.iter().find(|c| c.label == label).unwrap();
+            (closure.scope, closure.escape.clone())
+        };
+
+        let captured = Escape::Captured {
+            by: 6,
+            variable: 'g',
+        };
+        assert_eq!(escape(2), (Some(8), Some(captured)));
+        assert_eq!(escape(6), (Some(8), Some(Escape::Returned)));
+        assert_eq!(escape(8), (None, None));
+    }
+
+    #[test]
+    fn closures_passed_to_free_variables_escape() {
+        let closures = escapes("let g = fn y -> y in let z = (h g) in (g 1) ");
+        assert_eq!(
+            closures,
+            [ClosureEscape {
+                label: 2,
+                scope: None,
+                escape: Some(Escape::Passed { call: 5 }),
+            }]
+        );
+
+        // and so does what they capture
+        let source = "let g = fn y -> y in let k = fn x -> (g x) in (h k) ";
+        let closures = escapes(source);
+        assert_eq!(
+            closures[0].escape,
+            Some(Escape::Captured {
+                by: 6,
+                variable: 'g',
+            })
+        );
+    }
+
+    #[test]
+    fn reachable_from_outside_the_program() {
+        let reachable = |source: &str| {
+            let program = parser::parse(source).unwrap();
+            let (cache, env) = analyse(&program, &program.constraint_system());
+            reachable_from_outside(&program, &cache, &env)
+        };
+
+        // the result, and what it returns
+        assert_eq!(reachable("fn x -> fn y -> x "), HashSet::from([2, 3]));
+        // what escapes a closure, but not the program
+        let source = "let f = fn x -> (let g = fn y -> y in fn z -> (g z)) in let w = (f 1) in 2 ";
+        assert!(reachable(source).is_empty());
+        // arguments of unknown functions, and what they capture
+        let source = "let g = fn y -> y in let k = fn x -> (g x) in (h k) ";
+        assert_eq!(reachable(source), HashSet::from([2, 6]));
+    }
+}
@@ -1,8 +1,11 @@
 use fmtastic::Superscript;
-use std::{collections::HashSet, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
 use crate::{
-    constraint::{ConSet, Constraint},
+    constraint::{CallSite, ConSet, Constraint, ConstraintSystem},
     term::Term,
     types::{Label, Variable},
 };
@@ -64,27 +67,98 @@ impl Expression {
         variables
     }
 
+    /// the label of every `fn` and `fun` expression, by its term
+    pub fn closures(&self) -> HashMap<&Term, Label> {
+        self.subexprs()
+            .into_iter()
+            .filter(|e| matches!(e.term, Term::Closure(..) | Term::RecursiveClosure(..)))
+            .map(|e| (&e.term, e.label))
+            .collect()
+    }
+
+    /// the variables that occur free in the expression
+    pub fn free_variables(&self) -> HashSet<Variable> {
+        let mut variables = HashSet::new();
+
+        match &self.term {
+            Term::Constant(_) => {}
+
+            Term::Variable(x) => {
+                variables.insert(*x);
+            }
+
+            Term::Closure(x, e0) => {
+                variables.extend(e0.free_variables());
+                variables.remove(x);
+            }
+
+            Term::RecursiveClosure(f, x, e0) => {
+                variables.extend(e0.free_variables());
+                variables.remove(f);
+                variables.remove(x);
+            }
+
+            Term::Application(e1, e2) | Term::BinaryOp(e1, _, e2) => {
+                variables.extend(e1.free_variables());
+                variables.extend(e2.free_variables());
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                variables.extend(e0.free_variables());
+                variables.extend(e1.free_variables());
+                variables.extend(e2.free_variables());
+            }
+
+            Term::Let(x, e1, e2) => {
+                variables.extend(e2.free_variables());
+                variables.remove(x);
+                variables.extend(e1.free_variables());
+            }
+        }
+
+        variables
+    }
+
+    /// all constraints, including the conditional constraints of every application
+    /// for every closure in the program (only used for display; see `constraint_system`)
     pub fn constraints(&self) -> HashSet<Constraint> {
-        self.constr(&self.subterms())
+        self.constraint_system().expand(&self.subterms())
     }
 
-    fn subexprs(&self) -> HashSet<&Expression> {
+    pub fn constraint_system(&self) -> ConstraintSystem {
+        self.constraint_system_excluding(&HashSet::new())
+    }
+
+    /// like `constraint_system`, but skips the subexpressions whose label is in `excluded`
+    /// (e.g. unreachable code), including the constraints linking them to their parent
+    pub fn constraint_system_excluding(&self, excluded: &HashSet<Label>) -> ConstraintSystem {
+        let mut system = ConstraintSystem::default();
+        self.constr(excluded, &mut system);
+        system
+    }
+
+    /// all subexpressions that are not inside one of the `excluded` subexpressions
+    pub fn subexprs_excluding(&self, excluded: &HashSet<Label>) -> HashSet<&Expression> {
+        if excluded.contains(&self.label) {
+            return HashSet::new();
+        }
+
         let mut expressions = HashSet::from([self]);
 
         match &self.term {
             Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
-                expressions.extend(e0.subexprs());
+                expressions.extend(e0.subexprs_excluding(excluded));
             }
 
             Term::Application(e1, e2) | Term::Let(_, e1, e2) | Term::BinaryOp(e1, _, e2) => {
-                expressions.extend(e1.subexprs());
-                expressions.extend(e2.subexprs());
+                expressions.extend(e1.subexprs_excluding(excluded));
+                expressions.extend(e2.subexprs_excluding(excluded));
             }
 
             Term::IfThenElse(e0, e1, e2) => {
-                expressions.extend(e0.subexprs());
-                expressions.extend(e1.subexprs());
-                expressions.extend(e2.subexprs());
+                expressions.extend(e0.subexprs_excluding(excluded));
+                expressions.extend(e1.subexprs_excluding(excluded));
+                expressions.extend(e2.subexprs_excluding(excluded));
             }
 
             _ => {}
@@ -93,34 +167,63 @@ impl Expression {
         expressions
     }
 
+    pub fn subexprs(&self) -> HashSet<&Expression> {
+        self.subexprs_excluding(&HashSet::new())
+    }
+
     pub fn subterms(&self) -> HashSet<&Term> {
         self.subexprs().iter().map(|e| &e.term).collect()
     }
 
-    fn constr(&self, subterms: &HashSet<&Term>) -> HashSet<Constraint> {
-        let mut constraints: HashSet<Constraint> = HashSet::new();
+    fn constr(&self, excluded: &HashSet<Label>, system: &mut ConstraintSystem) {
+        if excluded.contains(&self.label) {
+            return;
+        }
+
+        self.local_constraints(excluded, system);
+
+        match &self.term {
+            Term::Constant(_) | Term::Variable(_) => {}
+
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+                e0.constr(excluded, system);
+            }
+
+            Term::Application(e1, e2) | Term::Let(_, e1, e2) | Term::BinaryOp(e1, _, e2) => {
+                e1.constr(excluded, system);
+                e2.constr(excluded, system);
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                e0.constr(excluded, system);
+                e1.constr(excluded, system);
+                e2.constr(excluded, system);
+            }
+        }
+    }
+
+    /// the constraints (and call site) of this expression itself, including those linking
+    /// it to its direct subexpressions, but not those of the subexpressions
+    pub fn local_constraints(&self, excluded: &HashSet<Label>, system: &mut ConstraintSystem) {
+        let constraints = &mut system.constraints;
 
         use ConSet::*;
         use Constraint::*;
         match &self.term {
-            Term::Constant(_) => {}
+            Term::Constant(_) | Term::BinaryOp(..) => {}
 
             Term::Variable(x) => {
                 constraints.insert(Unconditional(Env(*x), Cache(self.label)));
             }
 
-            Term::Closure(_, e0) => {
-                constraints.extend(e0.constr(subterms));
-
+            Term::Closure(..) => {
                 constraints.insert(Unconditional(
                     SingleTerm(self.term.clone()),
                     Cache(self.label),
                 ));
             }
 
-            Term::RecursiveClosure(f, _, e0) => {
-                constraints.extend(e0.constr(subterms));
-
+            Term::RecursiveClosure(f, _, _) => {
                 constraints.extend([
                     Unconditional(SingleTerm(self.term.clone()), Cache(self.label)),
                     Unconditional(SingleTerm(self.term.clone()), Env(*f)),
@@ -128,51 +231,28 @@ impl Expression {
             }
 
             Term::Application(e1, e2) => {
-                constraints.extend(e1.constr(subterms));
-                constraints.extend(e2.constr(subterms));
-
-                subterms.iter().for_each(|&t| {
-                    if let Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) = t {
-                        constraints.extend([
-                            Conditional((t.clone(), Cache(e1.label)), Cache(e2.label), Env(*x)),
-                            Conditional(
-                                (t.clone(), Cache(e1.label)),
-                                Cache(e0.label),
-                                Cache(self.label),
-                            ),
-                        ]);
-                    }
+                system.call_sites.push(CallSite {
+                    label: self.label,
+                    operator: e1.label,
+                    operand: e2.label,
                 });
             }
 
-            Term::IfThenElse(e0, e1, e2) => {
-                constraints.extend(e0.constr(subterms));
-                constraints.extend(e1.constr(subterms));
-                constraints.extend(e2.constr(subterms));
-
-                constraints.extend([
-                    Unconditional(Cache(e1.label), Cache(self.label)),
-                    Unconditional(Cache(e2.label), Cache(self.label)),
-                ]);
+            Term::IfThenElse(_, e1, e2) => {
+                for branch in [e1, e2] {
+                    if !excluded.contains(&branch.label) {
+                        constraints.insert(Unconditional(Cache(branch.label), Cache(self.label)));
+                    }
+                }
             }
 
             Term::Let(x, e1, e2) => {
-                constraints.extend(e1.constr(subterms));
-                constraints.extend(e2.constr(subterms));
-
                 constraints.extend([
                     Unconditional(Cache(e1.label), Env(*x)),
                     Unconditional(Cache(e2.label), Cache(self.label)),
                 ]);
             }
-
-            Term::BinaryOp(e1, _, e2) => {
-                constraints.extend(e1.constr(subterms));
-                constraints.extend(e2.constr(subterms));
-            }
         }
-
-        constraints
     }
 }
 
@@ -194,7 +274,7 @@ impl Display for Expression {
                         "({inner:#level$}\n\
                     {pad:prev_level$}){label}",
                         pad = "",
-                        prev_level = if level >= 4 { level - 4 } else { 0 }
+                        prev_level = level.saturating_sub(4)
                     )
                 } else {
                     write!(f, "({inner:#level$}){label}",)
@@ -207,3 +287,32 @@ impl Display for Expression {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{
+        parser,
+        types::{Label, Variable},
+    };
+
+    #[test]
+    fn free_variables_of_the_program_and_its_closures() {
+        let program = parser::parse("let f = fun g x -> g (x y) in (fn z -> f z w) ").unwrap();
+        assert_eq!(program.free_variables(), HashSet::from(['w', 'y']));
+
+        let mut closures: Vec<(Label, Vec<Variable>)> = program
+            .subexprs()
+            .into_iter()
+            .filter(|e| program.closures().get(&e.term) == Some(&e.label))
+            .map(|e| {
+                let mut free = Vec::from_iter(e.free_variables());
+                free.sort();
+                (e.label, free)
+            })
+            .collect();
+        closures.sort();
+        assert_eq!(closures, [(6, vec!['y']), (12, vec!['f', 'w'])]);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::{expression::Expression, parser};
+
+/// small deterministic pseudo-random number generator (xorshift64*)
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// uniformly distributed in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+const VARIABLES: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+const OPERATORS: &[&str] = &["+", "-", "*", "<", "==", "&&"];
+
+fn variable(rng: &mut Rng) -> char {
+    VARIABLES[rng.below(VARIABLES.len())] as char
+}
+
+/// source code of a random expression with roughly `size` labels
+///
+/// the tree is split randomly at every node, so its depth stays logarithmic in `size`
+/// for the most part and the parser does not run out of stack
+fn source(rng: &mut Rng, size: usize, out: &mut String) {
+    if size <= 1 {
+        match rng.below(3) {
+            0 => out.push_str(&rng.below(10).to_string()),
+            _ => out.push(variable(rng)),
+        }
+        return;
+    }
+
+    // split the remaining labels between two subexpressions
+    let left = 1 + rng.below(size - 1);
+    let right = size - left;
+
+    match rng.below(8) {
+        0 | 1 => {
+            out.push_str(&format!("fn {} -> (", variable(rng)));
+            source(rng, size - 1, out);
+            out.push(')');
+        }
+
+        2 => {
+            out.push_str(&format!("fun {} {} -> (", variable(rng), variable(rng)));
+            source(rng, size - 1, out);
+            out.push(')');
+        }
+
+        3 | 4 => {
+            out.push('(');
+            source(rng, left, out);
+            out.push_str(") (");
+            source(rng, right.max(1), out);
+            out.push(')');
+        }
+
+        5 | 6 => {
+            out.push_str(&format!("let {} = (", variable(rng)));
+            source(rng, left, out);
+            out.push_str(") in (");
+            source(rng, right.max(1), out);
+            out.push(')');
+        }
+
+        _ if size >= 3 => {
+            let condition = 1 + rng.below(size - 2);
+            let then = 1 + rng.below(size - condition - 1);
+            out.push_str("if (");
+            source(rng, condition, out);
+            out.push_str(") then (");
+            source(rng, then, out);
+            out.push_str(") else (");
+            source(rng, (size - condition - then).max(1), out);
+            out.push(')');
+        }
+
+        _ => {
+            out.push('(');
+            source(rng, left, out);
+            out.push_str(&format!(") {} (", OPERATORS[rng.below(OPERATORS.len())]));
+            source(rng, right.max(1), out);
+            out.push(')');
+        }
+    }
+}
+
+/// source code of a random (and usually ill-typed) program with roughly `size` labels
+pub fn random_source(rng: &mut Rng, size: usize) -> String {
+    let mut input = String::new();
+    source(rng, size, &mut input);
+    input.push(' ');
+    input
+}
+
+pub fn random_program(rng: &mut Rng, size: usize) -> Expression {
+    parser::parse(&random_source(rng, size)).expect("generated program should parse")
+}
+
+/**
+ * source code of a random program of three parts with roughly `size` labels, and of
+ * the program after an edit that replaces one part with a new random one, or drops it
+ * (leaving a constant); the other parts stay the same
+ */
+pub fn random_edit(rng: &mut Rng, size: usize) -> (String, String) {
+    let program =
+        |parts: &[String]| format!("let a = ({}) in (({}) ({})) ", parts[0], parts[1], parts[2]);
+
+    let mut parts: Vec<String> = (0..3)
+        .map(|_| random_source(rng, size / 3).trim_end().to_string())
+        .collect();
+    let old = program(&parts);
+    let part = rng.below(parts.len());
+    parts[part] = match rng.below(2) {
+        0 => random_source(rng, size / 3).trim_end().to_string(),
+        _ => "0".to_string(),
+    };
+    (old, program(&parts))
+}
@@ -0,0 +1,504 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    analysis::{solve_from, AbstractCache, AbstractEnv},
+    constraint::{CallSite, ConSet, Constraint, ConstraintSystem},
+    expression::Expression,
+    term::Term,
+    types::Label,
+};
+
+/// the constraints of a program, by the label of the subexpression that generates them
+/// (see `Expression::local_constraints`)
+pub type ConstraintsByLabel = HashMap<Label, ConstraintSystem>;
+
+pub fn constraints_by_label(expr: &Expression) -> ConstraintsByLabel {
+    let mut subexprs = vec![];
+    preorder(expr, &mut subexprs);
+    subexprs
+        .into_iter()
+        .map(|e| {
+            let mut system = ConstraintSystem::default();
+            e.local_constraints(&HashSet::new(), &mut system);
+            (e.label, system)
+        })
+        .collect()
+}
+
+fn merge(constraints: &ConstraintsByLabel) -> ConstraintSystem {
+    let mut labels = Vec::from_iter(constraints.keys());
+    labels.sort();
+
+    let mut merged = ConstraintSystem::default();
+    for label in labels {
+        let system = &constraints[label];
+        merged
+            .constraints
+            .extend(system.constraints.iter().cloned());
+        merged.call_sites.extend(system.call_sites.iter().cloned());
+    }
+    merged
+}
+
+/// result of re-analysing an edited program
+pub struct Reanalysis {
+    /// the edited program, with the labels of unchanged subexpressions kept
+    pub program: Expression,
+    /// the label in `program` of every subexpression of the edited program as it was
+    /// passed in
+    pub labels: HashMap<Label, Label>,
+    /// the constraints of `program`, those of unchanged subexpressions taken over from
+    /// the old program
+    pub constraints: ConstraintsByLabel,
+    pub system: ConstraintSystem,
+    pub cache: AbstractCache,
+    pub env: AbstractEnv,
+    /// nodes whose set from the previous solution could be kept
+    pub reused_nodes: usize,
+    /// nodes that had to be solved again (affected by the edit or new)
+    pub recomputed_nodes: usize,
+    /// subexpressions whose constraints were generated again, as they are new
+    pub regenerated: usize,
+    /// nodes taken from the worklist while solving again
+    pub iterations: usize,
+}
+
+/// the subexpressions of `expr`, without hashing them as `Expression::subexprs` does
+fn preorder<'a>(expr: &'a Expression, found: &mut Vec<&'a Expression>) {
+    found.push(expr);
+    match &expr.term {
+        Term::Constant(_) | Term::Variable(_) => {}
+        Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => preorder(e0, found),
+        Term::Application(e1, e2) | Term::Let(_, e1, e2) | Term::BinaryOp(e1, _, e2) => {
+            preorder(e1, found);
+            preorder(e2, found);
+        }
+        Term::IfThenElse(e0, e1, e2) => {
+            for e in [e0, e1, e2] {
+                preorder(e, found);
+            }
+        }
+    }
+}
+
+/// hash of the structure of `expr`, ignoring labels
+fn shape_hash(expr: &Expression, hashes: &mut HashMap<Label, u64>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    std::mem::discriminant(&expr.term).hash(&mut hasher);
+
+    match &expr.term {
+        Term::Constant(c) => c.hash(&mut hasher),
+        Term::Variable(x) => x.hash(&mut hasher),
+        Term::Closure(x, e0) => {
+            x.hash(&mut hasher);
+            shape_hash(e0, hashes).hash(&mut hasher);
+        }
+        Term::RecursiveClosure(f, x, e0) => {
+            (f, x).hash(&mut hasher);
+            shape_hash(e0, hashes).hash(&mut hasher);
+        }
+        Term::Application(e1, e2) => {
+            shape_hash(e1, hashes).hash(&mut hasher);
+            shape_hash(e2, hashes).hash(&mut hasher);
+        }
+        Term::IfThenElse(e0, e1, e2) => {
+            shape_hash(e0, hashes).hash(&mut hasher);
+            shape_hash(e1, hashes).hash(&mut hasher);
+            shape_hash(e2, hashes).hash(&mut hasher);
+        }
+        Term::Let(x, e1, e2) => {
+            x.hash(&mut hasher);
+            shape_hash(e1, hashes).hash(&mut hasher);
+            shape_hash(e2, hashes).hash(&mut hasher);
+        }
+        Term::BinaryOp(e1, op, e2) => {
+            op.hash(&mut hasher);
+            shape_hash(e1, hashes).hash(&mut hasher);
+            shape_hash(e2, hashes).hash(&mut hasher);
+        }
+    }
+
+    let hash = hasher.finish();
+    hashes.insert(expr.label, hash);
+    hash
+}
+
+/// whether `a` and `b` are the same expression apart from their labels
+fn same_shape(a: &Expression, b: &Expression) -> bool {
+    match (&a.term, &b.term) {
+        (Term::Constant(c), Term::Constant(d)) => c == d,
+        (Term::Variable(x), Term::Variable(y)) => x == y,
+        (Term::Closure(x, a0), Term::Closure(y, b0)) => x == y && same_shape(a0, b0),
+        (Term::RecursiveClosure(f, x, a0), Term::RecursiveClosure(g, y, b0)) => {
+            f == g && x == y && same_shape(a0, b0)
+        }
+        (Term::Application(a1, a2), Term::Application(b1, b2)) => {
+            same_shape(a1, b1) && same_shape(a2, b2)
+        }
+        (Term::IfThenElse(a0, a1, a2), Term::IfThenElse(b0, b1, b2)) => {
+            same_shape(a0, b0) && same_shape(a1, b1) && same_shape(a2, b2)
+        }
+        (Term::Let(x, a1, a2), Term::Let(y, b1, b2)) => {
+            x == y && same_shape(a1, b1) && same_shape(a2, b2)
+        }
+        (Term::BinaryOp(a1, op, a2), Term::BinaryOp(b1, op2, b2)) => {
+            op == op2 && same_shape(a1, b1) && same_shape(a2, b2)
+        }
+        _ => false,
+    }
+}
+
+struct Relabeller<'a> {
+    /// subexpressions of the old program, by `shape_hash`
+    old_by_shape: HashMap<u64, Vec<&'a Expression>>,
+    /// old labels that have already been given to a subexpression of the new program
+    used: HashSet<Label>,
+    new_hashes: HashMap<Label, u64>,
+    next_label: Label,
+    /// the new label of every subexpression relabelled so far, by its label before
+    labels: HashMap<Label, Label>,
+}
+
+impl Relabeller<'_> {
+    /// whether a subexpression of `old` has already been given to the new program
+    fn overlaps_used(&self, old: &Expression) -> bool {
+        let mut subexprs = vec![];
+        preorder(old, &mut subexprs);
+        subexprs.iter().any(|e| self.used.contains(&e.label))
+    }
+
+    fn relabel(&mut self, expr: Expression) -> Expression {
+        let hash = self.new_hashes[&expr.label];
+
+        let unchanged = self.old_by_shape.get(&hash).and_then(|candidates| {
+            candidates
+                .iter()
+                .copied()
+                .find(|old| same_shape(old, &expr) && !self.overlaps_used(old))
+        });
+        if let Some(old) = unchanged {
+            pair_labels(&expr, old, &mut self.labels);
+            let mut subexprs = vec![];
+            preorder(old, &mut subexprs);
+            self.used.extend(subexprs.iter().map(|e| e.label));
+            return old.clone();
+        }
+
+        let label = expr.label;
+        let term = match expr.term {
+            Term::Closure(x, e0) => Term::Closure(x, Box::new(self.relabel(*e0))),
+            Term::RecursiveClosure(f, x, e0) => {
+                Term::RecursiveClosure(f, x, Box::new(self.relabel(*e0)))
+            }
+            Term::Application(e1, e2) => {
+                let e1 = self.relabel(*e1);
+                Term::Application(Box::new(e1), Box::new(self.relabel(*e2)))
+            }
+            Term::IfThenElse(e0, e1, e2) => {
+                let e0 = self.relabel(*e0);
+                let e1 = self.relabel(*e1);
+                Term::IfThenElse(Box::new(e0), Box::new(e1), Box::new(self.relabel(*e2)))
+            }
+            Term::Let(x, e1, e2) => {
+                let e1 = self.relabel(*e1);
+                Term::Let(x, Box::new(e1), Box::new(self.relabel(*e2)))
+            }
+            Term::BinaryOp(e1, op, e2) => {
+                let e1 = self.relabel(*e1);
+                Term::BinaryOp(Box::new(e1), op, Box::new(self.relabel(*e2)))
+            }
+            term => term,
+        };
+
+        self.next_label += 1;
+        self.labels.insert(label, self.next_label);
+        Expression {
+            label: self.next_label,
+            term,
+        }
+    }
+}
+
+/// maps the labels of `new` to those of `old`, which has the same shape
+fn pair_labels(new: &Expression, old: &Expression, labels: &mut HashMap<Label, Label>) {
+    labels.insert(new.label, old.label);
+    match (&new.term, &old.term) {
+        (Term::Closure(_, n0), Term::Closure(_, o0))
+        | (Term::RecursiveClosure(_, _, n0), Term::RecursiveClosure(_, _, o0)) => {
+            pair_labels(n0, o0, labels)
+        }
+        (Term::Application(n1, n2), Term::Application(o1, o2))
+        | (Term::Let(_, n1, n2), Term::Let(_, o1, o2))
+        | (Term::BinaryOp(n1, _, n2), Term::BinaryOp(o1, _, o2)) => {
+            pair_labels(n1, o1, labels);
+            pair_labels(n2, o2, labels);
+        }
+        (Term::IfThenElse(n0, n1, n2), Term::IfThenElse(o0, o1, o2)) => {
+            pair_labels(n0, o0, labels);
+            pair_labels(n1, o1, labels);
+            pair_labels(n2, o2, labels);
+        }
+        _ => {}
+    }
+}
+
+/**
+ * relabels `edited` so that every subexpression which also occurs unchanged in `old`
+ * keeps its label from `old`
+ *
+ * all other subexpressions get fresh labels, greater than any label of `old`; also
+ * returns the new label of every subexpression of `edited` by its label before
+ */
+pub fn relabel_stable(old: &Expression, edited: Expression) -> (Expression, HashMap<Label, Label>) {
+    let mut old_hashes = HashMap::new();
+    shape_hash(old, &mut old_hashes);
+
+    let mut subexprs = vec![];
+    preorder(old, &mut subexprs);
+    // among equal subexpressions, prefer the one that came first
+    subexprs.sort_by_key(|e| e.label);
+    let max_label = subexprs.last().map_or(0, |e| e.label);
+    let mut old_by_shape: HashMap<u64, Vec<&Expression>> = HashMap::new();
+    for e in subexprs {
+        old_by_shape
+            .entry(old_hashes[&e.label])
+            .or_default()
+            .push(e);
+    }
+
+    let mut new_hashes = HashMap::new();
+    shape_hash(&edited, &mut new_hashes);
+
+    let mut relabeller = Relabeller {
+        old_by_shape,
+        used: HashSet::new(),
+        new_hashes,
+        next_label: max_label,
+        labels: HashMap::new(),
+    };
+    let relabelled = relabeller.relabel(edited);
+    (relabelled, relabeller.labels)
+}
+
+/// edges of the constraint graph the old solution was computed on, including the
+/// constraints instantiated for the closures that reached each call site
+fn old_edges(
+    system: ConstraintSystem,
+    old_cache: &AbstractCache,
+) -> (HashMap<ConSet, Vec<ConSet>>, HashSet<Constraint>) {
+    let mut edges: HashMap<ConSet, Vec<ConSet>> = HashMap::new();
+
+    for constraint in &system.constraints {
+        match constraint {
+            Constraint::Unconditional(ConSet::SingleTerm(_), _) => {}
+            Constraint::Unconditional(p1, p2) => {
+                edges.entry(p1.clone()).or_default().push(p2.clone());
+            }
+            Constraint::Conditional((_, p), p1, p2) => {
+                edges.entry(p.clone()).or_default().push(p2.clone());
+                edges.entry(p1.clone()).or_default().push(p2.clone());
+            }
+        }
+    }
+
+    let mut instantiated = HashSet::new();
+    for call_site in &system.call_sites {
+        let operator = ConSet::Cache(call_site.operator);
+        for t in &old_cache[&call_site.operator] {
+            for constraint in call_site.instantiate(t).into_iter().flatten() {
+                if let Constraint::Unconditional(p1, p2) = &constraint {
+                    // the call site's result also depends on which closures reach it
+                    edges.entry(operator.clone()).or_default().push(p2.clone());
+                    edges.entry(p1.clone()).or_default().push(p2.clone());
+                }
+                instantiated.insert(constraint);
+            }
+        }
+    }
+
+    let mut constraints = system.constraints;
+    constraints.extend(instantiated);
+    (edges, constraints)
+}
+
+/**
+ * re-analyses `edited` after it was changed from `old`, whose constraints are
+ * `old_constraints` and whose solution is `old_cache` and `old_env`
+ *
+ * The constraints of unchanged subexpressions are taken over, and only those of new
+ * ones are generated. The sets of all nodes that do not depend on a constraint which
+ * no longer exists are kept, and only the rest is solved again: the worklist starts
+ * with what flows from the kept sets into the other nodes and along new constraints.
+ * The result is the same as that of `analyse`.
+ */
+pub fn reanalyse(
+    old: &Expression,
+    old_constraints: &ConstraintsByLabel,
+    old_cache: &AbstractCache,
+    old_env: &AbstractEnv,
+    edited: Expression,
+) -> Reanalysis {
+    let (program, labels) = relabel_stable(old, edited);
+    let mut subexprs = vec![];
+    preorder(&program, &mut subexprs);
+
+    // unchanged subexpressions keep their labels, and so their constraints
+    let mut regenerated = 0;
+    let constraints: ConstraintsByLabel = subexprs
+        .iter()
+        .map(|e| match old_constraints.get(&e.label) {
+            Some(system) => (e.label, system.clone()),
+            None => {
+                regenerated += 1;
+                let mut system = ConstraintSystem::default();
+                e.local_constraints(&HashSet::new(), &mut system);
+                (e.label, system)
+            }
+        })
+        .collect();
+    let system = merge(&constraints);
+
+    // what the old solution does not satisfy yet
+    let old_system = merge(old_constraints);
+    let old_sites: HashSet<&CallSite> = old_system.call_sites.iter().collect();
+    let added = ConstraintSystem {
+        constraints: system
+            .constraints
+            .difference(&old_system.constraints)
+            .cloned()
+            .collect(),
+        call_sites: system
+            .call_sites
+            .iter()
+            .filter(|site| !old_sites.contains(site))
+            .cloned()
+            .collect(),
+    };
+
+    let (edges, old_constraints) = old_edges(old_system, old_cache);
+    let mut new_constraints = system.constraints.clone();
+    for call_site in &system.call_sites {
+        for t in old_cache.get(&call_site.operator).into_iter().flatten() {
+            new_constraints.extend(call_site.instantiate(t).into_iter().flatten());
+        }
+    }
+
+    // whatever was derived through a removed constraint may no longer hold
+    let mut affected: HashSet<ConSet> = HashSet::new();
+    let mut queue: VecDeque<ConSet> = old_constraints
+        .difference(&new_constraints)
+        .map(|constraint| match constraint {
+            Constraint::Unconditional(_, p2) | Constraint::Conditional(_, _, p2) => p2.clone(),
+        })
+        .collect();
+    while let Some(node) = queue.pop_front() {
+        if affected.insert(node.clone()) {
+            queue.extend(edges.get(&node).into_iter().flatten().cloned());
+        }
+    }
+
+    let nodes: Vec<ConSet> = subexprs
+        .iter()
+        .map(|e| ConSet::Cache(e.label))
+        .chain(program.variables().into_iter().map(ConSet::Env))
+        .collect();
+    let initial: HashMap<ConSet, HashSet<Term>> = nodes
+        .iter()
+        .filter(|node| !affected.contains(node))
+        .filter_map(|node| {
+            let old_terms = match node {
+                ConSet::Cache(l) => old_cache.get(l),
+                ConSet::Env(x) => old_env.get(x),
+                ConSet::SingleTerm(_) => None,
+            };
+            old_terms.map(|terms| (node.clone(), terms.clone()))
+        })
+        .collect();
+
+    let reused_nodes = initial.len();
+    let solution = solve_from(&program, &system, &initial, &added);
+    let iterations = solution.statistics.iterations;
+    let (cache, env) = solution.into_analysis();
+
+    Reanalysis {
+        recomputed_nodes: nodes.len() - reused_nodes,
+        reused_nodes,
+        regenerated,
+        iterations,
+        cache,
+        env,
+        system,
+        constraints,
+        labels,
+        program,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        generator::{random_edit, Rng},
+        parser,
+    };
+
+    #[test]
+    fn same_as_analysing_from_scratch() {
+        for seed in 0..40 {
+            let (source, edited) = random_edit(&mut Rng::new(seed), 60);
+            let old = parser::parse(&source).unwrap();
+            let (old_cache, old_env) = analyse(&old, &old.constraint_system());
+            let mut constraints = constraints_by_label(&old);
+
+            let mut previous = (old, old_cache, old_env);
+            // edit it, then edit it back
+            for source in [&edited, &source] {
+                let parsed = parser::parse(source).unwrap();
+                let (old, old_cache, old_env) = &previous;
+                let result = reanalyse(old, &constraints, old_cache, old_env, parsed.clone());
+
+                let system = result.program.constraint_system();
+                assert_eq!(result.system.constraints, system.constraints, "{source}");
+                assert_eq!(
+                    HashSet::<_>::from_iter(&result.system.call_sites),
+                    HashSet::from_iter(&system.call_sites)
+                );
+                assert_eq!(
+                    (result.cache.clone(), result.env.clone()),
+                    analyse(&result.program, &system),
+                    "{source}"
+                );
+                // two of the three parts are unchanged
+                assert!(result.regenerated < result.constraints.len(), "{source}");
+                assert!(parsed
+                    .labels()
+                    .iter()
+                    .all(|label| result.labels.contains_key(label)));
+
+                constraints = result.constraints;
+                previous = (result.program, result.cache, result.env);
+            }
+        }
+    }
+
+    #[test]
+    fn nothing_to_solve_without_changes() {
+        let source = random_edit(&mut Rng::new(0), 60).0;
+        let program = parser::parse(&source).unwrap();
+        let (cache, env) = analyse(&program, &program.constraint_system());
+
+        let result = reanalyse(
+            &program,
+            &constraints_by_label(&program),
+            &cache,
+            &env,
+            parser::parse(&source).unwrap(),
+        );
+        assert_eq!((result.recomputed_nodes, result.iterations), (0, 0));
+        assert_eq!((result.cache, result.env), (cache, env));
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// index of a type in the arena of `Inference`
+type TypeId = usize;
+
+#[derive(Debug, Clone, Copy)]
+enum Node {
+    /// an unknown type, created while inferring the `let` at nesting depth `level`
+    Unknown {
+        level: usize,
+    },
+    /// the same type as another one
+    Link(TypeId),
+    Int,
+    Function(TypeId, TypeId),
+}
+
+/// simple type, where unknown types are numbered from 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Variable(usize),
+    Function(Box<Type>, Box<Type>),
+}
+
+impl Display for Type {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int => write!(formatter, "int"),
+            Self::Variable(n) => {
+                write!(formatter, "'{}", (b'a' + (n % 26) as u8) as char)?;
+                // 'a, ..., 'z, 'a1, ..., 'z1, 'a2, ...
+                match n / 26 {
+                    0 => Ok(()),
+                    round => write!(formatter, "{round}"),
+                }
+            }
+            Self::Function(parameter, result) => match **parameter {
+                Self::Function(..) => write!(formatter, "({parameter}) -> {result}"),
+                _ => write!(formatter, "{parameter} -> {result}"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeError {
+    /// the expression `label` has type `found` where `expected` is required
+    Mismatch {
+        label: Label,
+        expected: Type,
+        found: Type,
+    },
+    /// the type of `label` would have to contain itself
+    Infinite { label: Label },
+    /// `variable` is not bound where it is used at `label`
+    Unbound { label: Label, variable: Variable },
+}
+
+impl TypeError {
+    pub fn label(&self) -> Label {
+        match self {
+            Self::Mismatch { label, .. }
+            | Self::Infinite { label }
+            | Self::Unbound { label, .. } => *label,
+        }
+    }
+}
+
+impl Display for TypeError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mismatch {
+                expected, found, ..
+            } => write!(formatter, "expected {expected}, found {found}"),
+            Self::Infinite { .. } => write!(formatter, "infinite type"),
+            Self::Unbound { variable, .. } => write!(formatter, "unbound variable {variable}"),
+        }
+    }
+}
+
+/// type scheme `∀ generic. body`
+#[derive(Debug, Clone)]
+struct Scheme {
+    generic: Vec<TypeId>,
+    body: TypeId,
+}
+
+/**
+ * Hindley–Milner type inference (algorithm J), where types are unified in place
+ *
+ * every unknown type remembers how deeply nested the `let` it was created in is, so
+ * that generalising the type of a `let`-bound expression only has to look at the type
+ * itself and not at the whole environment
+ */
+struct Inference {
+    nodes: Vec<Node>,
+    /// the type of every labelled subexpression
+    label_types: HashMap<Label, TypeId>,
+    level: usize,
+}
+
+impl Inference {
+    fn new_node(&mut self, node: Node) -> TypeId {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn fresh(&mut self) -> TypeId {
+        self.new_node(Node::Unknown { level: self.level })
+    }
+
+    fn find(&mut self, t: TypeId) -> TypeId {
+        match self.nodes[t] {
+            Node::Link(u) => {
+                let representative = self.find(u);
+                self.nodes[t] = Node::Link(representative);
+                representative
+            }
+            _ => t,
+        }
+    }
+
+    /// whether `u` occurs in `t`; also lowers the levels in `t` to that of `u`
+    fn occurs(&mut self, u: TypeId, level: usize, t: TypeId) -> bool {
+        let t = self.find(t);
+        match self.nodes[t] {
+            _ if t == u => true,
+            Node::Unknown { level: other } => {
+                self.nodes[t] = Node::Unknown {
+                    level: level.min(other),
+                };
+                false
+            }
+            Node::Function(parameter, result) => {
+                self.occurs(u, level, parameter) || self.occurs(u, level, result)
+            }
+            Node::Int | Node::Link(_) => false,
+        }
+    }
+
+    fn unify(&mut self, expected: TypeId, found: TypeId, label: Label) -> Result<(), TypeError> {
+        let (a, b) = (self.find(expected), self.find(found));
+        if a == b {
+            return Ok(());
+        }
+
+        match (self.nodes[a], self.nodes[b]) {
+            (Node::Unknown { level }, _) => {
+                if self.occurs(a, level, b) {
+                    return Err(TypeError::Infinite { label });
+                }
+                self.nodes[a] = Node::Link(b);
+                Ok(())
+            }
+            (_, Node::Unknown { .. }) => self.unify(b, a, label),
+            (Node::Int, Node::Int) => Ok(()),
+            (Node::Function(p1, r1), Node::Function(p2, r2)) => {
+                self.unify(p1, p2, label)?;
+                self.unify(r1, r2, label)
+            }
+            _ => Err(TypeError::Mismatch {
+                label,
+                expected: self.resolve(a, &mut HashMap::new()),
+                found: self.resolve(b, &mut HashMap::new()),
+            }),
+        }
+    }
+
+    /// the unknown types in `t` that were created inside the current `let`
+    fn generic(&mut self, t: TypeId, generic: &mut Vec<TypeId>) {
+        let t = self.find(t);
+        match self.nodes[t] {
+            Node::Unknown { level } if level > self.level && !generic.contains(&t) => {
+                generic.push(t)
+            }
+            Node::Function(parameter, result) => {
+                self.generic(parameter, generic);
+                self.generic(result, generic);
+            }
+            _ => {}
+        }
+    }
+
+    /// a copy of `scheme` with fresh unknown types for its generic ones
+    fn instantiate(&mut self, scheme: &Scheme) -> TypeId {
+        let fresh: HashMap<TypeId, TypeId> =
+            scheme.generic.iter().map(|&t| (t, self.fresh())).collect();
+        self.copy(scheme.body, &fresh)
+    }
+
+    fn copy(&mut self, t: TypeId, fresh: &HashMap<TypeId, TypeId>) -> TypeId {
+        let t = self.find(t);
+        match self.nodes[t] {
+            Node::Function(parameter, result) => {
+                let parameter = self.copy(parameter, fresh);
+                let result = self.copy(result, fresh);
+                self.new_node(Node::Function(parameter, result))
+            }
+            _ => fresh.get(&t).copied().unwrap_or(t),
+        }
+    }
+
+    fn infer(
+        &mut self,
+        expr: &Expression,
+        env: &mut Vec<(Variable, Scheme)>,
+    ) -> Result<TypeId, TypeError> {
+        let l = expr.label;
+        let t = match &expr.term {
+            Term::Constant(_) => self.new_node(Node::Int),
+
+            Term::Variable(x) => {
+                let Some((_, scheme)) = env.iter().rev().find(|(y, _)| y == x) else {
+                    return Err(TypeError::Unbound {
+                        label: l,
+                        variable: *x,
+                    });
+                };
+                let scheme = scheme.clone();
+                self.instantiate(&scheme)
+            }
+
+            Term::Closure(x, e0) => {
+                let parameter = self.fresh();
+                env.push((*x, Scheme::monomorphic(parameter)));
+                let result = self.infer(e0, env);
+                env.pop();
+                self.new_node(Node::Function(parameter, result?))
+            }
+
+            Term::RecursiveClosure(f, x, e0) => {
+                let parameter = self.fresh();
+                let result = self.fresh();
+                let function = self.new_node(Node::Function(parameter, result));
+                env.push((*f, Scheme::monomorphic(function)));
+                env.push((*x, Scheme::monomorphic(parameter)));
+                let body = self.infer(e0, env);
+                env.truncate(env.len() - 2);
+                self.unify(result, body?, e0.label)?;
+                function
+            }
+
+            Term::Application(e1, e2) => {
+                let function = self.infer(e1, env)?;
+                let argument = self.infer(e2, env)?;
+                let result = self.fresh();
+                let expected = self.new_node(Node::Function(argument, result));
+                self.unify(expected, function, e1.label)?;
+                result
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                let condition = self.infer(e0, env)?;
+                let int = self.new_node(Node::Int);
+                self.unify(int, condition, e0.label)?;
+                let then = self.infer(e1, env)?;
+                let otherwise = self.infer(e2, env)?;
+                self.unify(then, otherwise, e2.label)?;
+                then
+            }
+
+            Term::Let(x, e1, e2) => {
+                self.level += 1;
+                let bound = self.infer(e1, env);
+                self.level -= 1;
+                let bound = bound?;
+
+                let mut generic = vec![];
+                self.generic(bound, &mut generic);
+                env.push((
+                    *x,
+                    Scheme {
+                        generic,
+                        body: bound,
+                    },
+                ));
+                let body = self.infer(e2, env);
+                env.pop();
+                body?
+            }
+
+            // all operators take and return integers (comparisons return 1 or 0)
+            Term::BinaryOp(e1, _, e2) => {
+                for operand in [e1, e2] {
+                    let t = self.infer(operand, env)?;
+                    let int = self.new_node(Node::Int);
+                    self.unify(int, t, operand.label)?;
+                }
+                self.new_node(Node::Int)
+            }
+        };
+
+        self.label_types.insert(l, t);
+        Ok(t)
+    }
+
+    fn resolve(&mut self, t: TypeId, names: &mut HashMap<TypeId, usize>) -> Type {
+        let t = self.find(t);
+        match self.nodes[t] {
+            Node::Unknown { .. } | Node::Link(_) => {
+                let next = names.len();
+                Type::Variable(*names.entry(t).or_insert(next))
+            }
+            Node::Int => Type::Int,
+            Node::Function(parameter, result) => Type::Function(
+                Box::new(self.resolve(parameter, names)),
+                Box::new(self.resolve(result, names)),
+            ),
+        }
+    }
+}
+
+impl Scheme {
+    fn monomorphic(body: TypeId) -> Self {
+        Self {
+            generic: vec![],
+            body,
+        }
+    }
+}
+
+/// infers the type of every labelled subexpression of `expr`, with `let`-polymorphism
+///
+/// the unknown types are numbered consistently across all labels
+pub fn infer_types(expr: &Expression) -> Result<HashMap<Label, Type>, TypeError> {
+    let mut inference = Inference {
+        nodes: vec![],
+        label_types: HashMap::new(),
+        level: 0,
+    };
+    inference.infer(expr, &mut vec![])?;
+
+    let mut labels = Vec::from_iter(inference.label_types.clone());
+    labels.sort();
+    let mut names = HashMap::new();
+    Ok(labels
+        .into_iter()
+        .map(|(label, t)| (label, inference.resolve(t, &mut names)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{examples, parse, parse_with_spans};
+
+    /// the type error in `input`, and the source of the expression it is reported at
+    fn type_error(input: &str) -> (TypeError, &str) {
+        let (program, spans) = parse_with_spans(input).unwrap();
+        let error = infer_types(&program).unwrap_err();
+        let span = spans[&error.label()];
+        (error, &input[span.start..span.end])
+    }
+
+    #[test]
+    fn let_polymorphism_in_example4() {
+        let types = infer_types(&examples()[3]).unwrap();
+        let type_of = |label| types[&label].to_string();
+        assert_eq!(type_of(2), "'a -> 'a");
+        // each use of `f` has its own instance of the type of `fn x -> x`
+        assert_eq!(
+            type_of(3),
+            "(('b -> 'b) -> 'b -> 'b) -> ('b -> 'b) -> 'b -> 'b"
+        );
+        assert_eq!(type_of(4), "('b -> 'b) -> 'b -> 'b");
+        assert_eq!(type_of(7), "'b -> 'b");
+        assert_eq!(type_of(9), "'b -> 'b");
+    }
+
+    #[test]
+    fn self_application_is_an_infinite_type() {
+        let (error, source) = type_error("fn x -> x x ");
+        assert_eq!(error, TypeError::Infinite { label: 1 });
+        assert_eq!(source, "x");
+    }
+
+    #[test]
+    fn applying_a_number_is_a_mismatch() {
+        let (error, source) = type_error("let f = fn x -> x in (1 (f 2)) ");
+        assert_eq!(error.label(), 3);
+        assert_eq!(source, "1");
+        assert_eq!(error.to_string(), "expected int -> 'a, found int");
+    }
+
+    #[test]
+    fn free_variables_are_unbound() {
+        let (error, source) = type_error("let f = fn x -> x in\n(f h) ");
+        assert_eq!(
+            error,
+            TypeError::Unbound {
+                label: 4,
+                variable: 'h'
+            }
+        );
+        assert_eq!(source, "h");
+        assert!(infer_types(&parse("fn x -> x ").unwrap()).is_ok());
+    }
+
+    #[test]
+    fn type_variables_beyond_z_are_numbered() {
+        let names = [0, 25, 26, 53].map(|n| Type::Variable(n).to_string());
+        assert_eq!(names, ["'a", "'z", "'a1", "'b2"]);
+    }
+}
@@ -0,0 +1,647 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    rc::Rc,
+};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::ConSet,
+    domain::{eval_binary_op, BinaryOpError},
+    expression::Expression,
+    term::Term,
+    types::{Constant, Label, Operator, Variable},
+};
+
+/// evaluation steps after which a program is assumed not to terminate
+pub const MAX_STEPS: usize = 10_000_000;
+
+/// run-time value, where a closure is represented by `F` (the closure expression in
+/// the original language)
+#[derive(Debug, Clone)]
+pub enum Value<F> {
+    Int(Constant),
+    /// a closure with the environment it was evaluated in
+    Closure(F, Env<F>),
+}
+
+impl Display for Value<&Expression> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Int(c) => write!(f, "{c}"),
+            Self::Closure(closure, _) => write!(f, "{}", closure.term),
+        }
+    }
+}
+
+/// a variable bound to a value, in front of the rest of the environment
+type Binding<F> = (Variable, Value<F>, Env<F>);
+
+/// immutable environment, shared between the closures that capture it
+#[derive(Debug, Clone)]
+pub struct Env<F>(Option<Rc<Binding<F>>>);
+
+impl<F> Default for Env<F> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<F: Clone> Env<F> {
+    pub fn bind(&self, x: Variable, value: Value<F>) -> Self {
+        Self(Some(Rc::new((x, value, self.clone()))))
+    }
+
+    pub fn lookup(&self, x: Variable) -> Option<&Value<F>> {
+        let mut env = self;
+        while let Some(binding) = &env.0 {
+            let (y, value, rest) = binding.as_ref();
+            if *y == x {
+                return Some(value);
+            }
+            env = rest;
+        }
+        None
+    }
+}
+
+impl<F> Drop for Env<F> {
+    /// frees chains of environments (e.g. built up by a loop) without recursing
+    fn drop(&mut self) {
+        let mut pending = vec![self.0.take()];
+        while let Some(binding) = pending.pop() {
+            let Some(Ok((_, value, mut rest))) = binding.map(Rc::try_unwrap) else {
+                continue;
+            };
+            pending.push(rest.0.take());
+            if let Value::Closure(_, mut env) = value {
+                pending.push(env.0.take());
+            }
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// the operator of the application `label` is not a closure
+    NotAFunction {
+        label: Label,
+        value: String,
+    },
+    /// an operand of the operation or the condition of the `if` at `label` is a closure
+    NotAnInteger {
+        label: Label,
+        value: String,
+    },
+    DivisionByZero {
+        label: Label,
+    },
+    Overflow {
+        label: Label,
+    },
+    Unbound {
+        label: Label,
+        variable: Variable,
+    },
+    /// the program did not finish within `MAX_STEPS` steps
+    StepLimit,
+}
+
+impl RuntimeError {
+    /// the label of the expression whose evaluation failed, if any
+    pub fn label(&self) -> Option<Label> {
+        match self {
+            Self::NotAFunction { label, .. }
+            | Self::NotAnInteger { label, .. }
+            | Self::DivisionByZero { label }
+            | Self::Overflow { label }
+            | Self::Unbound { label, .. } => Some(*label),
+            Self::StepLimit => None,
+        }
+    }
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAFunction { value, .. } => write!(f, "cannot apply {value}"),
+            Self::NotAnInteger { value, .. } => write!(f, "expected an integer, found {value}"),
+            Self::DivisionByZero { .. } => write!(f, "division by zero"),
+            Self::Overflow { .. } => write!(f, "integer overflow"),
+            Self::Unbound { variable, .. } => write!(f, "unbound variable {variable}"),
+            Self::StepLimit => write!(f, "no result after {MAX_STEPS} steps"),
+        }
+    }
+}
+
+/// closures (by the label of their `fn` or `fun`) seen at run time at each label and
+/// bound to each variable
+#[derive(Debug, Default)]
+pub struct Observations {
+    pub cache: HashMap<Label, HashSet<Label>>,
+    pub env: HashMap<Variable, HashSet<Label>>,
+}
+
+/// an expression of a language the machine evaluates, by what it does
+pub enum Step<'a, N> {
+    Constant(Constant),
+    Variable(Variable),
+    /// evaluates to a closure, see `Language::close`
+    Closure,
+    Application(&'a N, &'a N),
+    IfThenElse(&'a N, &'a N, &'a N),
+    Let(Variable, &'a N, &'a N),
+    BinaryOp(&'a N, &'a Operator, &'a N),
+}
+
+/// the body of the closure called by an application, and how to bind its parameter
+pub struct Callee<'a, N, F> {
+    /// the environment of the closure
+    pub env: Env<F>,
+    /// for `fun f x -> e0`, `f`, which is bound to the closure itself
+    pub recursive: Option<Variable>,
+    pub parameter: Variable,
+    pub body: &'a N,
+}
+
+/// a call-by-value language with closures, whose expressions `N` are evaluated by the
+/// machine and whose closures are represented by `F`
+pub trait Language<'a> {
+    type Node;
+    type Function: Clone;
+
+    fn label(node: &Self::Node) -> Label;
+    fn step(node: &'a Self::Node) -> Step<'a, Self::Node>;
+    /// the label of the closure expression a closure comes from
+    fn closure_label(function: &Self::Function) -> Label;
+    /// the value of the closure expression `node` in `env`
+    fn close(&self, node: &'a Self::Node, env: Env<Self::Function>) -> Value<Self::Function>;
+    /// what the application `app` calls, when its operator evaluates to `function`
+    fn callee(
+        &self,
+        app: Label,
+        function: &Value<Self::Function>,
+    ) -> Result<Callee<'a, Self::Node, Self::Function>, RuntimeError>;
+}
+
+/// what to do with the value of the expression evaluated last
+enum Frame<'a, N, F> {
+    /// record the value as the value of `label` (only when observing)
+    Observe { label: Label },
+    /// evaluate the operand of the application `app`
+    Operand {
+        app: Label,
+        operand: &'a N,
+        env: Env<F>,
+    },
+    /// apply `function` to the value
+    Call { app: Label, function: Value<F> },
+    /// evaluate one of the branches of the `if` at `label`, depending on the value
+    Branches {
+        label: Label,
+        then: &'a N,
+        otherwise: &'a N,
+        env: Env<F>,
+    },
+    /// bind the value to `x` in `body`
+    LetBody {
+        x: Variable,
+        body: &'a N,
+        env: Env<F>,
+    },
+    /// evaluate the right operand `rhs` of the operation at `label`
+    RightOperand {
+        label: Label,
+        op: &'a Operator,
+        rhs: &'a N,
+        env: Env<F>,
+    },
+    /// apply `op` to `lhs` and the value
+    Operator {
+        label: Label,
+        op: &'a Operator,
+        lhs: Constant,
+    },
+}
+
+/// state of the CEK machine: either an expression to evaluate or a value to return
+enum Control<'a, N, F> {
+    Eval(&'a N, Env<F>),
+    Return(Value<F>),
+}
+
+/**
+ * call-by-value evaluator, as a CEK machine with an explicit stack of frames so that deep
+ * recursion in the program does not overflow the stack of the interpreter
+ */
+pub struct Machine<'a, L: Language<'a>> {
+    language: L,
+    stack: Vec<Frame<'a, L::Node, L::Function>>,
+    steps: usize,
+    /// the flows of closures so far, if they are recorded
+    observations: Option<Observations>,
+}
+
+impl<'a, L: Language<'a>> Machine<'a, L>
+where
+    Value<L::Function>: Display,
+{
+    pub fn new(language: L) -> Self {
+        Self {
+            language,
+            stack: vec![],
+            steps: 0,
+            observations: None,
+        }
+    }
+
+    fn observe(&mut self, node: ConSet, value: &Value<L::Function>) {
+        let (Some(observations), Value::Closure(closure, _)) = (&mut self.observations, value)
+        else {
+            return;
+        };
+        let closures = match node {
+            ConSet::Cache(l) => observations.cache.entry(l).or_default(),
+            ConSet::Env(x) => observations.env.entry(x).or_default(),
+            ConSet::SingleTerm(_) => unreachable!("observed value of a single term"),
+        };
+        closures.insert(L::closure_label(closure));
+    }
+
+    fn bind(
+        &mut self,
+        env: &Env<L::Function>,
+        x: Variable,
+        value: Value<L::Function>,
+    ) -> Env<L::Function> {
+        self.observe(ConSet::Env(x), &value);
+        env.bind(x, value)
+    }
+
+    fn eval(
+        &mut self,
+        node: &'a L::Node,
+        env: Env<L::Function>,
+    ) -> Result<Control<'a, L::Node, L::Function>, RuntimeError> {
+        let label = L::label(node);
+        let step = L::step(node);
+        let compound = matches!(
+            step,
+            Step::Application(..) | Step::IfThenElse(..) | Step::Let(..)
+        );
+        if compound && self.observations.is_some() {
+            self.stack.push(Frame::Observe { label });
+        }
+
+        let value = match step {
+            Step::Constant(c) => Value::Int(c),
+
+            Step::Variable(x) => match env.lookup(x) {
+                Some(value) => value.clone(),
+                None => return Err(RuntimeError::Unbound { label, variable: x }),
+            },
+
+            Step::Closure => self.language.close(node, env),
+
+            Step::Application(e1, e2) => {
+                self.stack.push(Frame::Operand {
+                    app: label,
+                    operand: e2,
+                    env: env.clone(),
+                });
+                return Ok(Control::Eval(e1, env));
+            }
+
+            Step::IfThenElse(e0, e1, e2) => {
+                self.stack.push(Frame::Branches {
+                    label,
+                    then: e1,
+                    otherwise: e2,
+                    env: env.clone(),
+                });
+                return Ok(Control::Eval(e0, env));
+            }
+
+            Step::Let(x, e1, e2) => {
+                self.stack.push(Frame::LetBody {
+                    x,
+                    body: e2,
+                    env: env.clone(),
+                });
+                return Ok(Control::Eval(e1, env));
+            }
+
+            Step::BinaryOp(e1, op, e2) => {
+                self.stack.push(Frame::RightOperand {
+                    label,
+                    op,
+                    rhs: e2,
+                    env: env.clone(),
+                });
+                return Ok(Control::Eval(e1, env));
+            }
+        };
+
+        self.observe(ConSet::Cache(label), &value);
+        Ok(Control::Return(value))
+    }
+
+    fn apply(
+        &mut self,
+        frame: Frame<'a, L::Node, L::Function>,
+        value: Value<L::Function>,
+    ) -> Result<Control<'a, L::Node, L::Function>, RuntimeError> {
+        match frame {
+            Frame::Observe { label } => {
+                self.observe(ConSet::Cache(label), &value);
+                Ok(Control::Return(value))
+            }
+
+            Frame::Operand { app, operand, env } => {
+                self.stack.push(Frame::Call {
+                    app,
+                    function: value,
+                });
+                Ok(Control::Eval(operand, env))
+            }
+
+            Frame::Call { app, function } => {
+                let callee = self.language.callee(app, &function)?;
+                let mut env = callee.env;
+                if let Some(f) = callee.recursive {
+                    env = self.bind(&env, f, function);
+                }
+                let env = self.bind(&env, callee.parameter, value);
+                Ok(Control::Eval(callee.body, env))
+            }
+
+            Frame::Branches {
+                label,
+                then,
+                otherwise,
+                env,
+            } => {
+                let condition = integer(label, &value)?;
+                Ok(Control::Eval(
+                    if condition != 0 { then } else { otherwise },
+                    env,
+                ))
+            }
+
+            Frame::LetBody { x, body, env } => {
+                let env = self.bind(&env, x, value);
+                Ok(Control::Eval(body, env))
+            }
+
+            Frame::RightOperand {
+                label,
+                op,
+                rhs,
+                env,
+            } => {
+                self.stack.push(Frame::Operator {
+                    label,
+                    op,
+                    lhs: integer(label, &value)?,
+                });
+                Ok(Control::Eval(rhs, env))
+            }
+
+            Frame::Operator { label, op, lhs } => {
+                let rhs = integer(label, &value)?;
+                match eval_binary_op(lhs, op, rhs) {
+                    Ok(c) => Ok(Control::Return(Value::Int(c))),
+                    Err(BinaryOpError::DivisionByZero) => {
+                        Err(RuntimeError::DivisionByZero { label })
+                    }
+                    Err(BinaryOpError::Overflow) => Err(RuntimeError::Overflow { label }),
+                }
+            }
+        }
+    }
+
+    /// evaluates `node` in the empty environment
+    pub fn run(&mut self, node: &'a L::Node) -> Result<Value<L::Function>, RuntimeError> {
+        let mut control = Control::Eval(node, Env::default());
+        loop {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return Err(RuntimeError::StepLimit);
+            }
+
+            control = match control {
+                Control::Eval(node, env) => self.eval(node, env)?,
+                Control::Return(value) => match self.stack.pop() {
+                    Some(frame) => self.apply(frame, value)?,
+                    None => return Ok(value),
+                },
+            };
+        }
+    }
+}
+
+/// the integer `value`, which the expression at `label` requires
+fn integer<F>(label: Label, value: &Value<F>) -> Result<Constant, RuntimeError>
+where
+    Value<F>: Display,
+{
+    match value {
+        Value::Int(c) => Ok(*c),
+        Value::Closure(..) => Err(RuntimeError::NotAnInteger {
+            label,
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// the language of the analysed programs
+struct Original;
+
+impl<'a> Language<'a> for Original {
+    type Node = Expression;
+    type Function = &'a Expression;
+
+    fn label(node: &Expression) -> Label {
+        node.label
+    }
+
+    fn step(node: &'a Expression) -> Step<'a, Expression> {
+        match &node.term {
+            Term::Constant(c) => Step::Constant(*c),
+            Term::Variable(x) => Step::Variable(*x),
+            Term::Closure(..) | Term::RecursiveClosure(..) => Step::Closure,
+            Term::Application(e1, e2) => Step::Application(e1, e2),
+            Term::IfThenElse(e0, e1, e2) => Step::IfThenElse(e0, e1, e2),
+            Term::Let(x, e1, e2) => Step::Let(*x, e1, e2),
+            Term::BinaryOp(e1, op, e2) => Step::BinaryOp(e1, op, e2),
+        }
+    }
+
+    fn closure_label(function: &&'a Expression) -> Label {
+        function.label
+    }
+
+    fn close(&self, node: &'a Expression, env: Env<&'a Expression>) -> Value<&'a Expression> {
+        Value::Closure(node, env)
+    }
+
+    fn callee(
+        &self,
+        app: Label,
+        function: &Value<&'a Expression>,
+    ) -> Result<Callee<'a, Expression, &'a Expression>, RuntimeError> {
+        let Value::Closure(closure, env) = function else {
+            return Err(RuntimeError::NotAFunction {
+                label: app,
+                value: function.to_string(),
+            });
+        };
+        let (recursive, parameter, body) = match &closure.term {
+            Term::Closure(x, e0) => (None, *x, e0),
+            Term::RecursiveClosure(f, x, e0) => (Some(*f), *x, e0),
+            _ => unreachable!("closure value of a non-closure"),
+        };
+        Ok(Callee {
+            env: env.clone(),
+            recursive,
+            parameter,
+            body,
+        })
+    }
+}
+
+/// evaluates `expr` call-by-value
+pub fn evaluate(expr: &Expression) -> Result<Value<&Expression>, RuntimeError> {
+    Machine::new(Original).run(expr)
+}
+
+/// evaluates `expr` like `evaluate`, and records which closures flow where until it
+/// finishes or fails
+pub fn observe_flows(
+    expr: &Expression,
+) -> (Observations, Result<Value<&Expression>, RuntimeError>) {
+    let mut machine = Machine::new(Original);
+    machine.observations = Some(Observations::default());
+    let result = machine.run(expr);
+    (machine.observations.unwrap_or_default(), result)
+}
+
+/// a closure observed at run time in a set `C(l)` or `r(x)` that does not contain it
+pub struct UnsoundFlow {
+    pub node: ConSet,
+    pub closure: Term,
+}
+
+/**
+ * the observed flows of closures that `cache` and `env` miss
+ *
+ * a sound analysis has every closure that a run of the program produces at a label or
+ * binds to a variable in the corresponding set, so each of these is a bug in the analysis
+ */
+pub fn unsound_flows(
+    expr: &Expression,
+    observations: &Observations,
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+) -> Vec<UnsoundFlow> {
+    let terms: HashMap<Label, &Term> = expr
+        .subexprs()
+        .into_iter()
+        .map(|e| (e.label, &e.term))
+        .collect();
+
+    let mut labels = Vec::from_iter(&observations.cache);
+    labels.sort_by_key(|(label, _)| **label);
+    let mut variables = Vec::from_iter(&observations.env);
+    variables.sort_by_key(|(x, _)| **x);
+
+    let mut unsound = vec![];
+    let mut check = |node: ConSet, closures: &HashSet<Label>, analysed: Option<&HashSet<Term>>| {
+        let mut closures = Vec::from_iter(closures);
+        closures.sort();
+        for closure in closures {
+            let term = terms[closure];
+            if !analysed.is_some_and(|analysed| analysed.contains(term)) {
+                unsound.push(UnsoundFlow {
+                    node: node.clone(),
+                    closure: term.clone(),
+                });
+            }
+        }
+    };
+    for (label, closures) in labels {
+        check(ConSet::Cache(*label), closures, cache.get(label));
+    }
+    for (x, closures) in variables {
+        check(ConSet::Env(*x), closures, env.get(x));
+    }
+    unsound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser, parser::examples};
+
+    fn result(program: &Expression) -> Result<String, RuntimeError> {
+        evaluate(program).map(|value| value.to_string())
+    }
+
+    fn result_of(source: &str) -> Result<String, RuntimeError> {
+        result(&parser::parse(&format!("{source} ")).unwrap())
+    }
+
+    #[test]
+    fn results_of_the_examples() {
+        assert_eq!(
+            examples().each_ref().map(result),
+            [
+                Ok("fn y -> y³".to_string()),
+                Ok("7".to_string()),
+                Err(RuntimeError::Unbound {
+                    label: 18,
+                    variable: 'h'
+                }),
+                Ok("fn y -> y⁶".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_recursion() {
+        let sum = "(fun f n -> if n < 1 then 0 else n + (f (n - 1))) 10000";
+        assert_eq!(result_of(sum), Ok("50005000".to_string()));
+    }
+
+    #[test]
+    fn run_time_errors() {
+        assert_eq!(
+            result_of("1 2"),
+            Err(RuntimeError::NotAFunction {
+                label: 3,
+                value: "1".to_string()
+            })
+        );
+        assert_eq!(
+            result_of("7 / (3 - 3)"),
+            Err(RuntimeError::DivisionByZero { label: 5 })
+        );
+        assert_eq!(
+            result_of("(fun f x -> f x) 0"),
+            Err(RuntimeError::StepLimit)
+        );
+    }
+
+    #[test]
+    fn flows_of_the_examples_are_in_the_analysis() {
+        for program in examples() {
+            let (cache, env) = analyse(&program, &program.constraint_system());
+            let (observations, _) = observe_flows(&program);
+            assert!(unsound_flows(&program, &observations, &cache, &env).is_empty());
+        }
+
+        let [.., program] = examples();
+        let (observations, _) = observe_flows(&program);
+        assert_eq!(observations.cache[&5], HashSet::from([2]));
+        assert_eq!(observations.env[&'x'], HashSet::from([2, 7]));
+    }
+}
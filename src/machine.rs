@@ -0,0 +1,561 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// the labels of the last `k` applications that were entered, most recent first
+type Context = Vec<Label>;
+
+/// abstract address of a variable binding
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Address {
+    variable: Variable,
+    context: Context,
+}
+
+type Env = BTreeMap<Variable, Address>;
+
+/// abstract address of a continuation: the expression whose value it receives
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct KontAddress {
+    label: Label,
+    context: Context,
+}
+
+/// abstract value: a closure (by its label) with its environment, or some integer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Value {
+    Closure(Label, Env),
+    Number,
+}
+
+/// what to do with the value of an expression
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Frame {
+    /// evaluate the operand of application `app`
+    Operand { app: Label, env: Env },
+    /// call `function` with the value of the operand of application `app`
+    Call { app: Label, function: Value },
+    /// evaluate both branches of `if`
+    Branches { label: Label, env: Env },
+    /// bind the variable of `let` and evaluate its body
+    LetBody { label: Label, env: Env },
+    /// evaluate the right operand of a binary operation
+    RightOperand { label: Label, env: Env },
+    /// both operands of a binary operation have been evaluated
+    Operator { label: Label },
+    /// the value is also the value of `label` (of a call, `if` or `let`)
+    Result { label: Label },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum State {
+    /// evaluate the expression `label` in `env`
+    Eval {
+        label: Label,
+        env: Env,
+        kont: KontAddress,
+        time: Context,
+    },
+    /// the expression `label` evaluated to `value`
+    Return {
+        label: Label,
+        value: Value,
+        kont: KontAddress,
+        time: Context,
+    },
+}
+
+/// result of running the abstract machine, projected onto `C(l)` and `r(x)`
+pub struct MachineAnalysis {
+    pub cache: AbstractCache,
+    pub env: AbstractEnv,
+    /// number of distinct states that were reached
+    pub states: usize,
+}
+
+/**
+ * abstracted CESK machine (abstracting abstract machines, Van Horn & Might) with a single
+ * global store of values and one of continuations
+ *
+ * variables are bound at addresses `(x, the last k call sites)`, so `k = 0` gives 0-CFA
+ * and larger `k` give k-CFA. continuations are allocated at the expression whose value
+ * they receive, in the same context
+ */
+struct Machine<'a> {
+    k: usize,
+    exprs: HashMap<Label, &'a Expression>,
+
+    store: HashMap<Address, HashSet<Value>>,
+    kont_store: HashMap<KontAddress, HashSet<(Frame, KontAddress)>>,
+    /// values each expression has evaluated to
+    values: HashMap<Label, HashSet<Value>>,
+
+    seen: HashSet<State>,
+    work_list: VecDeque<State>,
+    /// states that have read an address, to step again when its values grow
+    readers: HashMap<Address, HashSet<State>>,
+    kont_readers: HashMap<KontAddress, HashSet<State>>,
+}
+
+impl<'a> Machine<'a> {
+    fn new(expr: &'a Expression, k: usize) -> Self {
+        Self {
+            k,
+            exprs: expr.subexprs().into_iter().map(|e| (e.label, e)).collect(),
+
+            store: HashMap::new(),
+            kont_store: HashMap::new(),
+            values: HashMap::new(),
+
+            seen: HashSet::new(),
+            work_list: VecDeque::new(),
+            readers: HashMap::new(),
+            kont_readers: HashMap::new(),
+        }
+    }
+
+    fn expr(&self, label: Label) -> &'a Expression {
+        self.exprs[&label]
+    }
+
+    fn visit(&mut self, state: State) {
+        if self.seen.insert(state.clone()) {
+            self.work_list.push_back(state);
+        }
+    }
+
+    fn bind(&mut self, address: Address, value: Value) {
+        if self.store.entry(address.clone()).or_default().insert(value) {
+            let readers = self.readers.get(&address).into_iter().flatten().cloned();
+            self.work_list.extend(readers);
+        }
+    }
+
+    fn push(&mut self, kont: KontAddress, frame: Frame, next: KontAddress) {
+        if self
+            .kont_store
+            .entry(kont.clone())
+            .or_default()
+            .insert((frame, next))
+        {
+            let readers = self.kont_readers.get(&kont).into_iter().flatten().cloned();
+            self.work_list.extend(readers);
+        }
+    }
+
+    /// evaluates `expr` with a new continuation `frame`, followed by `next`
+    fn eval_then(
+        &mut self,
+        expr: &Expression,
+        env: Env,
+        time: &Context,
+        frame: Frame,
+        next: KontAddress,
+    ) {
+        let kont = KontAddress {
+            label: expr.label,
+            context: time.clone(),
+        };
+        self.push(kont.clone(), frame, next);
+        self.visit(State::Eval {
+            label: expr.label,
+            env,
+            kont,
+            time: time.clone(),
+        });
+    }
+
+    /// the context after entering application `app`
+    fn tick(&self, app: Label, time: &Context) -> Context {
+        let mut context = vec![app];
+        context.extend(time.iter().copied());
+        context.truncate(self.k);
+        context
+    }
+
+    fn step(&mut self, state: State) {
+        match state.clone() {
+            State::Eval {
+                label,
+                env,
+                kont,
+                time,
+            } => {
+                let ret = |value| State::Return {
+                    label,
+                    value,
+                    kont: kont.clone(),
+                    time: time.clone(),
+                };
+
+                match &self.expr(label).term {
+                    Term::Constant(_) => self.visit(ret(Value::Number)),
+
+                    Term::Variable(x) => {
+                        // free variables have no value
+                        let Some(address) = env.get(x) else {
+                            return;
+                        };
+                        self.readers
+                            .entry(address.clone())
+                            .or_default()
+                            .insert(state);
+                        let values: Vec<Value> = self
+                            .store
+                            .get(address)
+                            .into_iter()
+                            .flatten()
+                            .cloned()
+                            .collect();
+                        for value in values {
+                            self.visit(ret(value));
+                        }
+                    }
+
+                    Term::Closure(..) => self.visit(ret(Value::Closure(label, env))),
+
+                    // as in 0-CFA, the closure is bound to its name where it is created
+                    Term::RecursiveClosure(f, _, _) => {
+                        let address = Address {
+                            variable: *f,
+                            context: time.clone(),
+                        };
+                        let mut env = env;
+                        env.insert(*f, address.clone());
+                        let closure = Value::Closure(label, env);
+                        self.bind(address, closure.clone());
+                        self.visit(ret(closure));
+                    }
+
+                    Term::Application(e1, _) => self.eval_then(
+                        e1,
+                        env.clone(),
+                        &time,
+                        Frame::Operand { app: label, env },
+                        kont,
+                    ),
+
+                    Term::IfThenElse(e0, _, _) => {
+                        self.eval_then(e0, env.clone(), &time, Frame::Branches { label, env }, kont)
+                    }
+
+                    Term::Let(_, e1, _) => {
+                        self.eval_then(e1, env.clone(), &time, Frame::LetBody { label, env }, kont)
+                    }
+
+                    Term::BinaryOp(e1, _, _) => {
+                        let frame = Frame::RightOperand {
+                            label,
+                            env: env.clone(),
+                        };
+                        self.eval_then(e1, env, &time, frame, kont)
+                    }
+                }
+            }
+
+            State::Return {
+                label,
+                value,
+                kont,
+                time,
+            } => {
+                self.values.entry(label).or_default().insert(value.clone());
+
+                self.kont_readers
+                    .entry(kont.clone())
+                    .or_default()
+                    .insert(state);
+                let frames: Vec<(Frame, KontAddress)> = self
+                    .kont_store
+                    .get(&kont)
+                    .into_iter()
+                    .flatten()
+                    .cloned()
+                    .collect();
+                for (frame, next) in frames {
+                    self.apply(frame, value.clone(), next, &time);
+                }
+            }
+        }
+    }
+
+    /// passes `value` to the continuation `frame`, which is followed by `next`
+    fn apply(&mut self, frame: Frame, value: Value, next: KontAddress, time: &Context) {
+        let ret = |label, value| State::Return {
+            label,
+            value,
+            kont: next.clone(),
+            time: time.clone(),
+        };
+
+        match frame {
+            Frame::Operand { app, env } => {
+                let Term::Application(_, e2) = &self.expr(app).term else {
+                    unreachable!("operand frame of a non-application")
+                };
+                let frame = Frame::Call {
+                    app,
+                    function: value,
+                };
+                self.eval_then(e2, env, time, frame, next.clone());
+            }
+
+            Frame::Call { app, function } => {
+                // applying a number gets stuck
+                let Value::Closure(closure, mut env) = function.clone() else {
+                    return;
+                };
+                let time = self.tick(app, time);
+
+                let (Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0)) =
+                    &self.expr(closure).term
+                else {
+                    unreachable!("closure value of a non-closure")
+                };
+                let x = *x;
+                let address = Address {
+                    variable: x,
+                    context: time.clone(),
+                };
+                env.insert(x, address.clone());
+                self.bind(address, value);
+
+                self.eval_then(e0, env, &time, Frame::Result { label: app }, next.clone());
+            }
+
+            Frame::Branches { label, env } => {
+                let Term::IfThenElse(_, e1, e2) = &self.expr(label).term else {
+                    unreachable!("branches frame of a non-conditional")
+                };
+                // the condition is not interpreted, so both branches may be taken
+                for branch in [e1, e2] {
+                    self.eval_then(
+                        branch,
+                        env.clone(),
+                        time,
+                        Frame::Result { label },
+                        next.clone(),
+                    );
+                }
+            }
+
+            Frame::LetBody { label, mut env } => {
+                let Term::Let(x, _, e2) = &self.expr(label).term else {
+                    unreachable!("let frame of a non-let")
+                };
+                let address = Address {
+                    variable: *x,
+                    context: time.clone(),
+                };
+                env.insert(*x, address.clone());
+                self.bind(address, value);
+                self.eval_then(e2, env, time, Frame::Result { label }, next.clone());
+            }
+
+            Frame::RightOperand { label, env } => {
+                let Term::BinaryOp(_, _, e2) = &self.expr(label).term else {
+                    unreachable!("operand frame of a non-operation")
+                };
+                self.eval_then(e2, env, time, Frame::Operator { label }, next.clone());
+            }
+
+            Frame::Operator { label } => self.visit(ret(label, Value::Number)),
+
+            Frame::Result { label } => self.visit(ret(label, value)),
+        }
+    }
+
+    /// steps from evaluating `expr` until no new states are reached
+    fn explore(&mut self, expr: &Expression) {
+        self.visit(State::Eval {
+            label: expr.label,
+            env: Env::new(),
+            // nothing is ever pushed here, so the machine halts when it returns to it
+            kont: KontAddress {
+                label: 0,
+                context: vec![],
+            },
+            time: vec![],
+        });
+
+        while let Some(state) = self.work_list.pop_front() {
+            self.step(state);
+        }
+    }
+
+    /// forgets the environments of closures and the contexts of addresses
+    fn projection(&self, expr: &Expression) -> MachineAnalysis {
+        let closures = |values: Option<&HashSet<Value>>| -> HashSet<Term> {
+            values
+                .into_iter()
+                .flatten()
+                .filter_map(|value| match value {
+                    Value::Closure(label, _) => Some(self.exprs[label].term.clone()),
+                    Value::Number => None,
+                })
+                .collect()
+        };
+
+        let cache = expr
+            .labels()
+            .into_iter()
+            .map(|label| (label, closures(self.values.get(&label))))
+            .collect();
+
+        let mut env: AbstractEnv = expr
+            .variables()
+            .into_iter()
+            .map(|x| (x, HashSet::new()))
+            .collect();
+        for (address, values) in &self.store {
+            env.entry(address.variable)
+                .or_default()
+                .extend(closures(Some(values)));
+        }
+
+        MachineAnalysis {
+            cache,
+            env,
+            states: self.seen.len(),
+        }
+    }
+}
+
+/// runs the abstract machine for k-CFA on `expr`, where `k = 0` gives 0-CFA
+pub fn run_machine(expr: &Expression, k: usize) -> MachineAnalysis {
+    let mut machine = Machine::new(expr, k);
+    machine.explore(expr);
+    machine.projection(expr)
+}
+
+/// the sets in which the machine found fewer or more closures than another analysis
+pub struct Comparison {
+    pub smaller: Vec<String>,
+    pub larger: Vec<String>,
+}
+
+/// compares the machine's sets with `cache` and `env`, e.g. from `analyse`
+pub fn compare(machine: &MachineAnalysis, cache: &AbstractCache, env: &AbstractEnv) -> Comparison {
+    let mut comparison = Comparison {
+        smaller: vec![],
+        larger: vec![],
+    };
+    let mut check = |name: String, ours: &HashSet<Term>, theirs: Option<&HashSet<Term>>| {
+        let empty = HashSet::new();
+        let theirs = theirs.unwrap_or(&empty);
+        if !ours.is_subset(theirs) {
+            comparison.larger.push(name);
+        } else if ours != theirs {
+            comparison.smaller.push(name);
+        }
+    };
+
+    let mut labels = Vec::from_iter(&machine.cache);
+    labels.sort_by_key(|(label, _)| **label);
+    for (label, terms) in labels {
+        check(format!("C({label})"), terms, cache.get(label));
+    }
+    let mut variables = Vec::from_iter(&machine.env);
+    variables.sort_by_key(|(x, _)| **x);
+    for (x, terms) in variables {
+        check(format!("r({x})"), terms, env.get(x));
+    }
+
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        generator::{random_source, Rng},
+        parser::{examples, parse},
+    };
+
+    /// a random program whose free variables are bound to numbers, as the machine gets
+    /// stuck on free variables where 0-CFA gives them the closures of variables with the
+    /// same name
+    fn closed_random_program(seed: u64) -> Expression {
+        let source = random_source(&mut Rng::new(seed), 60);
+        let mut free = Vec::from_iter(parse(&source).unwrap().free_variables());
+        free.sort();
+        let bindings: String = free.iter().map(|x| format!("let {x} = 0 in ")).collect();
+        parse(&format!("{bindings}({source}) ")).unwrap()
+    }
+
+    /// the machine's sets, and those of 0-CFA on the expressions the machine evaluated
+    fn machine_and_analysis(
+        program: &Expression,
+        k: usize,
+    ) -> (MachineAnalysis, AbstractCache, AbstractEnv) {
+        let mut machine = Machine::new(program, k);
+        machine.explore(program);
+        let evaluated: HashSet<Label> = machine
+            .seen
+            .iter()
+            .filter_map(|state| match state {
+                State::Eval { label, .. } => Some(*label),
+                State::Return { .. } => None,
+            })
+            .collect();
+        let excluded = program
+            .labels()
+            .into_iter()
+            .filter(|l| !evaluated.contains(l))
+            .collect();
+        let (cache, env) = analyse(program, &program.constraint_system_excluding(&excluded));
+        (machine.projection(program), cache, env)
+    }
+
+    fn is_subset(smaller: &MachineAnalysis, larger: &MachineAnalysis) -> bool {
+        smaller
+            .cache
+            .iter()
+            .all(|(l, set)| set.is_subset(&larger.cache[l]))
+            && smaller
+                .env
+                .iter()
+                .all(|(x, set)| set.is_subset(&larger.env[x]))
+    }
+
+    #[test]
+    fn k0_gives_0cfa() {
+        for program in examples() {
+            let (cache, env) = analyse(&program, &program.constraint_system());
+            let comparison = compare(&run_machine(&program, 0), &cache, &env);
+            assert!(comparison.smaller.is_empty() && comparison.larger.is_empty());
+        }
+
+        for program in (0..100).map(closed_random_program) {
+            let (machine, cache, env) = machine_and_analysis(&program, 0);
+            let comparison = compare(&machine, &cache, &env);
+            assert_eq!(comparison.smaller, Vec::<String>::new(), "{program}");
+            assert_eq!(comparison.larger, Vec::<String>::new(), "{program}");
+        }
+    }
+
+    #[test]
+    fn k1_is_at_least_as_precise_as_k0() {
+        let random = (0..50).map(closed_random_program);
+        for program in examples().into_iter().chain(random) {
+            let k1 = run_machine(&program, 1);
+            let k0 = run_machine(&program, 0);
+            assert!(is_subset(&k1, &k0), "{program}");
+        }
+    }
+
+    #[test]
+    fn k1_is_more_precise_on_example4() {
+        let program = &examples()[3];
+        let (cache, env) = analyse(program, &program.constraint_system());
+        let comparison = compare(&run_machine(program, 1), &cache, &env);
+        assert_eq!(comparison.smaller, ["C(5)", "C(6)", "C(8)", "C(9)", "r(y)"]);
+        assert!(comparison.larger.is_empty());
+    }
+}
@@ -1,23 +1,100 @@
 use std::{
-    env,
+    collections::{HashMap, HashSet},
+    env, fs,
     io::{self, IsTerminal},
+    process,
 };
 
 use expression::Expression;
 use rustyline::{config::Configurer, DefaultEditor};
 use term::Term;
 
-use crate::analysis::analyse;
+use crate::{
+    acceptability::{check_acceptable, parse_analysis, parse_node},
+    analysis::{
+        imprecision, solve, solve_traced, solve_without_differences, AbstractCache, AbstractEnv,
+    },
+    annotated::analyse_types,
+    callgraph::call_graph,
+    callsites::{classify_calls, CallKind},
+    constraint::ConSet,
+    conversion::closure_convert,
+    defunctionalize::{defunctionalize, run as run_defunctionalized, same_outcome},
+    dot::constraint_graph,
+    escape::escaping_closures,
+    incremental::{constraints_by_label, reanalyse, ConstraintsByLabel, Reanalysis},
+    inference::{infer_types, Type},
+    interpreter::{evaluate, observe_flows, unsound_flows},
+    machine::{compare, run_machine},
+    options::{CallGraphFormat, Options, TraceFormat},
+    parser::Span,
+    provenance::explain,
+    pruning::{analyse_pruned, Prune, PrunedAnalysis},
+    pushdown::{analyse_pushdown, spurious_returns},
+    reference::solve_naive,
+    trace::{trace_json, trace_text},
+    types::{Label, Variable},
+    unification::analyse_unification,
+};
 
+mod acceptability;
 mod analysis;
+mod annotated;
+mod baseline;
+mod bench;
+mod bitset;
+mod callgraph;
+mod callsites;
 mod constraint;
+mod conversion;
+mod defunctionalize;
+mod domain;
+mod dot;
+mod escape;
 mod expression;
+mod generator;
+mod incremental;
+mod inference;
+mod interpreter;
+mod machine;
+mod options;
 mod parser;
+mod provenance;
+mod pruning;
+mod pushdown;
+mod reference;
 mod term;
+mod trace;
 mod types;
+mod unification;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let options = Options::parse(&args).unwrap_or_else(|err| {
+        eprintln!("{err}\n\nUsage: {} [options]\n{}", args[0], options::USAGE);
+        process::exit(2);
+    });
+
+    if options.bench && options.incremental {
+        bench::check_incremental();
+        return;
+    }
+    if options.bench && options.soundness {
+        bench::check_soundness();
+        return;
+    }
+    if options.bench && options.defunctionalize {
+        bench::check_defunctionalization();
+        return;
+    }
+    if options.bench && options.reference {
+        bench::cross_check();
+        return;
+    }
+    if options.bench {
+        bench::run(options.bench_baseline);
+        return;
+    }
 
     let is_terminal = io::stdin().is_terminal();
     let mut rl = DefaultEditor::new().unwrap();
@@ -30,12 +107,15 @@ fn main() {
         println!("To exit, press Ctrl+C or submit a blank program.")
     }
 
+    // with `--incremental`: the last program, its constraints and its solution
+    let mut previous: Option<(Expression, ConstraintsByLabel, AbstractCache, AbstractEnv)> = None;
+
     loop {
         let mut rl_prompt = ">>> ";
 
         let mut input = String::new();
         while let Ok(line) = rl.readline(rl_prompt) {
-            if line.len() == 0 && is_terminal {
+            if line.is_empty() && is_terminal {
                 break;
             }
 
@@ -45,11 +125,11 @@ fn main() {
         }
 
         input = input.trim_end().to_string();
-        if input.len() == 0 {
+        if input.is_empty() {
             return;
         }
         input.push(' ');
-        let program = parser::parse(&input);
+        let program = parser::parse_with_spans(&input);
 
         // parse error -> print location of the error
         if let Err(err) = program {
@@ -68,9 +148,36 @@ fn main() {
         }
 
         // parsed successfully -> proceed with analysis
-        let program = program.unwrap();
+        let (mut program, mut spans) = program.unwrap();
+
+        // re-analyse incrementally, keeping the labels of unchanged subexpressions
+        let mut reanalysis = None;
+        if let Some((old, old_constraints, old_cache, old_env)) = &previous {
+            let result = reanalyse(old, old_constraints, old_cache, old_env, program);
+            // the spans are keyed by the parsed labels
+            spans = spans
+                .into_iter()
+                .filter_map(|(label, span)| Some((*result.labels.get(&label)?, span)))
+                .collect();
+            program = result.program.clone();
+            reanalysis = Some(result);
+        }
+
         println!("\nProgram:\n{program:#}");
 
+        if options.eval {
+            match evaluate(&program) {
+                Ok(value) => println!("\nResult: {value}"),
+                Err(error) => match error.label() {
+                    Some(label) => println!(
+                        "\nRun-time error at {}: {error}",
+                        location(label, &spans, &input)
+                    ),
+                    None => println!("\nRun-time error: {error}"),
+                },
+            }
+        }
+
         let labels = {
             let labels_unsorted = program.labels();
             let mut vec = Vec::from_iter(labels_unsorted);
@@ -84,39 +191,614 @@ fn main() {
             vec
         };
 
-        let constraints = program.constraints();
+        let prune = Prune {
+            branches: options.prune_branches,
+            closure_bodies: options.reachability,
+        };
+        let mut reuse = None;
+        let mut reused_constraints = None;
+        let analysis = match reanalysis {
+            Some(Reanalysis {
+                constraints,
+                system,
+                cache,
+                env,
+                reused_nodes,
+                recomputed_nodes,
+                regenerated,
+                iterations,
+                ..
+            }) => {
+                reuse = Some((reused_nodes, recomputed_nodes, regenerated, iterations));
+                reused_constraints = Some(constraints);
+                PrunedAnalysis {
+                    system,
+                    cache,
+                    env,
+                    dead_branches: vec![],
+                    uncalled_closures: vec![],
+                    unreachable: HashSet::new(),
+                }
+            }
+            None => analyse_pruned(&program, prune, options.threads.unwrap_or(1)),
+        };
+
         println!("\nConstraints:");
+        let constraints = if prune.branches || prune.closure_bodies {
+            analysis.system.expand(&program.subterms())
+        } else {
+            program.constraints()
+        };
         for constraint in &constraints {
             println!("  {constraint}");
         }
 
+        if let Some(format) = options.trace {
+            let (_, events) = solve_traced(&program, &analysis.system);
+            println!("\nSolver trace:");
+            let lines = match format {
+                TraceFormat::Text => trace_text(&events),
+                TraceFormat::Json => trace_json(&events),
+            };
+            for line in lines {
+                println!("{line}");
+            }
+        }
+
+        let mut inferred = None;
+        if options.infer {
+            match infer_types(&program) {
+                Ok(types) => inferred = Some(types),
+                Err(error) => println!(
+                    "\nType error at {}: {error}",
+                    location(error.label(), &spans, &input)
+                ),
+            }
+        }
+
         println!("\nAnalysis:");
-        let (analysis_cache, analysis_env) = analyse(&program, &constraints);
-        for label in labels {
-            let terms = analysis_cache[&label]
+        print_tables(
+            &analysis.cache,
+            &analysis.env,
+            &labels,
+            &variables,
+            inferred.as_ref(),
+        );
+
+        if prune.branches || prune.closure_bodies {
+            if prune.branches {
+                println!("Dead branches:");
+                if analysis.dead_branches.is_empty() {
+                    println!("  none");
+                }
+                for dead in &analysis.dead_branches {
+                    println!(
+                        "  {} branch {} of if {} is never taken, condition is {}",
+                        if dead.is_then { "then" } else { "else" },
+                        dead.branch_label,
+                        dead.if_label,
+                        dead.condition
+                    );
+                }
+                println!();
+            }
+
+            if prune.closure_bodies {
+                println!("Closures never applied:");
+                if analysis.uncalled_closures.is_empty() {
+                    println!("  none");
+                }
+                for uncalled in &analysis.uncalled_closures {
+                    println!(
+                        "  closure {}, body {} is never reached",
+                        uncalled.label, uncalled.body_label
+                    );
+                }
+                println!();
+            }
+
+            let mut unreachable = Vec::from_iter(&analysis.unreachable);
+            unreachable.sort();
+            println!(
+                "Unreachable labels: {}",
+                if unreachable.is_empty() {
+                    "none".to_string()
+                } else {
+                    unreachable
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                }
+            );
+            println!();
+        }
+
+        if options.reference {
+            let (cache, env) = solve_naive(&program, &constraints);
+            let differences: Vec<String> =
+                imprecision((&cache, &env), (&analysis.cache, &analysis.env))
+                    .into_iter()
+                    .chain(imprecision(
+                        (&analysis.cache, &analysis.env),
+                        (&cache, &env),
+                    ))
+                    .map(|imprecision| imprecision.node)
+                    .collect();
+            if !differences.is_empty() {
+                eprintln!(
+                    "The reference solver finds different sets in: {}",
+                    differences.join(", ")
+                );
+                process::exit(1);
+            }
+            println!("Same sets as the reference solver");
+            println!();
+        }
+
+        if options.dot {
+            println!("Constraint graph (DOT):");
+            let solution = options.dot_sets.then_some((&analysis.cache, &analysis.env));
+            for line in constraint_graph(&constraints, &labels, &variables, solution) {
+                println!("{line}");
+            }
+            println!();
+        }
+
+        if let Some(format) = options.call_graph {
+            let graph = call_graph(&program, &analysis.cache);
+            match format {
+                CallGraphFormat::Text => {
+                    println!("Call graph:");
+                    if graph.calls.is_empty() {
+                        println!("  no calls");
+                    }
+                    for line in graph.text() {
+                        println!("{line}");
+                    }
+                }
+                CallGraphFormat::Dot => {
+                    println!("Call graph (DOT):");
+                    for line in graph.dot() {
+                        println!("{line}");
+                    }
+                }
+                CallGraphFormat::Json => println!("{}", graph.json()),
+            }
+            println!();
+        }
+
+        if options.call_sites {
+            println!("Call sites:");
+            let calls = classify_calls(&program, &analysis.cache, &analysis.unreachable);
+            if calls.is_empty() {
+                println!("  no calls");
+            }
+            for call in &calls {
+                let closures = call
+                    .closures
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                match call.kind {
+                    CallKind::Dead => println!("  call {}: dead", call.label),
+                    _ => println!("  call {}: {}, calls {closures}", call.label, call.kind),
+                }
+                if let Some(values) = &call.non_closures {
+                    println!("    non-closure may be called: {values}");
+                }
+            }
+            let count = |kind| calls.iter().filter(|call| call.kind == kind).count();
+            println!(
+                "  {} monomorphic, {} polymorphic, {} dead, {} may call a non-closure",
+                count(CallKind::Monomorphic),
+                count(CallKind::Polymorphic),
+                count(CallKind::Dead),
+                calls
+                    .iter()
+                    .filter(|call| call.non_closures.is_some())
+                    .count()
+            );
+            println!();
+        }
+
+        if options.escape {
+            let closures = escaping_closures(&program, &analysis.cache, &analysis.env);
+            println!("Escaping closures:");
+            let escaping: Vec<_> = closures.iter().filter(|c| c.escape.is_some()).collect();
+            if escaping.is_empty() {
+                println!("  none");
+            }
+            for closure in escaping {
+                let scope = match closure.scope {
+                    Some(scope) => format!("closure {scope}"),
+                    None => "the program".to_string(),
+                };
+                println!(
+                    "  closure {} escapes {scope}: {}",
+                    closure.label,
+                    closure.escape.as_ref().unwrap()
+                );
+            }
+            let local: Vec<String> = closures
                 .iter()
-                .map(ToString::to_string)
-                .collect::<Vec<_>>();
+                .filter(|c| c.escape.is_none())
+                .map(|c| c.label.to_string())
+                .collect();
+            println!(
+                "Closures that never escape: {}",
+                if local.is_empty() {
+                    "none".to_string()
+                } else {
+                    local.join(", ")
+                }
+            );
+            println!();
+        }
 
+        if options.defunctionalize {
+            let first_order = defunctionalize(&program, &analysis.cache);
+            println!("Defunctionalized program:");
+            for line in first_order.lines() {
+                println!("{line}");
+            }
+            let original = evaluate(&program);
+            let result = run_defunctionalized(&first_order);
+            let outcome = |result: Result<String, String>| match result {
+                Ok(value) => format!("result {value}"),
+                Err(error) => format!("run-time error: {error}"),
+            };
+            let original_outcome = outcome(
+                original
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .map_err(ToString::to_string),
+            );
+            let outcome = outcome(
+                result
+                    .as_ref()
+                    .map(ToString::to_string)
+                    .map_err(ToString::to_string),
+            );
+            match same_outcome(&original, &result) {
+                Some(true) => println!("Runs like the original program, {outcome}"),
+                Some(false) => {
+                    eprintln!(
+                        "The defunctionalized program gives {outcome}, the original {original_outcome}"
+                    );
+                    process::exit(1);
+                }
+                None => println!("Cannot compare with the original program, {outcome}"),
+            }
+            println!();
+        }
+
+        if options.closure_convert {
+            let converted = closure_convert(&program, &analysis.cache, &analysis.env);
+            println!("Closure-converted program:");
+            for line in converted.lines() {
+                println!("{line}");
+            }
+            let well_known = converted.codes.iter().filter(|code| code.well_known);
             println!(
-                "  {rowlabel:<7} {}",
-                terms.join(", "),
-                rowlabel = format!("C({label}):")
+                "  {} closures, {} well-known ({} without environment), {} direct and {} indirect calls",
+                converted.codes.len(),
+                well_known.clone().count(),
+                well_known.filter(|code| code.without_environment()).count(),
+                converted.direct_calls,
+                converted.indirect_calls
             );
+            println!();
+        }
+
+        if let Some(set) = &options.explain {
+            let node = parse_node(set).or_else(|| set.parse().ok().map(ConSet::Cache));
+            match node {
+                Some(ConSet::Cache(l)) if !labels.contains(&l) => {
+                    println!("Cannot explain {set}: no label {l} in the program")
+                }
+                Some(ConSet::Env(x)) if !variables.contains(&x) => {
+                    println!("Cannot explain {set}: no variable {x} in the program")
+                }
+                Some(node) => {
+                    println!("Why the closures are in {node}:");
+                    let lines = explain(&program, &analysis.system, &node);
+                    if lines.is_empty() {
+                        println!("  {node} is empty");
+                    }
+                    for line in lines {
+                        println!("{line}");
+                    }
+                }
+                None => println!("Cannot explain {set}: expected C(l) or r(x)"),
+            }
+            println!();
+        }
+
+        if options.soundness {
+            let (observations, result) = observe_flows(&program);
+            let unsound = unsound_flows(&program, &observations, &analysis.cache, &analysis.env);
+            let observed: usize = observations.cache.values().map(HashSet::len).sum::<usize>()
+                + observations.env.values().map(HashSet::len).sum::<usize>();
+            if unsound.is_empty() {
+                println!("Soundness: all {observed} closure flows seen when running the program are in the analysis");
+            } else {
+                println!("Soundness bug, closure flows seen when running the program but missing from the analysis:");
+                for flow in &unsound {
+                    println!("  {} in {}", flow.closure, flow.node);
+                }
+            }
+            if let Err(error) = result {
+                println!("  (the run stopped early: {error})");
+            }
+            println!();
+        }
+
+        if options.check {
+            let checked = match &options.check_file {
+                Some(path) => fs::read_to_string(path)
+                    .map_err(|err| format!("cannot read {path}: {err}"))
+                    .and_then(|text| parse_analysis(&program, &text)),
+                None => Ok((analysis.cache.clone(), analysis.env.clone())),
+            };
+            match checked {
+                Ok((cache, env)) => {
+                    if options.check_file.is_some() {
+                        println!("Analysis to check:");
+                        print_tables(&cache, &env, &labels, &variables, None);
+                    }
+
+                    let violations = check_acceptable(&program, &cache, &env);
+                    if violations.is_empty() {
+                        println!("Acceptable: (C, r) ⊨ e holds");
+                    } else {
+                        println!("Not acceptable, violated clauses:");
+                        for violation in &violations {
+                            println!("  {violation}");
+                        }
+                    }
+                }
+                Err(err) => println!("Cannot check the analysis: {err}"),
+            }
+            println!();
+        }
+
+        if let Some(k) = options.machine {
+            let machine = run_machine(&program, k);
+            println!("Abstract machine ({k}-CFA, {} states):", machine.states);
+            print_tables(&machine.cache, &machine.env, &labels, &variables, None);
+
+            let comparison = compare(&machine, &analysis.cache, &analysis.env);
+            if comparison.smaller.is_empty() && comparison.larger.is_empty() {
+                println!("Same sets as the constraint-based analysis");
+            }
+            if !comparison.smaller.is_empty() {
+                println!(
+                    "Fewer closures than the constraint-based analysis in: {}",
+                    comparison.smaller.join(", ")
+                );
+            }
+            if !comparison.larger.is_empty() {
+                println!(
+                    "More closures than the constraint-based analysis in: {}",
+                    comparison.larger.join(", ")
+                );
+            }
+            println!();
         }
-        println!();
-        for variable in variables {
-            let terms = analysis_env[&variable]
+
+        if options.pushdown {
+            let pushdown = analyse_pushdown(&program);
+            println!("Pushdown analysis:");
+            print_tables(&pushdown.cache, &pushdown.env, &labels, &variables, None);
+
+            println!("Returns merged by 0-CFA from calls made elsewhere:");
+            let spurious = spurious_returns(&program, &pushdown, &analysis.cache);
+            let terms = |terms: &[Term]| {
+                let terms = terms.iter().map(ToString::to_string).collect::<Vec<_>>();
+                terms.join(", ")
+            };
+            if spurious.iter().all(|returns| returns.terms.is_empty()) {
+                println!("  none");
+            }
+            for returns in spurious.iter().filter(|returns| !returns.terms.is_empty()) {
+                println!("  call {}: {}", returns.call_site, terms(&returns.terms));
+            }
+            if spurious.iter().any(|returns| !returns.extra.is_empty()) {
+                println!("Extra closures of 0-CFA at calls, not returned by a call elsewhere:");
+            }
+            for returns in spurious.iter().filter(|returns| !returns.extra.is_empty()) {
+                println!("  call {}: {}", returns.call_site, terms(&returns.extra));
+            }
+            if !spurious.is_empty() {
+                println!("  (bodies are analysed once per argument value, so these mix returns");
+                println!("  matched with their calls and arguments no longer merged)");
+            }
+            if !pushdown.unreached_calls.is_empty() {
+                let calls = pushdown
+                    .unreached_calls
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                println!("  (calls never reached: {})", calls.join(", "));
+            }
+            println!();
+        }
+
+        if options.unification {
+            let (cache, env) = analyse_unification(&program);
+            println!("Unification-based analysis:");
+            print_tables(&cache, &env, &labels, &variables, None);
+
+            println!("Less precise than the inclusion-based analysis:");
+            let imprecise = imprecision((&cache, &env), (&analysis.cache, &analysis.env));
+            if imprecise.is_empty() {
+                println!("  nowhere");
+            }
+            for imprecision in &imprecise {
+                let extra = imprecision
+                    .extra
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+                println!(
+                    "  {rowlabel:<7} also {}",
+                    extra.join(", "),
+                    rowlabel = format!("{}:", imprecision.node)
+                );
+            }
+            println!();
+        }
+
+        if options.types {
+            match analyse_types(&program) {
+                Ok(typing) => {
+                    println!("Annotated types:");
+                    let rows: Vec<(String, String, String)> = labels
+                        .iter()
+                        .map(|label| {
+                            let terms = typing.cache[label]
+                                .iter()
+                                .map(ToString::to_string)
+                                .collect::<Vec<_>>();
+                            (
+                                format!("C({label}):"),
+                                terms.join(", "),
+                                typing.types[label].to_string(),
+                            )
+                        })
+                        .chain(variables.iter().map(|variable| {
+                            let terms = typing.env[variable]
+                                .iter()
+                                .map(ToString::to_string)
+                                .collect::<Vec<_>>();
+                            (
+                                format!("r({variable}):"),
+                                terms.join(", "),
+                                typing.variable_types[variable].to_string(),
+                            )
+                        }))
+                        .collect();
+                    let width = rows.iter().map(|(_, terms, _)| terms.chars().count()).max();
+                    for (rowlabel, terms, annotated_type) in &rows {
+                        println!(
+                            "  {rowlabel:<7} {terms:<width$}  : {annotated_type}",
+                            width = width.unwrap_or(0)
+                        );
+                    }
+                    println!();
+
+                    let differences: Vec<String> = imprecision(
+                        (&typing.cache, &typing.env),
+                        (&analysis.cache, &analysis.env),
+                    )
+                    .into_iter()
+                    .chain(imprecision(
+                        (&analysis.cache, &analysis.env),
+                        (&typing.cache, &typing.env),
+                    ))
+                    .map(|imprecision| imprecision.node)
+                    .collect();
+                    if differences.is_empty() {
+                        println!("Same sets as the constraint-based analysis");
+                    } else {
+                        println!(
+                            "Different sets than the constraint-based analysis: {}",
+                            differences.join(", ")
+                        );
+                    }
+                }
+                Err(error) => println!("Annotated types: not typable, {error}"),
+            }
+            println!();
+        }
+
+        if options.stats {
+            let statistics = solve(&program, &analysis.system).statistics;
+            let without_differences =
+                solve_without_differences(&program, &analysis.system).statistics;
+            println!("Statistics:");
+            println!("  worklist iterations: {}", statistics.iterations);
+            println!("  collapsed nodes:     {}", statistics.collapsed_nodes);
+            println!(
+                "  term transfers:      {} (instead of {} when propagating whole sets)",
+                statistics.transfers, without_differences.transfers
+            );
+            println!();
+        }
+
+        if let Some((reused_nodes, recomputed_nodes, regenerated, iterations)) = reuse {
+            println!(
+                "Re-analysed incrementally: kept the sets of {reused_nodes} nodes, \
+                 solved {recomputed_nodes} nodes again in {iterations} worklist iterations, \
+                 generated the constraints of {regenerated} subexpressions again"
+            );
+            println!();
+        }
+
+        if options.incremental {
+            let constraints = reused_constraints.unwrap_or_else(|| constraints_by_label(&program));
+            previous = Some((program, constraints, analysis.cache, analysis.env));
+        }
+    }
+}
+
+/// `label l (line L, column C)`, or only the label if its position is not known
+fn location(label: Label, spans: &HashMap<Label, Span>, input: &str) -> String {
+    match spans.get(&label) {
+        Some(span) => {
+            let (line, column) = span.line_col(input);
+            format!("label {label} (line {line}, column {column})")
+        }
+        None => format!("label {label}"),
+    }
+}
+
+/// prints the sets `C(l)` and `r(x)` of `labels` and `variables`, and the type of each
+/// label if there are `types`
+fn print_tables(
+    cache: &AbstractCache,
+    env: &AbstractEnv,
+    labels: &[Label],
+    variables: &[Variable],
+    types: Option<&HashMap<Label, Type>>,
+) {
+    let rows: Vec<(Label, String)> = labels
+        .iter()
+        .map(|label| {
+            let terms = cache[label]
                 .iter()
                 .map(ToString::to_string)
                 .collect::<Vec<_>>();
+            (*label, terms.join(", "))
+        })
+        .collect();
+    let width = rows.iter().map(|(_, terms)| terms.chars().count()).max();
 
-            println!(
-                "  {rowlabel:<7} {}",
-                terms.join(", "),
-                rowlabel = format!("r({variable}):")
-            );
+    for (label, terms) in &rows {
+        let rowlabel = format!("C({label}):");
+        match types {
+            Some(types) => println!(
+                "  {rowlabel:<7} {terms:<width$}  : {}",
+                types[label],
+                width = width.unwrap_or(0)
+            ),
+            None => println!("  {rowlabel:<7} {terms}"),
         }
-        println!();
     }
+    println!();
+    for variable in variables {
+        let terms = env[variable]
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        println!(
+            "  {rowlabel:<7} {}",
+            terms.join(", "),
+            rowlabel = format!("r({variable}):")
+        );
+    }
+    println!();
 }
@@ -0,0 +1,179 @@
+use crate::analysis::available_threads;
+
+/// how `--trace` prints the steps of the solver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+/// how `--call-graph` prints the call graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallGraphFormat {
+    Text,
+    Dot,
+    Json,
+}
+
+/// Command line options
+#[derive(Debug, Default)]
+pub struct Options {
+    /// skip the constraints of `if` branches whose condition rules them out
+    pub prune_branches: bool,
+    /// only consider the bodies of closures that may be applied somewhere
+    pub reachability: bool,
+    /// re-analyse each program incrementally, reusing the solution of the previous one
+    pub incremental: bool,
+    /// also run the abstract machine for k-CFA with this `k`, and compare with the analysis
+    pub machine: Option<usize>,
+    /// also run the pushdown analysis, and report returns that 0-CFA merges spuriously
+    pub pushdown: bool,
+    /// also run the equality-based analysis, and report where it is less precise
+    pub unification: bool,
+    /// also infer annotated types, and compare the closures they give with the analysis
+    pub types: bool,
+    /// infer `let`-polymorphic types and print them alongside the analysis
+    pub infer: bool,
+    /// check that the analysis (or the one in `check_file`) is acceptable
+    pub check: bool,
+    /// file with an analysis to check instead of the computed one
+    pub check_file: Option<String>,
+    /// print every step of the worklist solver
+    pub trace: Option<TraceFormat>,
+    /// print the constraint graph in DOT
+    pub dot: bool,
+    /// with `dot`, also show the set of each node
+    pub dot_sets: bool,
+    /// print which closures each application may call
+    pub call_graph: Option<CallGraphFormat>,
+    /// classify the applications by how many closures they may call
+    pub call_sites: bool,
+    /// report which closures may escape the scope that defines them
+    pub escape: bool,
+    /// defunctionalize the program with the analysis and check it behaves the same
+    pub defunctionalize: bool,
+    /// print the closure-converted program, with direct calls where the analysis allows
+    pub closure_convert: bool,
+    /// set `C(l)` or `r(x)` whose closures to explain with derivations from the constraints
+    pub explain: Option<String>,
+    /// evaluate the program and print its result
+    pub eval: bool,
+    /// run the program and check that the closures it produces are in the analysis
+    pub soundness: bool,
+    /// also solve with the naive reference solver, and fail if it finds another solution
+    pub reference: bool,
+    /// print statistics about the solver
+    pub stats: bool,
+    /// solve on this many threads instead of one
+    pub threads: Option<usize>,
+    /// time the analysis on generated programs instead of reading a program
+    pub bench: bool,
+    /// with `bench`, also time the previous solver on the programs with thousands of labels
+    pub bench_baseline: bool,
+}
+
+pub const USAGE: &str = "\
+Options:
+  --prune-branches    ignore `if` branches that can never be taken and report them
+  --reachability      ignore bodies of closures that are never applied and report them
+  --incremental       re-analyse each program incrementally, based on the previous one
+                      (with --bench: check it on edits of generated programs)
+  --machine[=K]       also run an abstract machine for K-CFA (default 0) and compare
+  --pushdown          also run a pushdown analysis and report spuriously merged returns
+  --unification       also run an equality-based analysis and report where it is less precise
+  --types             also infer annotated types carrying closure labels and compare
+  --infer             infer let-polymorphic types and report type errors with positions
+  --check[=FILE]      check that the analysis (or the one in FILE) is acceptable
+  --trace[=json]      print every step of the worklist solver, as text or JSON lines
+  --dot[=sets]        print the constraint graph in DOT (with the sets of the solution)
+  --call-graph[=F]    print the closures each call site may call, F is text, dot or json
+  --call-sites        classify call sites as monomorphic, polymorphic or dead, and flag
+                      those where a non-closure may be called
+  --escape            report which closures may outlive the scope that defines them
+  --defunctionalize   print the first-order program given by the analysis and check that
+                      it runs like the original (with --bench: on generated programs)
+  --closure-convert   print the program closure-converted into code and environment
+                      records, with direct calls and no records where possible
+  --explain=SET       explain why each closure is in SET, e.g. 'C(5)', 'r(x)' or just 5
+  --eval              evaluate the program and print its result or run-time error
+  --soundness         run the program and check that every closure flow seen is in the
+                      analysis (with --bench: on generated programs)
+  --reference         also solve naively and fail if the solutions differ (with --bench:
+                      compare on generated programs instead of timing)
+  --stats             print statistics about the solver (iterations, collapsed cycles)
+  --threads[=N]       solve on N threads (default: all cores), which is not faster yet
+  --bench[=baseline]  time the analysis on generated programs with thousands of labels
+                      (and the previous solver on all of them, which takes long)";
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+
+        for arg in args.iter().skip(1) {
+            match arg.as_str() {
+                "--prune-branches" => options.prune_branches = true,
+                "--reachability" => options.reachability = true,
+                "--incremental" => options.incremental = true,
+                "--stats" => options.stats = true,
+                "--bench" => options.bench = true,
+                "--bench=baseline" => (options.bench, options.bench_baseline) = (true, true),
+
+                "--threads" => options.threads = Some(available_threads()),
+                _ if arg.starts_with("--threads=") => {
+                    let n = &arg["--threads=".len()..];
+                    let n =
+                        n.parse().ok().filter(|&n| n > 0).ok_or_else(|| {
+                            format!("Invalid number of threads for --threads: {n}")
+                        })?;
+                    options.threads = Some(n);
+                }
+
+                "--machine" => options.machine = Some(0),
+                "--pushdown" => options.pushdown = true,
+                "--unification" => options.unification = true,
+                "--types" => options.types = true,
+                "--infer" => options.infer = true,
+                "--check" => options.check = true,
+                "--reference" => options.reference = true,
+                "--eval" => options.eval = true,
+                "--soundness" => options.soundness = true,
+                "--call-sites" => options.call_sites = true,
+                "--escape" => options.escape = true,
+                "--defunctionalize" => options.defunctionalize = true,
+                "--closure-convert" => options.closure_convert = true,
+                _ if arg.starts_with("--machine=") => {
+                    let k = &arg["--machine=".len()..];
+                    let k = k
+                        .parse()
+                        .map_err(|_| format!("Invalid k for --machine: {k}"))?;
+                    options.machine = Some(k);
+                }
+                "--trace" | "--trace=text" => options.trace = Some(TraceFormat::Text),
+                "--trace=json" => options.trace = Some(TraceFormat::Json),
+                "--dot" => options.dot = true,
+                "--dot=sets" => (options.dot, options.dot_sets) = (true, true),
+                "--call-graph" | "--call-graph=text" => {
+                    options.call_graph = Some(CallGraphFormat::Text)
+                }
+                "--call-graph=dot" => options.call_graph = Some(CallGraphFormat::Dot),
+                "--call-graph=json" => options.call_graph = Some(CallGraphFormat::Json),
+                _ if arg.starts_with("--explain=") => {
+                    options.explain = Some(arg["--explain=".len()..].to_string());
+                }
+                _ if arg.starts_with("--check=") => {
+                    options.check = true;
+                    options.check_file = Some(arg["--check=".len()..].to_string());
+                }
+
+                _ => return Err(format!("Unknown option: {arg}")),
+            }
+        }
+
+        if options.incremental && (options.prune_branches || options.reachability) {
+            return Err("--incremental cannot be combined with pruning".to_string());
+        }
+
+        Ok(options)
+    }
+}
@@ -1,3 +1,5 @@
+use std::{cell::RefCell, collections::HashMap};
+
 use peg::{self, error::ParseError, str::LineCol};
 
 use crate::{
@@ -5,11 +7,35 @@ use crate::{
     Expression, Term,
 };
 
-fn expr(term: Term) -> Box<Expression> {
-    Box::new(Expression { label: 0, term })
+/// byte offsets of the source text of a subexpression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-peg::parser!(grammar func() for str {
+impl Span {
+    /// line and column (both starting at 1) of the start of the span in `input`
+    pub fn line_col(&self, input: &str) -> (usize, usize) {
+        let before = &input[..self.start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        (line, column)
+    }
+}
+
+/// records the span in `spans`, and until relabelling, the label of the parsed
+/// expression is its index there
+fn spanned(spans: &RefCell<Vec<Span>>, term: Term, start: usize, end: usize) -> Box<Expression> {
+    let mut spans = spans.borrow_mut();
+    spans.push(Span { start, end });
+    Box::new(Expression {
+        label: spans.len() - 1,
+        term,
+    })
+}
+
+peg::parser!(grammar func(spans: &RefCell<Vec<Span>>) for str {
     rule __ = quiet!{ [' ' | '\n']+ }
     rule _  = quiet!{ [' ' | '\n']* }
     rule ws_or_eof() = &(_ / ![_])
@@ -28,130 +54,140 @@ peg::parser!(grammar func() for str {
 
     rule closure() -> Term
         = "fn" __ x:variable() _ ("->" / "=>") _ t:term() {
-            Term::Closure(x, expr(t))
+            Term::Closure(x, t)
         }
 
     rule recursive_closure() -> Term
         = "fun" __ f:variable() __ x:variable() _ ("->" / "=>") _ t:term() {
-            Term::RecursiveClosure(f, x, expr(t))
+            Term::RecursiveClosure(f, x, t)
         }
 
     rule if_then_else() -> Term
         = "if" __ t0:term() __ "then" __ t1:term() __ "else" __ t2:term() {
-            Term::IfThenElse(expr(t0), expr(t1), expr(t2))
+            Term::IfThenElse(t0, t1, t2)
         }
 
     rule let() -> Term
         = "let" __ x:variable() _ "=" _ t1:term() __ "in" __ t2:term() {
-            Term::Let(x, expr(t1), expr(t2))
+            Term::Let(x, t1, t2)
         }
 
-    rule term() -> Term
+    rule term() -> Box<Expression>
         = _ t:precedence!{
+                start:position!() t:@ end:position!() { spanned(spans, t, start, end) }
+                --
                 l:let() { l }
                 --
                 i:if_then_else() { i }
                 --
-                t1:@ __ t2:(@) { Term::Application(expr(t1), expr(t2)) }
+                t1:@ __ t2:(@) { Term::Application(t1, t2) }
                 --
                 c:closure() { c }
                 r:recursive_closure() { r }
                 --
-                x:(@) _ op:$("||")  _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
+                x:(@) _ op:$("||")  _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
                 --
-                x:(@) _ op:$("&&")  _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
+                x:(@) _ op:$("&&")  _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
                 --
-                x:(@) _ op:$("<=" / "==" / "!=" / ">=")  _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
-                x:(@) _ op:$("<" / ">")  _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
+                x:(@) _ op:$("<=" / "==" / "!=" / ">=")  _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
+                x:(@) _ op:$("<" / ">")  _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
                 --
-                x:(@) _ op:$("+" / "-") _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
+                x:(@) _ op:$("+" / "-") _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
                 --
-                x:(@) _ op:$("*" / "/") _ y:@ { Term::BinaryOp(expr(x), op.to_string(), expr(y)) }
+                x:(@) _ op:$("*" / "/") _ y:@ { Term::BinaryOp(x, op.to_string(), y) }
                 --
                 n:constant() { Term::Constant(n) }
                 v:variable() { Term::Variable(v) }
                 --
-                "(" _ t:term() _ ")" { t }
+                "(" _ t:term() _ ")" { t.term }
             }
         { t }
 
-        pub rule program() -> Term = t:term() _ { t }
+        pub rule program() -> Box<Expression> = t:term() _ { t }
 });
 
-fn relabel(expr: Expression, start: Label) -> (Expression, Label) {
+/// labels the subexpressions in post-order from `start`, and records the span of each
+/// new label, found in `parsed` by the label given while parsing
+fn relabel(
+    expr: Expression,
+    start: Label,
+    parsed: &[Span],
+    spans: &mut HashMap<Label, Span>,
+) -> (Expression, Label) {
+    let old_span = parsed[expr.label];
     match expr.term {
         Term::Closure(x, e0) => {
-            let (new_e0, next) = relabel(*e0, start);
+            let (new_e0, next) = relabel(*e0, start, parsed, spans);
 
             (
                 Expression {
                     term: Term::Closure(x, Box::new(new_e0)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
         }
 
         Term::RecursiveClosure(f, x, e0) => {
-            let (new_e0, next) = relabel(*e0, start);
+            let (new_e0, next) = relabel(*e0, start, parsed, spans);
 
             (
                 Expression {
                     term: Term::RecursiveClosure(f, x, Box::new(new_e0)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
         }
 
         Term::Application(e1, e2) => {
-            let (new_e1, e2_start) = relabel(*e1, start);
-            let (new_e2, next) = relabel(*e2, e2_start);
+            let (new_e1, e2_start) = relabel(*e1, start, parsed, spans);
+            let (new_e2, next) = relabel(*e2, e2_start, parsed, spans);
 
             (
                 Expression {
                     term: Term::Application(Box::new(new_e1), Box::new(new_e2)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
         }
 
         Term::IfThenElse(e0, e1, e2) => {
-            let (new_e0, e1_start) = relabel(*e0, start);
-            let (new_e1, e2_start) = relabel(*e1, e1_start);
-            let (new_e2, next) = relabel(*e2, e2_start);
+            let (new_e0, e1_start) = relabel(*e0, start, parsed, spans);
+            let (new_e1, e2_start) = relabel(*e1, e1_start, parsed, spans);
+            let (new_e2, next) = relabel(*e2, e2_start, parsed, spans);
 
             (
                 Expression {
                     term: Term::IfThenElse(Box::new(new_e0), Box::new(new_e1), Box::new(new_e2)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
         }
 
         Term::Let(x, e1, e2) => {
-            let (new_e1, e2_start) = relabel(*e1, start);
-            let (new_e2, next) = relabel(*e2, e2_start);
+            let (new_e1, e2_start) = relabel(*e1, start, parsed, spans);
+            let (new_e2, next) = relabel(*e2, e2_start, parsed, spans);
 
             (
                 Expression {
                     term: Term::Let(x, Box::new(new_e1), Box::new(new_e2)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
         }
 
         Term::BinaryOp(e1, op, e2) => {
-            let (new_e1, e2_start) = relabel(*e1, start);
-            let (new_e2, next) = relabel(*e2, e2_start);
+            let (new_e1, e2_start) = relabel(*e1, start, parsed, spans);
+            let (new_e2, next) = relabel(*e2, e2_start, parsed, spans);
 
             (
                 Expression {
                     term: Term::BinaryOp(Box::new(new_e1), op, Box::new(new_e2)),
-                    label: next,
+                    label: spans_insert(spans, next, old_span),
                 },
                 next + 1,
             )
@@ -160,16 +196,66 @@ fn relabel(expr: Expression, start: Label) -> (Expression, Label) {
         _ => (
             Expression {
                 term: expr.term,
-                label: start,
+                label: spans_insert(spans, start, old_span),
             },
             start + 1,
         ),
     }
 }
 
+/// records the span of the expression that has been given `label`
+fn spans_insert(spans: &mut HashMap<Label, Span>, label: Label, span: Span) -> Label {
+    spans.insert(label, span);
+    label
+}
+
 /// `input` is expected to end with a space (improves parser output)
 pub fn parse(input: &str) -> Result<Expression, ParseError<LineCol>> {
-    let program = func::program(input)?;
+    Ok(parse_with_spans(input)?.0)
+}
+
+/// like `parse`, but also returns where in `input` the expression of each label is
+pub fn parse_with_spans(
+    input: &str,
+) -> Result<(Expression, HashMap<Label, Span>), ParseError<LineCol>> {
+    let parsed = RefCell::new(vec![]);
+    let program = func::program(input, &parsed)?;
 
-    Ok(relabel(*expr(program), 1).0)
+    let mut spans = HashMap::new();
+    let (program, _) = relabel(*program, 1, &parsed.into_inner(), &mut spans);
+    Ok((program, spans))
+}
+
+/// the programs `example1` to `example4` of the repository
+#[cfg(test)]
+pub fn examples() -> [Expression; 4] {
+    [
+        include_str!("../example1"),
+        include_str!("../example2"),
+        include_str!("../example3"),
+        include_str!("../example4"),
+    ]
+    .map(|source| parse(&format!("{source} ")).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans_of_labels() {
+        let input = "let f = fn x -> x\nin (f 1) 2 ";
+        let (program, spans) = parse_with_spans(input).unwrap();
+        assert_eq!(program.label, 8);
+
+        let source = |label| {
+            let span: Span = spans[&label];
+            &input[span.start..span.end]
+        };
+        assert_eq!(source(1), "x");
+        assert_eq!(source(2), "fn x -> x");
+        assert_eq!(source(5), "(f 1)");
+        assert_eq!(source(7), "(f 1) 2");
+        assert_eq!(spans[&5].line_col(input), (2, 4));
+    }
 }
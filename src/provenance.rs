@@ -0,0 +1,133 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::solve_with_reasons,
+    constraint::{ConSet, Constraint, ConstraintSystem},
+    expression::Expression,
+    term::Term,
+};
+
+/// the constraint that first added each closure to each set
+type Reasons = HashMap<(ConSet, Term), Constraint>;
+
+/**
+ * a derivation of `t ∈ node` as an indented tree: every line is a fact with the
+ * constraint that established it, followed by the facts that constraint relied on
+ *
+ * a fact that has already been derived further up is not derived again. the tree is
+ * walked with an explicit stack, as derivations can be as long as the program
+ */
+fn derive(t: &Term, node: &ConSet, reasons: &Reasons, lines: &mut Vec<String>) {
+    let mut derived: HashSet<(ConSet, Term)> = HashSet::new();
+    let mut stack = vec![(t, node, 0)];
+
+    while let Some((t, node, depth)) = stack.pop() {
+        let indent = "  ".repeat(depth + 1);
+        let key = (node.clone(), t.clone());
+        let Some(constraint) = reasons.get(&key) else {
+            lines.push(format!("{indent}{t} ∈ {node}, not derivable"));
+            continue;
+        };
+        if !derived.insert(key) {
+            lines.push(format!("{indent}{t} ∈ {node}, see above"));
+            continue;
+        }
+        lines.push(format!("{indent}{t} ∈ {node}, by {constraint}"));
+
+        // pushed in reverse, so that the guard comes first
+        match constraint {
+            Constraint::Unconditional(ConSet::SingleTerm(_), _) => {}
+            Constraint::Unconditional(lhs, _) => stack.push((t, lhs, depth + 1)),
+            Constraint::Conditional((guard_term, guard), lhs, _) => {
+                stack.push((t, lhs, depth + 1));
+                stack.push((guard_term, guard, depth + 1));
+            }
+        }
+    }
+}
+
+/// why each closure is in `node` in the least solution of `system`, as one derivation
+/// tree per closure, starting from the constraints of the closures' definitions
+pub fn explain(expr: &Expression, system: &ConstraintSystem, node: &ConSet) -> Vec<String> {
+    let (solution, reasons) = solve_with_reasons(expr, system);
+
+    let mut closures: Vec<&Term> = solution
+        .nodes
+        .iter()
+        .position(|n| n == node)
+        .map(|n| {
+            solution.node_data[n]
+                .iter()
+                .map(|c| solution.closures[c])
+                .collect()
+        })
+        .unwrap_or_default();
+    closures.sort_by_key(|t| t.to_string());
+
+    let mut lines = vec![];
+    for t in closures {
+        derive(t, node, &reasons, &mut lines);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        generator::{random_program, Rng},
+        parser::examples,
+    };
+
+    /// checks that every derivation in `lines` goes back to the definition of a closure,
+    /// or to a fact derived before
+    fn check(lines: &[String]) {
+        let depth = |line: &str| line.len() - line.trim_start().len();
+        let mut facts = HashSet::new();
+
+        for (i, line) in lines.iter().enumerate() {
+            assert!(!line.ends_with("not derivable"), "{line}");
+            let (fact, reason) = line.trim_start().split_once(", ").unwrap();
+            if reason == "see above" {
+                assert!(facts.contains(fact), "{line}");
+                continue;
+            }
+            facts.insert(fact);
+
+            let leaf = lines
+                .get(i + 1)
+                .is_none_or(|next| depth(next) <= depth(line));
+            if leaf {
+                // `{t} ⊆ p`, not a conditional constraint or an inclusion between sets
+                assert!(
+                    reason.starts_with("by {") && !reason.contains("=>"),
+                    "{line}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn derivations_start_at_definitions() {
+        let random = (0..20).map(|seed| random_program(&mut Rng::new(seed), 50));
+        for program in examples().into_iter().chain(random) {
+            let system = program.constraint_system();
+            let (cache, env) = analyse(&program, &system);
+            let nodes = cache
+                .into_iter()
+                .filter(|(_, terms)| !terms.is_empty())
+                .map(|(l, _)| ConSet::Cache(l))
+                .chain(
+                    env.into_iter()
+                        .filter(|(_, terms)| !terms.is_empty())
+                        .map(|(x, _)| ConSet::Env(x)),
+                );
+            for node in nodes {
+                let lines = explain(&program, &system, &node);
+                assert!(!lines.is_empty(), "{node} in {program}");
+                check(&lines);
+            }
+        }
+    }
+}
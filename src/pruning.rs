@@ -0,0 +1,336 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::{analyse_on, AbstractCache, AbstractEnv},
+    constraint::ConstraintSystem,
+    domain::AbstractValue,
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+pub type ValueCache = HashMap<Label, AbstractValue>;
+pub type ValueEnv = HashMap<Variable, AbstractValue>;
+
+/// an `if` branch that can never be taken
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadBranch {
+    /// label of the `if` expression
+    pub if_label: Label,
+    /// label of the branch itself
+    pub branch_label: Label,
+    /// `true` for the `then` branch, `false` for the `else` branch
+    pub is_then: bool,
+    /// abstract value of the condition
+    pub condition: AbstractValue,
+}
+
+/// a closure that never reaches an operator position, so its body is never evaluated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UncalledClosure {
+    /// label of the closure
+    pub label: Label,
+    /// label of its body
+    pub body_label: Label,
+}
+
+/// which kinds of code may be ignored until they are found to be reachable
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Prune {
+    /// `if` branches whose condition rules them out
+    pub branches: bool,
+    /// bodies of closures that are never applied
+    pub closure_bodies: bool,
+}
+
+pub struct PrunedAnalysis {
+    /// the constraints that were solved, i.e. without those of unreachable code
+    pub system: ConstraintSystem,
+    pub cache: AbstractCache,
+    pub env: AbstractEnv,
+    pub dead_branches: Vec<DeadBranch>,
+    pub uncalled_closures: Vec<UncalledClosure>,
+    /// all labels inside dead branches and bodies of uncalled closures
+    pub unreachable: HashSet<Label>,
+}
+
+/**
+ * computes the abstract base values of all subexpressions not inside `excluded`,
+ * given the closures found by the control flow analysis
+ *
+ * the free variables of `expr` are bound outside the program, so they start out as
+ * any integer rather than none
+ */
+pub fn values(
+    expr: &Expression,
+    cache: &AbstractCache,
+    excluded: &HashSet<Label>,
+) -> (ValueCache, ValueEnv) {
+    let subexprs = expr.subexprs_excluding(excluded);
+
+    let mut value_cache: ValueCache = expr
+        .labels()
+        .into_iter()
+        .map(|l| (l, AbstractValue::bottom()))
+        .collect();
+    let free = expr.free_variables();
+    let mut value_env: ValueEnv = expr
+        .variables()
+        .into_iter()
+        .map(|x| {
+            let value = if free.contains(&x) {
+                AbstractValue::Any
+            } else {
+                AbstractValue::bottom()
+            };
+            (x, value)
+        })
+        .collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for e in &subexprs {
+            let value = match &e.term {
+                Term::Constant(c) => AbstractValue::constant(*c),
+
+                Term::Variable(x) => value_env[x].clone(),
+
+                Term::Closure(_, _) | Term::RecursiveClosure(_, _, _) => AbstractValue::bottom(),
+
+                Term::Application(e1, e2) => {
+                    let mut value = AbstractValue::bottom();
+                    for t in &cache[&e1.label] {
+                        if let Term::Closure(x, e0) | Term::RecursiveClosure(_, x, e0) = t {
+                            let argument = value_cache[&e2.label].clone();
+                            changed |= value_env.get_mut(x).unwrap().join(&argument);
+                            value.join(&value_cache[&e0.label]);
+                        }
+                    }
+                    value
+                }
+
+                Term::IfThenElse(_, e1, e2) => {
+                    let mut value = AbstractValue::bottom();
+                    for branch in [e1, e2] {
+                        if !excluded.contains(&branch.label) {
+                            value.join(&value_cache[&branch.label]);
+                        }
+                    }
+                    value
+                }
+
+                Term::Let(x, e1, e2) => {
+                    let bound = value_cache[&e1.label].clone();
+                    changed |= value_env.get_mut(x).unwrap().join(&bound);
+                    value_cache[&e2.label].clone()
+                }
+
+                Term::BinaryOp(e1, op, e2) => {
+                    AbstractValue::binary_op(&value_cache[&e1.label], op, &value_cache[&e2.label])
+                }
+            };
+
+            changed |= value_cache.get_mut(&e.label).unwrap().join(&value);
+        }
+    }
+
+    (value_cache, value_env)
+}
+
+/// the branches of all `if` expressions that the condition's abstract value rules out
+fn dead_branches(expr: &Expression, value_cache: &ValueCache) -> Vec<DeadBranch> {
+    let mut dead = Vec::new();
+
+    for e in expr.subexprs() {
+        if let Term::IfThenElse(e0, e1, e2) = &e.term {
+            let condition = &value_cache[&e0.label];
+            let truthiness = condition.truthiness();
+
+            for (branch, is_then, live) in [
+                (e1, true, truthiness.may_be_true()),
+                (e2, false, truthiness.may_be_false()),
+            ] {
+                if !live {
+                    dead.push(DeadBranch {
+                        if_label: e.label,
+                        branch_label: branch.label,
+                        is_then,
+                        condition: condition.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    dead
+}
+
+/// the closures that do not occur in `C(e1)` of any application `(e1 e2)`
+fn uncalled_closures(expr: &Expression, cache: &AbstractCache) -> Vec<UncalledClosure> {
+    let subexprs = expr.subexprs();
+
+    let called: HashSet<&Term> = subexprs
+        .iter()
+        .flat_map(|e| match &e.term {
+            Term::Application(e1, _) => cache[&e1.label].iter().collect(),
+            _ => vec![],
+        })
+        .collect();
+
+    subexprs
+        .iter()
+        .filter(|e| !called.contains(&e.term))
+        .filter_map(|e| match &e.term {
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => Some(UncalledClosure {
+                label: e.label,
+                body_label: e0.label,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/**
+ * 0-CFA that ignores the constraints of code which can provably never be evaluated:
+ * `if` branches ruled out by their condition, and/or bodies of closures that never
+ * reach an operator position (cf. the reachability-enhanced analysis in the book)
+ *
+ * Starts out assuming all such code is unreachable and revives it as soon as a branch's
+ * condition may evaluate accordingly, or a closure may be applied, so the result is the
+ * least solution.
+ *
+ * Without anything to prune, this is just the plain 0-CFA. Each round is solved on
+ * `threads` threads if there are more than one.
+ */
+pub fn analyse_pruned(expr: &Expression, prune: Prune, threads: usize) -> PrunedAnalysis {
+    let mut excluded: HashSet<Label> = expr
+        .subexprs()
+        .iter()
+        .flat_map(|e| match &e.term {
+            Term::IfThenElse(_, e1, e2) if prune.branches => vec![e1.label, e2.label],
+            Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) if prune.closure_bodies => {
+                vec![e0.label]
+            }
+            _ => vec![],
+        })
+        .collect();
+
+    loop {
+        let system = expr.constraint_system_excluding(&excluded);
+        let (cache, env) = analyse_on(expr, &system, threads);
+
+        let dead = if prune.branches {
+            let (value_cache, _) = values(expr, &cache, &excluded);
+            dead_branches(expr, &value_cache)
+        } else {
+            vec![]
+        };
+        let uncalled = if prune.closure_bodies {
+            uncalled_closures(expr, &cache)
+        } else {
+            vec![]
+        };
+
+        let new_excluded: HashSet<Label> = dead
+            .iter()
+            .map(|d| d.branch_label)
+            .chain(uncalled.iter().map(|u| u.body_label))
+            .collect();
+
+        if new_excluded == excluded {
+            let reachable: HashSet<Label> = expr
+                .subexprs_excluding(&excluded)
+                .iter()
+                .map(|e| e.label)
+                .collect();
+
+            // code inside unreachable code is not worth reporting separately
+            return PrunedAnalysis {
+                system,
+                cache,
+                env,
+                dead_branches: dead
+                    .into_iter()
+                    .filter(|d| reachable.contains(&d.if_label))
+                    .collect(),
+                uncalled_closures: uncalled
+                    .into_iter()
+                    .filter(|u| reachable.contains(&u.label))
+                    .collect(),
+                unreachable: &expr.labels() - &reachable,
+            };
+        }
+
+        excluded = new_excluded;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{self, examples};
+
+    fn prune_branches(source: &str) -> PrunedAnalysis {
+        let prune = Prune {
+            branches: true,
+            closure_bodies: false,
+        };
+        analyse_pruned(&parser::parse(source).unwrap(), prune, 1)
+    }
+
+    #[test]
+    fn dead_else_branch_of_example3() {
+        let [_, _, program, _] = examples();
+        let prune = Prune {
+            branches: true,
+            closure_bodies: false,
+        };
+        let analysis = analyse_pruned(&program, prune, 1);
+
+        assert_eq!(
+            analysis.dead_branches,
+            [DeadBranch {
+                if_label: 24,
+                branch_label: 23,
+                is_then: false,
+                condition: AbstractValue::constant(1),
+            }]
+        );
+        assert_eq!(analysis.unreachable, HashSet::from([21, 22, 23]));
+    }
+
+    #[test]
+    fn free_variables_may_be_any_integer() {
+        let analysis = prune_branches("let f = fn y -> y in if h then (f 1) else (f 2) ");
+        assert!(analysis.dead_branches.is_empty());
+        assert!(analysis.unreachable.is_empty());
+        // `f` is applied in both branches
+        assert_eq!(analysis.cache[&4].len(), 1);
+        assert_eq!(analysis.cache[&7].len(), 1);
+    }
+
+    #[test]
+    fn uncalled_closure_bodies_are_unreachable() {
+        let source = "let f = fn x -> (let g = fn y -> y in (g x)) in let h = fn z -> z in (h 3) ";
+        let prune = Prune {
+            branches: false,
+            closure_bodies: true,
+        };
+        let analysis = analyse_pruned(&parser::parse(source).unwrap(), prune, 1);
+
+        assert_eq!(
+            analysis.uncalled_closures,
+            [UncalledClosure {
+                label: 7,
+                body_label: 6,
+            }]
+        );
+        assert_eq!(analysis.unreachable, HashSet::from([1, 2, 3, 4, 5, 6]));
+        // `g` is only applied in the body of `f`, so it never reaches its call either
+        assert!(analysis.cache[&3].is_empty());
+        assert!(analysis.env[&'y'].is_empty());
+        assert_eq!(analysis.cache[&10].len(), 1);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// abstract value: a closure (by its label) or some integer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Value {
+    Closure(Label),
+    Number,
+}
+
+/// one analysis of a closure's body, for a single argument value
+type Entry = (Label, Value);
+
+/// variables bound in the current activation (parameters and `let`s), which are
+/// looked up precisely; all other variables are looked up in the heap
+type Locals = BTreeMap<Variable, HashSet<Value>>;
+
+/// closures returned by a call site under 0-CFA, but not under pushdown CFA
+pub struct SpuriousReturns {
+    pub call_site: Label,
+    /// closures that a function called here returns from a call made elsewhere
+    pub terms: Vec<Term>,
+    /// the other closures, which 0-CFA has for other reasons, e.g. because it merges
+    /// the bindings of a variable
+    pub extra: Vec<Term>,
+}
+
+/// result of the pushdown analysis
+pub struct PushdownAnalysis {
+    pub cache: AbstractCache,
+    pub env: AbstractEnv,
+    /// call sites that are never reached
+    pub unreached_calls: Vec<Label>,
+}
+
+/**
+ * pushdown CFA by summarization (as in CFA2): the body of a closure is analysed once per
+ * argument value it is called with, and a call site only receives the results of the
+ * bodies it called, so returns always match their calls
+ *
+ * variables bound in the current activation are tracked precisely. variables of
+ * enclosing functions are looked up in a single heap that holds every value any
+ * binding of them was given, as in 0-CFA
+ */
+struct Pushdown<'a> {
+    exprs: HashMap<Label, &'a Expression>,
+    /// occurrences of variables that are not bound by any enclosing expression
+    free_occurrences: HashSet<Label>,
+
+    /// values the bodies of the entries return
+    summaries: HashMap<Entry, HashSet<Value>>,
+    heap: HashMap<Variable, HashSet<Value>>,
+    /// values of each expression, over all entries
+    values: HashMap<Label, HashSet<Value>>,
+    /// whether anything grew since the last round
+    changed: bool,
+}
+
+impl<'a> Pushdown<'a> {
+    fn new(expr: &'a Expression) -> Self {
+        Self {
+            exprs: expr.subexprs().into_iter().map(|e| (e.label, e)).collect(),
+            free_occurrences: free_occurrences(expr, &mut vec![]),
+            summaries: HashMap::new(),
+            heap: HashMap::new(),
+            values: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    fn join(changed: &mut bool, set: &mut HashSet<Value>, values: &HashSet<Value>) {
+        for &value in values {
+            *changed |= set.insert(value);
+        }
+    }
+
+    /// binds `x` to `values` in `locals`, and records them in the heap for closures
+    /// that capture `x`
+    fn bind(&mut self, locals: &mut Locals, x: Variable, values: HashSet<Value>) {
+        Self::join(&mut self.changed, self.heap.entry(x).or_default(), &values);
+        locals.insert(x, values);
+    }
+
+    fn eval(&mut self, expr: &'a Expression, locals: &Locals) -> HashSet<Value> {
+        let values = match &expr.term {
+            Term::Constant(_) => HashSet::from([Value::Number]),
+
+            Term::Variable(_) if self.free_occurrences.contains(&expr.label) => HashSet::new(),
+            Term::Variable(x) => match locals.get(x) {
+                Some(values) => values.clone(),
+                None => self.heap.get(x).cloned().unwrap_or_default(),
+            },
+
+            Term::Closure(..) | Term::RecursiveClosure(..) => {
+                HashSet::from([Value::Closure(expr.label)])
+            }
+
+            Term::Application(e1, e2) => {
+                let functions = self.eval(e1, locals);
+                if functions.is_empty() {
+                    return functions;
+                }
+                let arguments = self.eval(e2, locals);
+
+                let mut results = HashSet::new();
+                for &function in &functions {
+                    // applying a number gets stuck
+                    let Value::Closure(closure) = function else {
+                        continue;
+                    };
+                    for &argument in &arguments {
+                        match self.summaries.get(&(closure, argument)) {
+                            Some(returned) => results.extend(returned),
+                            None => {
+                                // analysed in the next round
+                                self.summaries.insert((closure, argument), HashSet::new());
+                                self.changed = true;
+                            }
+                        }
+                    }
+                }
+                results
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                // the condition is not interpreted, so both branches may be taken
+                if self.eval(e0, locals).is_empty() {
+                    return HashSet::new();
+                }
+                let mut values = self.eval(e1, locals);
+                values.extend(self.eval(e2, locals));
+                values
+            }
+
+            Term::Let(x, e1, e2) => {
+                let bound = self.eval(e1, locals);
+                if bound.is_empty() {
+                    return bound;
+                }
+                let mut locals = locals.clone();
+                self.bind(&mut locals, *x, bound);
+                self.eval(e2, &locals)
+            }
+
+            Term::BinaryOp(e1, _, e2) => {
+                if self.eval(e1, locals).is_empty() || self.eval(e2, locals).is_empty() {
+                    return HashSet::new();
+                }
+                HashSet::from([Value::Number])
+            }
+        };
+
+        Self::join(
+            &mut self.changed,
+            self.values.entry(expr.label).or_default(),
+            &values,
+        );
+        values
+    }
+
+    /// analyses the body of `closure` for `argument`
+    fn eval_entry(&mut self, (closure, argument): Entry) {
+        let (f, x, e0) = match &self.exprs[&closure].term {
+            Term::Closure(x, e0) => (None, *x, e0),
+            Term::RecursiveClosure(f, x, e0) => (Some(*f), *x, e0),
+            _ => unreachable!("entry of a non-closure"),
+        };
+
+        let mut locals = Locals::new();
+        if let Some(f) = f {
+            self.bind(&mut locals, f, HashSet::from([Value::Closure(closure)]));
+        }
+        self.bind(&mut locals, x, HashSet::from([argument]));
+
+        let returned = self.eval(e0, &locals);
+        let summary = self.summaries.entry((closure, argument)).or_default();
+        Self::join(&mut self.changed, summary, &returned);
+    }
+
+    fn run(mut self, expr: &'a Expression) -> PushdownAnalysis {
+        self.changed = true;
+        while self.changed {
+            self.changed = false;
+            self.eval(expr, &Locals::new());
+
+            let entries: Vec<Entry> = self.summaries.keys().copied().collect();
+            for entry in entries {
+                self.eval_entry(entry);
+            }
+        }
+
+        self.projection(expr)
+    }
+
+    fn projection(&self, expr: &Expression) -> PushdownAnalysis {
+        let closures = |values: Option<&HashSet<Value>>| -> HashSet<Term> {
+            values
+                .into_iter()
+                .flatten()
+                .filter_map(|value| match value {
+                    Value::Closure(label) => Some(self.exprs[label].term.clone()),
+                    Value::Number => None,
+                })
+                .collect()
+        };
+
+        let mut unreached_calls: Vec<Label> = self
+            .exprs
+            .values()
+            .filter(|e| matches!(e.term, Term::Application(..)))
+            .map(|e| e.label)
+            .filter(|label| !self.values.contains_key(label))
+            .collect();
+        unreached_calls.sort();
+
+        PushdownAnalysis {
+            cache: expr
+                .labels()
+                .into_iter()
+                .map(|label| (label, closures(self.values.get(&label))))
+                .collect(),
+            env: expr
+                .variables()
+                .into_iter()
+                .map(|x| (x, closures(self.heap.get(&x))))
+                .collect(),
+            unreached_calls,
+        }
+    }
+}
+
+/// labels of the occurrences of variables in `expr` that are bound neither in `scope`
+/// nor within `expr`
+fn free_occurrences(expr: &Expression, scope: &mut Vec<Variable>) -> HashSet<Label> {
+    // the subexpressions, each with the variables it binds in addition to `scope`
+    let subexprs: Vec<(&Expression, Vec<Variable>)> = match &expr.term {
+        Term::Constant(_) => vec![],
+        Term::Variable(x) if scope.contains(x) => vec![],
+        Term::Variable(_) => return HashSet::from([expr.label]),
+        Term::Closure(x, e0) => vec![(e0, vec![*x])],
+        Term::RecursiveClosure(f, x, e0) => vec![(e0, vec![*f, *x])],
+        Term::Application(e1, e2) | Term::BinaryOp(e1, _, e2) => vec![(e1, vec![]), (e2, vec![])],
+        Term::IfThenElse(e0, e1, e2) => vec![(e0, vec![]), (e1, vec![]), (e2, vec![])],
+        Term::Let(x, e1, e2) => vec![(e1, vec![]), (e2, vec![*x])],
+    };
+
+    let mut free = HashSet::new();
+    for (e, binders) in subexprs {
+        let depth = scope.len();
+        scope.extend(binders);
+        free.extend(free_occurrences(e, scope));
+        scope.truncate(depth);
+    }
+    free
+}
+
+/// runs the pushdown analysis on `expr`
+pub fn analyse_pushdown(expr: &Expression) -> PushdownAnalysis {
+    Pushdown::new(expr).run(expr)
+}
+
+/**
+ * the closures that 0-CFA (`cache`) lets return to each reached call site, although no
+ * call made there returns them
+ *
+ * those that the body of a closure called here returns when called elsewhere (according
+ * to the pushdown analysis) are merged returns; the rest are only extra closures
+ *
+ * as the summaries are per closure and argument value, the difference mixes two effects:
+ * returns matched with their calls, and bodies analysed separately for each argument
+ * (as 0-CFA merges the arguments of a closure in its parameter). a closure called with
+ * different arguments is counted as a merged return even where the calls alone would
+ * not tell them apart
+ */
+pub fn spurious_returns(
+    expr: &Expression,
+    pushdown: &PushdownAnalysis,
+    cache: &AbstractCache,
+) -> Vec<SpuriousReturns> {
+    let mut call_sites: Vec<(Label, Label)> = expr
+        .subexprs()
+        .into_iter()
+        .filter_map(|e| match &e.term {
+            Term::Application(e1, _) => Some((e.label, e1.label)),
+            _ => None,
+        })
+        .filter(|(label, _)| !pushdown.unreached_calls.contains(label))
+        .collect();
+    call_sites.sort();
+
+    call_sites
+        .into_iter()
+        .filter_map(|(call_site, operator)| {
+            let returned: HashSet<&Term> = pushdown.cache[&operator]
+                .iter()
+                .filter_map(|callee| match callee {
+                    Term::Closure(_, e0) | Term::RecursiveClosure(_, _, e0) => {
+                        pushdown.cache.get(&e0.label)
+                    }
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+
+            let (mut terms, mut extra): (Vec<Term>, Vec<Term>) = cache[&call_site]
+                .difference(&pushdown.cache[&call_site])
+                .cloned()
+                .partition(|t| returned.contains(t));
+            terms.sort_by_key(ToString::to_string);
+            extra.sort_by_key(ToString::to_string);
+            (!terms.is_empty() || !extra.is_empty()).then_some(SpuriousReturns {
+                call_site,
+                terms,
+                extra,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser};
+
+    #[test]
+    fn returns_merged_in_example4() {
+        let program = parser::parse(&format!("{} ", include_str!("../example4"))).unwrap();
+        let (cache, _) = analyse(&program, &program.constraint_system());
+        let pushdown = analyse_pushdown(&program);
+
+        let spurious: Vec<(Label, Vec<String>, usize)> =
+            spurious_returns(&program, &pushdown, &cache)
+                .into_iter()
+                .map(|returns| {
+                    let terms = returns.terms.iter().map(ToString::to_string).collect();
+                    (returns.call_site, terms, returns.extra.len())
+                })
+                .collect();
+        assert_eq!(
+            spurious,
+            [
+                (5, vec!["fn y -> y⁶".to_string()], 0),
+                (8, vec!["fn x -> x¹".to_string()], 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn closures_of_calls_never_made_are_only_extra() {
+        // `e` is free, so the argument never evaluates and the call never returns
+        let source = "(fn h -> (fn d -> (if j then (fn b -> r) else t))) \
+                      ((fn a -> ((fn q -> (e x)) i)) e) ";
+        let program = parser::parse(source).unwrap();
+        let (cache, _) = analyse(&program, &program.constraint_system());
+        let pushdown = analyse_pushdown(&program);
+
+        let spurious = spurious_returns(&program, &pushdown, &cache);
+        assert_eq!(spurious.len(), 1);
+        assert_eq!(spurious[0].call_site, 17);
+        assert!(spurious[0].terms.is_empty());
+        assert_eq!(spurious[0].extra.len(), 1);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::{ConSet, Constraint},
+    expression::Expression,
+    term::Term,
+};
+
+/**
+ * least solution of `constraints`, found by applying every constraint in turn until none
+ * of them adds anything
+ *
+ * this is the book's formulation taken literally, without a graph or a worklist, and
+ * serves as a reference for `analyse`. `constraints` are expected to be expanded, e.g.
+ * `expr.constraints()`
+ */
+pub fn solve_naive(
+    expr: &Expression,
+    constraints: &HashSet<Constraint>,
+) -> (AbstractCache, AbstractEnv) {
+    let mut sets: HashMap<ConSet, HashSet<Term>> = expr
+        .labels()
+        .into_iter()
+        .map(ConSet::Cache)
+        .chain(expr.variables().into_iter().map(ConSet::Env))
+        .map(|node| (node, HashSet::new()))
+        .collect();
+
+    let set = |sets: &HashMap<ConSet, HashSet<Term>>, node: &ConSet| match node {
+        ConSet::SingleTerm(t) => HashSet::from([t.clone()]),
+        node => sets.get(node).cloned().unwrap_or_default(),
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for constraint in constraints {
+            let (lhs, rhs) = match constraint {
+                Constraint::Unconditional(lhs, rhs) => (lhs, rhs),
+                Constraint::Conditional((t, guard), lhs, rhs) => {
+                    if !set(&sets, guard).contains(t) {
+                        continue;
+                    }
+                    (lhs, rhs)
+                }
+            };
+
+            let terms = set(&sets, lhs);
+            let target = sets.entry(rhs.clone()).or_default();
+            for t in terms {
+                changed |= target.insert(t);
+            }
+        }
+    }
+
+    let (mut cache, mut env) = (AbstractCache::new(), AbstractEnv::new());
+    for (node, terms) in sets {
+        match node {
+            ConSet::Cache(l) => cache.insert(l, terms),
+            ConSet::Env(x) => env.insert(x, terms),
+            ConSet::SingleTerm(_) => unreachable!("a single term is never constrained"),
+        };
+    }
+    (cache, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::analyse, parser::examples, types::Label};
+
+    /// the labels of the closures in `terms`, in order
+    fn labels(program: &Expression, terms: &HashSet<Term>) -> Vec<Label> {
+        let mut labels: Vec<Label> = program
+            .subexprs()
+            .into_iter()
+            .filter(|e| terms.contains(&e.term))
+            .map(|e| e.label)
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    #[test]
+    fn sets_of_example1() {
+        let [program, ..] = examples();
+        let (cache, env) = solve_naive(&program, &program.constraints());
+        assert_eq!(labels(&program, &cache[&2]), [2]);
+        assert_eq!(labels(&program, &cache[&5]), [4]);
+        assert_eq!(labels(&program, &env[&'x']), [4]);
+        assert!(cache[&3].is_empty() && env[&'y'].is_empty());
+    }
+
+    #[test]
+    fn sets_of_example2() {
+        let [_, program, ..] = examples();
+        let (cache, env) = solve_naive(&program, &program.constraints());
+        assert_eq!(labels(&program, &env[&'x']), [8, 12]);
+        assert_eq!(labels(&program, &cache[&1]), [8, 12]);
+        assert_eq!(labels(&program, &cache[&13]), [4]);
+        assert!(cache[&22].is_empty() && env[&'y'].is_empty());
+    }
+
+    #[test]
+    fn sets_of_example4() {
+        let [.., program] = examples();
+        let (cache, env) = solve_naive(&program, &program.constraints());
+        assert_eq!(labels(&program, &cache[&5]), [2, 7]);
+        assert_eq!(labels(&program, &cache[&9]), [2, 7]);
+        assert_eq!(labels(&program, &env[&'f']), [2]);
+        assert_eq!(labels(&program, &env[&'x']), [2, 7]);
+    }
+
+    #[test]
+    fn same_solution_as_the_solver() {
+        for program in examples() {
+            assert_eq!(
+                solve_naive(&program, &program.constraints()),
+                analyse(&program, &program.constraint_system()),
+                "{program}"
+            );
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use crate::{analysis::TraceEvent, constraint::ConSet, term::Term};
+
+fn set(terms: &[Term]) -> String {
+    let terms = terms.iter().map(ToString::to_string).collect::<Vec<_>>();
+    format!("{{{}}}", terms.join(", "))
+}
+
+fn worklist(nodes: &[ConSet]) -> String {
+    let nodes = nodes.iter().map(ToString::to_string).collect::<Vec<_>>();
+    format!("[{}]", nodes.join(", "))
+}
+
+/// the events as indented text, with the steps numbered
+pub fn trace_text(events: &[TraceEvent]) -> Vec<String> {
+    let mut lines = vec!["Initially:".to_string()];
+    for event in events {
+        lines.push(match event {
+            TraceEvent::Pop {
+                iteration,
+                node,
+                delta,
+                worklist: rest,
+            } => format!(
+                "Iteration {iteration}: {node}, new {}\n  worklist: {}",
+                set(delta),
+                worklist(rest)
+            ),
+            TraceEvent::Examine { constraint } => format!("  examine {constraint}"),
+            TraceEvent::Add { node, terms } => format!("    {node} ∪= {}", set(terms)),
+            TraceEvent::Collapse { node, merged } => {
+                format!("    merge cycle {} into {node}", worklist(merged))
+            }
+        });
+    }
+    lines
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_array<T: ToString>(items: &[T]) -> String {
+    let items = items
+        .iter()
+        .map(|item| json_string(&item.to_string()))
+        .collect::<Vec<_>>();
+    format!("[{}]", items.join(","))
+}
+
+/// the events as JSON lines, one object per event with its kind in `"event"`
+pub fn trace_json(events: &[TraceEvent]) -> Vec<String> {
+    events
+        .iter()
+        .map(|event| match event {
+            TraceEvent::Pop {
+                iteration,
+                node,
+                delta,
+                worklist,
+            } => format!(
+                r#"{{"event":"pop","iteration":{iteration},"node":{},"delta":{},"worklist":{}}}"#,
+                json_string(&node.to_string()),
+                json_array(delta),
+                json_array(worklist)
+            ),
+            TraceEvent::Examine { constraint } => format!(
+                r#"{{"event":"examine","constraint":{}}}"#,
+                json_string(&constraint.to_string())
+            ),
+            TraceEvent::Add { node, terms } => format!(
+                r#"{{"event":"add","node":{},"terms":{}}}"#,
+                json_string(&node.to_string()),
+                json_array(terms)
+            ),
+            TraceEvent::Collapse { node, merged } => format!(
+                r#"{{"event":"collapse","node":{},"merged":{}}}"#,
+                json_string(&node.to_string()),
+                json_array(merged)
+            ),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{analysis::solve_traced, expression::Expression, parser::examples};
+
+    /// a JSON object, with the values that the trace can contain
+    #[derive(Debug, PartialEq)]
+    enum Json {
+        Number(usize),
+        String(String),
+        Array(Vec<Json>),
+        Object(Vec<(String, Json)>),
+    }
+
+    peg::parser!(grammar json() for str {
+        rule string() -> String
+            = "\"" s:(
+                "\\\"" { '"' }
+                / "\\\\" { '\\' }
+                / "\\n" { '\n' }
+                / "\\u" n:$(['0'..='9' | 'a'..='f']*<4>) {?
+                    char::from_u32(u32::from_str_radix(n, 16).unwrap()).ok_or("code point")
+                }
+                / c:[^ '"' | '\\'] { c }
+            )* "\"" { s.into_iter().collect() }
+
+        rule value() -> Json
+            = n:$(['0'..='9']+) {? n.parse().map(Json::Number).or(Err("number")) }
+            / s:string() { Json::String(s) }
+            / "[" items:(value() ** ",") "]" { Json::Array(items) }
+            / o:object() { o }
+
+        pub rule object() -> Json
+            = "{" fields:((k:string() ":" v:value() { (k, v) }) ** ",") "}" {
+                Json::Object(fields)
+            }
+    });
+
+    fn traced(program: &Expression) -> Vec<TraceEvent> {
+        solve_traced(program, &program.constraint_system()).1
+    }
+
+    #[test]
+    fn text_trace_of_example1() {
+        let trace = trace_text(&traced(&examples()[0])).join("\n");
+        assert_eq!(
+            trace,
+            "\
+Initially:
+    C(2) ∪= {fn x -> x¹}
+    C(4) ∪= {fn y -> y³}
+Iteration 1: C(2), new {fn x -> x¹}
+  worklist: [C(4)]
+  examine {fn x -> x¹} ⊆ C(2) => C(4) ⊆ r(x)
+    r(x) ∪= {fn y -> y³}
+  examine {fn x -> x¹} ⊆ C(2) => C(1) ⊆ C(5)
+Iteration 2: C(4), new {fn y -> y³}
+  worklist: [r(x)]
+  examine C(4) ⊆ r(x)
+Iteration 3: r(x), new {fn y -> y³}
+  worklist: []
+  examine r(x) ⊆ C(1)
+    C(1) ∪= {fn y -> y³}
+Iteration 4: C(1), new {fn y -> y³}
+  worklist: []
+  examine C(1) ⊆ C(5)
+    C(5) ∪= {fn y -> y³}
+Iteration 5: C(5), new {fn y -> y³}
+  worklist: []"
+        );
+    }
+
+    #[test]
+    fn json_lines_are_objects_with_an_event() {
+        for program in examples() {
+            let events = traced(&program);
+            let lines = trace_json(&events);
+            assert_eq!(lines.len(), events.len());
+            for line in lines {
+                let Ok(Json::Object(fields)) = json::object(&line) else {
+                    panic!("not a JSON object: {line}")
+                };
+                assert!(
+                    matches!(&fields[0], (key, Json::String(_)) if key == "event"),
+                    "{line}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        let escaped = json_string("\"a\\b\"\n\u{1}");
+        assert_eq!(escaped, r#""\"a\\b\"\n\u0001""#);
+        assert_eq!(
+            json::object(&format!(r#"{{"s":{escaped}}}"#)),
+            Ok(Json::Object(vec![(
+                "s".to_string(),
+                Json::String("\"a\\b\"\n\u{1}".to_string())
+            )]))
+        );
+    }
+}
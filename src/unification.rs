@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+
+use crate::{
+    analysis::{AbstractCache, AbstractEnv},
+    constraint::ConSet,
+    expression::Expression,
+    term::Term,
+    types::{Label, Variable},
+};
+
+/// index of a node `C(l)` or `r(x)`
+type Node = usize;
+
+/**
+ * equality-based closure analysis (Steensgaard, Henglein): instead of `p1 ⊆ p2`, the
+ * nodes `p1` and `p2` are merged into one equivalence class with a single set
+ *
+ * every class that closures flow into has one function shape: the class of the
+ * parameters and the class of the results of all of those closures. merging two classes
+ * merges their shapes, so the analysis runs in almost linear time
+ */
+struct Unifier<'a> {
+    nodes: HashMap<ConSet, Node>,
+    parent: Vec<Node>,
+    rank: Vec<usize>,
+    /// closures of each class (valid for representatives)
+    closures: Vec<Vec<&'a Term>>,
+    /// `(parameter, result)` of the closures of each class (valid for representatives)
+    shapes: Vec<Option<(Node, Node)>>,
+    /// pairs of classes still to be merged
+    pending: Vec<(Node, Node)>,
+}
+
+impl<'a> Unifier<'a> {
+    fn new(expr: &Expression) -> Self {
+        let nodes: HashMap<ConSet, Node> = expr
+            .labels()
+            .into_iter()
+            .map(ConSet::Cache)
+            .chain(expr.variables().into_iter().map(ConSet::Env))
+            .enumerate()
+            .map(|(n, node)| (node, n))
+            .collect();
+        let count = nodes.len();
+
+        Self {
+            nodes,
+            parent: (0..count).collect(),
+            rank: vec![0; count],
+            closures: vec![vec![]; count],
+            shapes: vec![None; count],
+            pending: vec![],
+        }
+    }
+
+    fn cache(&self, label: Label) -> Node {
+        self.nodes[&ConSet::Cache(label)]
+    }
+
+    fn env(&self, x: Variable) -> Node {
+        self.nodes[&ConSet::Env(x)]
+    }
+
+    fn find(&mut self, mut n: Node) -> Node {
+        while self.parent[n] != n {
+            self.parent[n] = self.parent[self.parent[n]];
+            n = self.parent[n];
+        }
+        n
+    }
+
+    /// merges the classes of `a` and `b`, and then their shapes
+    fn unify(&mut self, a: Node, b: Node) {
+        self.pending.push((a, b));
+        while let Some((a, b)) = self.pending.pop() {
+            let (mut a, mut b) = (self.find(a), self.find(b));
+            if a == b {
+                continue;
+            }
+            if self.rank[a] < self.rank[b] {
+                (a, b) = (b, a);
+            }
+            if self.rank[a] == self.rank[b] {
+                self.rank[a] += 1;
+            }
+
+            self.parent[b] = a;
+            let mut closures = std::mem::take(&mut self.closures[b]);
+            if closures.len() > self.closures[a].len() {
+                std::mem::swap(&mut closures, &mut self.closures[a]);
+            }
+            self.closures[a].extend(closures);
+
+            match (self.shapes[a], self.shapes[b].take()) {
+                (Some((p1, r1)), Some((p2, r2))) => {
+                    self.pending.push((p1, p2));
+                    self.pending.push((r1, r2));
+                }
+                (None, shape) => self.shapes[a] = shape,
+                (Some(_), None) => {}
+            }
+        }
+    }
+
+    /// requires the closures of the class of `n` to take `parameter` and return `result`
+    fn shape(&mut self, n: Node, parameter: Node, result: Node) {
+        let n = self.find(n);
+        match self.shapes[n] {
+            Some((p, r)) => {
+                self.unify(p, parameter);
+                self.unify(r, result);
+            }
+            None => self.shapes[n] = Some((parameter, result)),
+        }
+    }
+
+    fn visit(&mut self, expr: &'a Expression) {
+        let l = self.cache(expr.label);
+
+        match &expr.term {
+            Term::Constant(_) => {}
+
+            Term::Variable(x) => self.unify(self.env(*x), l),
+
+            Term::Closure(x, e0) => {
+                self.add_closure(l, &expr.term);
+                self.shape(l, self.env(*x), self.cache(e0.label));
+                self.visit(e0);
+            }
+
+            Term::RecursiveClosure(f, x, e0) => {
+                self.add_closure(l, &expr.term);
+                self.shape(l, self.env(*x), self.cache(e0.label));
+                self.unify(self.env(*f), l);
+                self.visit(e0);
+            }
+
+            Term::Application(e1, e2) => {
+                self.visit(e1);
+                self.visit(e2);
+                self.shape(self.cache(e1.label), self.cache(e2.label), l);
+            }
+
+            Term::IfThenElse(e0, e1, e2) => {
+                self.visit(e0);
+                self.visit(e1);
+                self.visit(e2);
+                self.unify(self.cache(e1.label), l);
+                self.unify(self.cache(e2.label), l);
+            }
+
+            Term::Let(x, e1, e2) => {
+                self.visit(e1);
+                self.visit(e2);
+                self.unify(self.cache(e1.label), self.env(*x));
+                self.unify(self.cache(e2.label), l);
+            }
+
+            Term::BinaryOp(e1, _, e2) => {
+                self.visit(e1);
+                self.visit(e2);
+            }
+        }
+    }
+
+    fn add_closure(&mut self, n: Node, closure: &'a Term) {
+        let n = self.find(n);
+        self.closures[n].push(closure);
+    }
+
+    fn solution(mut self) -> Unification<'a> {
+        let nodes: Vec<(ConSet, Node)> = self.nodes.drain().collect();
+        let classes = nodes
+            .into_iter()
+            .map(|(node, n)| (node, self.find(n)))
+            .collect();
+
+        Unification {
+            classes,
+            closures: self.closures,
+        }
+    }
+}
+
+/// result of the equality-based analysis: the class of every node, and the closures of
+/// every class
+pub struct Unification<'a> {
+    classes: HashMap<ConSet, Node>,
+    closures: Vec<Vec<&'a Term>>,
+}
+
+impl Unification<'_> {
+    /// converts to the book's representation, cloning every closure into every set it is in
+    pub fn into_analysis(self) -> (AbstractCache, AbstractEnv) {
+        let (mut cache, mut env) = (AbstractCache::new(), AbstractEnv::new());
+
+        for (node, class) in self.classes {
+            let terms = self.closures[class].iter().map(|&t| t.clone()).collect();
+            match node {
+                ConSet::Cache(l) => cache.insert(l, terms),
+                ConSet::Env(x) => env.insert(x, terms),
+                ConSet::SingleTerm(_) => unreachable!("no node for a single term"),
+            };
+        }
+
+        (cache, env)
+    }
+}
+
+/// merges the nodes of `expr` into classes, without building the sets of every node
+pub fn unify(expr: &Expression) -> Unification<'_> {
+    let mut unifier = Unifier::new(expr);
+    unifier.visit(expr);
+    unifier.solution()
+}
+
+/// equality-based counterpart of `analyse`, less precise but in almost linear time
+pub fn analyse_unification(expr: &Expression) -> (AbstractCache, AbstractEnv) {
+    unify(expr).into_analysis()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::{
+        analysis::analyse,
+        generator::{random_program, Rng},
+        parser::{examples, parse},
+    };
+
+    #[test]
+    fn sets_include_those_of_0cfa() {
+        let random = (0..50).map(|seed| random_program(&mut Rng::new(seed), 100));
+        for program in examples().into_iter().chain(random) {
+            let (cache, env) = analyse(&program, &program.constraint_system());
+            let (unified_cache, unified_env) = analyse_unification(&program);
+            for (l, set) in &cache {
+                let unified = unified_cache.get(l).cloned().unwrap_or_default();
+                assert!(set.is_subset(&unified), "C({l}) of {program}");
+            }
+            for (x, set) in &env {
+                let unified = unified_env.get(x).cloned().unwrap_or_default();
+                assert!(set.is_subset(&unified), "r({x}) of {program}");
+            }
+        }
+    }
+
+    #[test]
+    fn branches_merge_closures_that_0cfa_keeps_apart() {
+        let program =
+            parse("let a = fn x -> x in let b = (if 1 then a else fn y -> y) in a ").unwrap();
+        let (cache, env) = analyse(&program, &program.constraint_system());
+        let (unified_cache, unified_env) = analyse_unification(&program);
+        let (fn_x, fn_y) = (&cache[&2], &cache[&6]);
+
+        // the branches are merged with the `if`, and so `r(a)` with `C(6)`
+        assert_eq!(env[&'a'], *fn_x);
+        assert_eq!(cache[&10], *fn_x);
+        let both: HashSet<Term> = fn_x.union(fn_y).cloned().collect();
+        for node in [2, 4, 6, 7, 8, 9, 10] {
+            assert_eq!(unified_cache[&node], both, "C({node})");
+        }
+        assert_eq!(unified_env[&'a'], both);
+    }
+}